@@ -0,0 +1,41 @@
+//! Arbitrary lines fed to an initialized node, as a nemesis could send
+//! them. The node may reply with errors or log and drop lines, but must
+//! not panic. Handlers' panics are normally turned into `crash` replies,
+//! so here they abort to be reported.
+#![no_main]
+
+use std::{
+    io::{self, BufRead, Cursor, Write},
+    panic,
+    sync::Once,
+};
+
+use distributed_systems_challenges::{run, transport::Transport, Config};
+use libfuzzer_sys::fuzz_target;
+
+const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}"#;
+
+/// Lines from memory in, replies discarded.
+struct Bytes(Vec<u8>);
+
+impl Transport for Bytes {
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn BufRead + Send>, Box<dyn Write>)> {
+        Ok((Box::new(Cursor::new(self.0)), Box::new(io::sink())))
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let report = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            report(info);
+            std::process::abort();
+        }));
+    });
+
+    let mut input = format!("{INIT}\n").into_bytes();
+    input.extend_from_slice(data);
+    // Errors stop the node, which is allowed; only panics are not
+    let _ = run(Config::default(), Box::new(Bytes(input)));
+});
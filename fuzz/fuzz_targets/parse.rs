@@ -0,0 +1,12 @@
+//! Arbitrary bytes as a message: parsing may fail, but whatever parses
+//! must serialize again.
+#![no_main]
+
+use distributed_systems_challenges::Message;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = serde_json::from_slice::<Message>(data) {
+        serde_json::to_string(&msg).unwrap();
+    }
+});
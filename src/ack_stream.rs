@@ -0,0 +1,130 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Items sent to every peer in the order they were pushed, like a
+/// replication log. Each peer's progress is a watermark: the offset before
+/// which it acknowledged everything. Sending again means sending from the
+/// watermark, and items every peer acknowledged can be released.
+#[derive(Debug)]
+pub struct AckStream<T> {
+    // Offset of the first item kept; everything before it was released
+    base: usize,
+    items: VecDeque<T>,
+    watermarks: HashMap<String, usize>,
+}
+
+impl<T> Default for AckStream<T> {
+    fn default() -> Self {
+        Self {
+            base: 0,
+            items: VecDeque::new(),
+            watermarks: HashMap::new(),
+        }
+    }
+}
+
+impl<T> AckStream<T> {
+    pub fn push(&mut self, item: T) {
+        self.items.push_back(item);
+    }
+
+    /// Approximate bytes held.
+    pub fn memory(&self) -> usize {
+        self.items.len() * size_of::<T>() + self.watermarks.len() * size_of::<(String, usize)>()
+    }
+
+    /// Offset the next item pushed gets.
+    pub fn end(&self) -> usize {
+        self.base + self.items.len()
+    }
+
+    pub fn watermark(&self, peer: &str) -> usize {
+        self.watermarks.get(peer).copied().unwrap_or(0)
+    }
+
+    pub fn watermarks(&self) -> &HashMap<String, usize> {
+        &self.watermarks
+    }
+
+    /// Items not released yet, in order.
+    pub fn kept(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+
+    /// Items `peer` has not acknowledged, with their offsets, or `None` if
+    /// some of them were already released and the peer has to catch up
+    /// some other way.
+    pub fn unacked(&self, peer: &str) -> Option<impl Iterator<Item = (usize, &T)>> {
+        let watermark = self.watermark(peer);
+        if watermark < self.base {
+            return None;
+        }
+        let skip = watermark - self.base;
+        Some(
+            self.items
+                .iter()
+                .enumerate()
+                .skip(skip)
+                .map(move |(i, item)| (self.base + i, item)),
+        )
+    }
+
+    /// Records that `peer` acknowledged the items from `start` up to `end`,
+    /// returning whether its watermark moved. Acks past a gap are ignored;
+    /// the items after the gap are sent again from the watermark.
+    pub fn ack(&mut self, peer: &str, start: usize, end: usize) -> bool {
+        let watermark = self.watermarks.entry(peer.to_string()).or_default();
+        if start > *watermark || end <= *watermark {
+            return false;
+        }
+        *watermark = end.min(self.base + self.items.len());
+        true
+    }
+
+    /// Moves `peer`'s watermark to the end, once it is known to have every
+    /// item by other means.
+    pub fn catch_up(&mut self, peer: &str) {
+        let end = self.end();
+        self.watermarks.insert(peer.to_string(), end);
+    }
+
+    /// Drops the items every one of `peers` acknowledged, returning how
+    /// many. A peer not among them later falls behind the stream.
+    pub fn release(&mut self, peers: &[String]) -> usize {
+        let Some(low) = peers.iter().map(|peer| self.watermark(peer)).min() else {
+            return 0;
+        };
+        let released = low.saturating_sub(self.base);
+        self.items.drain(..released);
+        self.base += released;
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resends_from_watermark_and_releases_acked_prefix() {
+        let peers = ["n2", "n3"].map(String::from);
+        let mut stream = AckStream::default();
+        for item in 10..15 {
+            stream.push(item);
+        }
+
+        // An ack past a gap does not move the watermark
+        assert!(!stream.ack("n2", 2, 4));
+        assert!(stream.ack("n2", 0, 3));
+        let unacked: Vec<_> = stream.unacked("n2").unwrap().collect();
+        assert_eq!(unacked, [(3, &13), (4, &14)]);
+
+        assert!(stream.ack("n3", 0, 2));
+        assert_eq!(stream.release(&peers), 2);
+        assert_eq!(stream.end(), 5);
+
+        // A peer that never acked fell behind what was released
+        assert!(stream.unacked("n4").is_none());
+        stream.catch_up("n4");
+        assert_eq!(stream.unacked("n4").unwrap().count(), 0);
+    }
+}
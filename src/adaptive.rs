@@ -0,0 +1,120 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How far back messages and operations are counted.
+const WINDOW: Duration = Duration::from_secs(5);
+/// How often the interval and fan-out are reconsidered.
+const ADJUST_EVERY: Duration = Duration::from_secs(1);
+const MIN_INTERVAL: Duration = Duration::from_millis(20);
+/// Below this fraction of the target there is budget to spend on latency.
+const LOW_WATER: f64 = 0.6;
+
+/// Targets for the adaptive gossip controller.
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveGossip {
+    pub target_msgs_per_op: f64,
+    /// Longest gossip interval allowed, bounding propagation latency.
+    pub max_interval: Duration,
+}
+
+impl Default for AdaptiveGossip {
+    fn default() -> Self {
+        Self {
+            target_msgs_per_op: 30.0,
+            max_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Tunes the gossip interval and fan-out from the messages-per-operation
+/// this node observed recently. Over budget it first gossips less often,
+/// then to fewer peers per round; under budget it undoes that in reverse,
+/// restoring fan-out before shortening the interval.
+#[derive(Debug)]
+pub struct GossipController {
+    target: AdaptiveGossip,
+    interval: Duration,
+    fanout: usize,
+    // (when, server messages, client operations)
+    samples: VecDeque<(Instant, u64, u64)>,
+    last_adjust: Option<Instant>,
+}
+
+impl GossipController {
+    pub fn new(target: AdaptiveGossip, interval: Duration) -> Self {
+        Self {
+            target,
+            interval: interval.min(target.max_interval),
+            fanout: usize::MAX,
+            samples: VecDeque::new(),
+            last_adjust: None,
+        }
+    }
+
+    /// Starts tuning over from `interval` and every peer, keeping what was
+    /// observed so far.
+    pub fn restart(&mut self, interval: Duration) {
+        self.interval = interval.min(self.target.max_interval);
+        self.fanout = usize::MAX;
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// How many of `peers` to gossip to each round.
+    pub fn fanout(&self, peers: usize) -> usize {
+        self.fanout.min(peers)
+    }
+
+    pub fn record_msgs(&mut self, now: Instant, msgs: usize) {
+        self.samples.push_back((now, msgs as u64, 0));
+    }
+
+    pub fn record_op(&mut self, now: Instant) {
+        self.samples.push_back((now, 0, 1));
+    }
+
+    pub fn adjust(&mut self, now: Instant, peers: usize) {
+        if self
+            .last_adjust
+            .is_some_and(|last| now.duration_since(last) < ADJUST_EVERY)
+        {
+            return;
+        }
+        self.last_adjust = Some(now);
+
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _, _)| now.duration_since(*at) > WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        let (msgs, ops) = self
+            .samples
+            .iter()
+            .fold((0, 0), |(msgs, ops), (_, m, o)| (msgs + m, ops + o));
+        if ops == 0 {
+            return;
+        }
+        let ratio = msgs as f64 / ops as f64;
+        let fanout = self.fanout(peers);
+
+        if ratio > self.target.target_msgs_per_op {
+            if self.interval < self.target.max_interval {
+                self.interval = self.interval.mul_f64(1.25).min(self.target.max_interval);
+            } else if fanout > 1 {
+                self.fanout = fanout - 1;
+            }
+        } else if ratio < self.target.target_msgs_per_op * LOW_WATER {
+            if fanout < peers {
+                self.fanout = fanout + 1;
+            } else {
+                self.interval = self.interval.mul_f64(0.8).max(MIN_INTERVAL);
+            }
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Reports where the messages of a Maelstrom run went.
+//!
+//! ```text
+//! analyze FILE...
+//! ```
+//!
+//! A `.edn` FILE is read as Maelstrom's `results.edn`, and its network and
+//! operation stats are printed. Any other FILE is read as a message log,
+//! one Maelstrom message per line as JSON, optionally with a `time` field
+//! in milliseconds. For those the messages each node sent are counted by
+//! type, split into client, gossip and service traffic, and replies are
+//! matched to their requests for latency percentiles per request type.
+//!
+//! Node logs written with `--trace` may be given too: their trace events
+//! are gathered into a timeline per client request, and lines that are
+//! not JSON are skipped.
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{Map, Number, Value};
+
+/// Who a message was between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Traffic {
+    /// To or from a client.
+    Client,
+    /// Between nodes.
+    Gossip,
+    /// To or from a Maelstrom service such as `lin-kv`.
+    Service,
+}
+
+impl Traffic {
+    fn of(src: &str, dest: &str) -> Self {
+        let node = |id: &str| id.starts_with('n');
+        if src.starts_with('c') || dest.starts_with('c') {
+            Self::Client
+        } else if node(src) && node(dest) {
+            Self::Gossip
+        } else {
+            Self::Service
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Log {
+    /// Messages sent, by sender, traffic and type.
+    sent: BTreeMap<String, BTreeMap<(Traffic, String), usize>>,
+    /// Request type and time of each request still waiting for a reply,
+    /// by sender, recipient and msg_id.
+    requests: HashMap<(String, String, u64), (String, f64)>,
+    /// Reply latencies in milliseconds, by request type.
+    latencies: BTreeMap<String, Vec<f64>>,
+}
+
+impl Log {
+    fn record(&mut self, msg: &Value) -> Option<()> {
+        let src = msg["src"].as_str()?;
+        let dest = msg["dest"].as_str()?;
+        let body = &msg["body"];
+        let kind = body["type"].as_str()?;
+        *self
+            .sent
+            .entry(src.to_string())
+            .or_default()
+            .entry((Traffic::of(src, dest), kind.to_string()))
+            .or_default() += 1;
+
+        let time = msg["time"].as_f64()?;
+        if let Some(in_reply_to) = body["in_reply_to"].as_u64() {
+            let key = (dest.to_string(), src.to_string(), in_reply_to);
+            if let Some((request, sent)) = self.requests.remove(&key) {
+                self.latencies.entry(request).or_default().push(time - sent);
+            }
+        } else if let Some(msg_id) = body["msg_id"].as_u64() {
+            let key = (src.to_string(), dest.to_string(), msg_id);
+            self.requests.insert(key, (kind.to_string(), time));
+        }
+        Some(())
+    }
+
+    fn total(&self, traffic: Traffic) -> usize {
+        self.sent
+            .values()
+            .flatten()
+            .filter(|((t, _), _)| *t == traffic)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    fn report(&mut self) {
+        println!("Messages sent:");
+        for (node, counts) in &self.sent {
+            let total: usize = counts.values().sum();
+            println!("  {node}: {total}");
+            for ((traffic, kind), count) in counts {
+                println!("    {:<8} {kind:<20} {count}", format!("{traffic:?}"));
+            }
+        }
+
+        let client = self.total(Traffic::Client);
+        let gossip = self.total(Traffic::Gossip);
+        let service = self.total(Traffic::Service);
+        println!("Client: {client}, gossip: {gossip}, service: {service}");
+        // Every operation is a request and its reply
+        let ops = client / 2;
+        if ops > 0 {
+            println!(
+                "Per op: {:.2} gossip, {:.2} service",
+                gossip as f64 / ops as f64,
+                service as f64 / ops as f64,
+            );
+        }
+
+        if !self.latencies.is_empty() {
+            println!("Latency (ms):");
+            println!(
+                "  {:<20} {:>7} {:>8} {:>8} {:>8} {:>8}",
+                "type", "count", "p50", "p95", "p99", "max"
+            );
+        }
+        for (kind, latencies) in &mut self.latencies {
+            latencies.sort_by(f64::total_cmp);
+            let at = |q: f64| latencies[((latencies.len() - 1) as f64 * q).round() as usize];
+            println!(
+                "  {kind:<20} {:>7} {:>8.1} {:>8.1} {:>8.1} {:>8.1}",
+                latencies.len(),
+                at(0.5),
+                at(0.95),
+                at(0.99),
+                at(1.0),
+            );
+        }
+    }
+}
+
+/// Reads EDN far enough for Maelstrom's results: keywords and symbols
+/// become strings, lists, vectors and sets arrays, and tags are dropped.
+struct Edn<'a> {
+    text: &'a [u8],
+    at: usize,
+}
+
+impl Edn<'_> {
+    fn skip_blank(&mut self) {
+        while let Some(&c) = self.text.get(self.at) {
+            match c {
+                b';' => {
+                    while self.text.get(self.at).is_some_and(|&c| c != b'\n') {
+                        self.at += 1;
+                    }
+                }
+                c if c.is_ascii_whitespace() || c == b',' => self.at += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn token(&mut self) -> &str {
+        let start = self.at;
+        while self
+            .text
+            .get(self.at)
+            .is_some_and(|&c| !c.is_ascii_whitespace() && !b",()[]{}\"".contains(&c))
+        {
+            self.at += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.at]).unwrap_or_default()
+    }
+
+    fn items(&mut self, close: u8) -> Result<Vec<Value>> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            match self.text.get(self.at) {
+                None => return Err(anyhow!("Unclosed {}", close as char)),
+                Some(&c) if c == close => {
+                    self.at += 1;
+                    return Ok(items);
+                }
+                _ => items.push(self.value()?),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.skip_blank();
+        let Some(&c) = self.text.get(self.at) else {
+            return Err(anyhow!("Unexpected end of EDN"));
+        };
+        Ok(match c {
+            b'{' => {
+                self.at += 1;
+                let items = self.items(b'}')?;
+                let mut map = Map::new();
+                for pair in items.chunks(2) {
+                    let key = match &pair[0] {
+                        Value::String(key) => key.clone(),
+                        key => key.to_string(),
+                    };
+                    map.insert(key, pair.get(1).cloned().unwrap_or(Value::Null));
+                }
+                Value::Object(map)
+            }
+            b'(' | b'[' => {
+                self.at += 1;
+                Value::Array(self.items(if c == b'(' { b')' } else { b']' })?)
+            }
+            b'#' if self.text.get(self.at + 1) == Some(&b'{') => {
+                self.at += 2;
+                Value::Array(self.items(b'}')?)
+            }
+            b'#' => {
+                // Tagged literal: keep what it tags
+                self.token();
+                self.value()?
+            }
+            b'"' => {
+                let start = self.at;
+                self.at += 1;
+                while let Some(&c) = self.text.get(self.at) {
+                    self.at += if c == b'\\' { 2 } else { 1 };
+                    if c == b'"' {
+                        break;
+                    }
+                }
+                serde_json::from_slice(&self.text[start..self.at])?
+            }
+            _ => match self.token() {
+                "" => return Err(anyhow!("Unexpected {} in EDN", c as char)),
+                "nil" => Value::Null,
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                token => {
+                    let number = token.trim_end_matches(['N', 'M']);
+                    if let Ok(n) = number.parse::<i64>() {
+                        n.into()
+                    } else if let Some(n) = number.parse().ok().and_then(Number::from_f64) {
+                        Value::Number(n)
+                    } else {
+                        token.trim_start_matches(':').into()
+                    }
+                }
+            },
+        })
+    }
+}
+
+fn results(text: &str) -> Result<()> {
+    let results = Edn {
+        text: text.as_bytes(),
+        at: 0,
+    }
+    .value()?;
+    println!("Valid: {}", results["valid?"]);
+    let stats = &results["stats"];
+    println!(
+        "Ops: {} ok, {} failed, {} indeterminate",
+        stats["ok-count"], stats["fail-count"], stats["info-count"]
+    );
+    for (name, net) in [
+        ("all", &results["net"]["all"]),
+        ("servers", &results["net"]["servers"]),
+    ] {
+        println!(
+            "Net {name}: {} sent, {} received, {} per op",
+            net["send-count"], net["recv-count"], net["msgs-per-op"]
+        );
+    }
+    if let Some(latency) = results["workload"]["stable-latencies"].as_object() {
+        let quantiles: Vec<String> = latency
+            .iter()
+            .map(|(q, ms)| format!("{q}: {ms}ms"))
+            .collect();
+        println!("Stable latency: {}", quantiles.join(", "));
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let paths: Vec<String> = env::args().skip(1).collect();
+    if paths.is_empty() {
+        return Err(anyhow!("Expected results.edn or a message log"));
+    }
+    let mut log = Log::default();
+    let mut logged = false;
+    let mut traces: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for path in paths {
+        let text = fs::read_to_string(&path).with_context(|| format!("Reading {path}"))?;
+        if path.ends_with(".edn") {
+            println!("{path}:");
+            results(&text).with_context(|| format!("Parsing {path}"))?;
+            continue;
+        }
+        for line in text.lines().filter(|line| line.starts_with('{')) {
+            match serde_json::from_str::<Value>(line) {
+                Ok(event) if event.get("event").is_some() => {
+                    for trace in event["traces"].as_array().into_iter().flatten() {
+                        let trace = trace.as_str().unwrap_or_default().to_string();
+                        traces.entry(trace).or_default().push(event.clone());
+                    }
+                }
+                Ok(msg) => {
+                    log.record(&msg);
+                    logged = true;
+                }
+                Err(e) => eprintln!("Ignoring malformed message {line}: {e}"),
+            }
+        }
+    }
+    if logged {
+        log.report();
+    }
+    for (trace, mut events) in traces {
+        events.sort_by_key(|event| event["time_ms"].as_u64());
+        let start = events[0]["time_ms"].as_u64().unwrap_or_default();
+        println!("Trace {trace}:");
+        for event in events {
+            println!(
+                "  {:>7}ms {:<4} {:<8} {:<20} {} -> {}",
+                event["time_ms"].as_u64().unwrap_or_default() - start,
+                event["node"].as_str().unwrap_or_default(),
+                event["event"].as_str().unwrap_or_default(),
+                event["type"].as_str().unwrap_or_default(),
+                event["src"].as_str().unwrap_or_default(),
+                event["dest"].as_str().unwrap_or_default(),
+            );
+        }
+    }
+    Ok(())
+}
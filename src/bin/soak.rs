@@ -0,0 +1,91 @@
+//! Runs the simulated cluster seed after seed under client load, partitions,
+//! crashes and restarts, checking after each heal that the nodes converged
+//! and that none outgrew its memory bound. Stops at the first violation and
+//! prints its seed, which replays the same run.
+//!
+//! ```text
+//! soak [--workload broadcast|g-counter|kv] [--seed N] [--runs N] [--hours H]
+//!      [--nodes N] [--sim-seconds S] [--rate R] [--memory-limit-mb M]
+//! ```
+//!
+//! Without `--runs` or `--hours` it runs for an hour.
+use std::{
+    env,
+    process::ExitCode,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use distributed_systems_challenges::{sim::soak::Soak, Workload};
+
+/// Value following `name` on the command line.
+fn arg(name: &str) -> Option<String> {
+    env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+/// Value following `name` on the command line, parsed.
+fn parsed<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    arg(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| anyhow!("Invalid {name} {value}: {e}"))
+        })
+        .transpose()
+}
+
+fn main() -> Result<ExitCode> {
+    let workload = Workload::parse(arg("--workload").as_deref().unwrap_or("broadcast"))?
+        .ok_or_else(|| anyhow!("Expected a workload to soak"))?;
+    let mut soak = Soak::new(workload)?;
+    if let Some(nodes) = parsed("--nodes")? {
+        soak.nodes = nodes;
+    }
+    if let Some(seconds) = parsed("--sim-seconds")? {
+        soak.duration = Duration::from_secs(seconds);
+    }
+    if let Some(rate) = parsed("--rate")? {
+        soak.rate = rate;
+    }
+    if let Some(mb) = parsed::<usize>("--memory-limit-mb")? {
+        soak.memory_limit = mb << 20;
+    }
+    let first: u64 = parsed("--seed")?.unwrap_or(1);
+    let runs: Option<u64> = parsed("--runs")?;
+    let hours: Option<f64> = parsed("--hours")?;
+    let deadline = match (runs, hours) {
+        (Some(_), None) => None,
+        (_, hours) => Some(Instant::now() + Duration::from_secs_f64(hours.unwrap_or(1.0) * 3600.0)),
+    };
+
+    let mut seed = first;
+    loop {
+        if runs.is_some_and(|runs| seed - first >= runs)
+            || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            break;
+        }
+        match soak.run(seed) {
+            Ok(report) => println!("Seed {seed}: {report}"),
+            Err(violation) => {
+                println!("{violation}");
+                println!(
+                    "Replay with: soak --workload {} --seed {seed} --runs 1 --nodes {} \
+                     --sim-seconds {}",
+                    arg("--workload").as_deref().unwrap_or("broadcast"),
+                    soak.nodes,
+                    soak.duration.as_secs()
+                );
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        seed += 1;
+    }
+    println!("Seeds {first}..{seed} upheld every invariant");
+    Ok(ExitCode::SUCCESS)
+}
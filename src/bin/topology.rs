@@ -0,0 +1,112 @@
+//! Prints the overlay a node would gossip over, how deep it is and how long
+//! a broadcast value should take to reach every node.
+//!
+//! ```text
+//! topology [FILE | --nodes N] [--tree] [--latency-ms MS]
+//! ```
+//!
+//! FILE holds a Maelstrom `topology` message, or just its `topology` map;
+//! `-` reads it from stdin. With `--nodes N` the nodes are n0..n(N-1) and,
+//! as no topology is given, each gossips to all others. `--tree` replaces
+//! the overlay with the one `--repair-topology` would propose.
+use std::{env, fs, io};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+
+use distributed_systems_challenges::{
+    node_index::NodeIndex,
+    topology::{mesh, tree, Analysis, Topology},
+    GOSSIP_INTERVAL,
+};
+
+/// Value following `name` on the command line.
+fn arg(name: &str) -> Option<String> {
+    env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+/// First argument that is neither a flag nor a flag's value.
+fn path() -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tree" => {}
+            "--nodes" | "--latency-ms" => {
+                args.next();
+            }
+            _ => return Some(arg),
+        }
+    }
+    None
+}
+
+fn read(path: &str) -> Result<Topology> {
+    let text = if path == "-" {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(path).with_context(|| format!("Reading {path}"))?
+    };
+    let mut json: Value = serde_json::from_str(&text)?;
+    // Whole message, its body, or the map itself
+    for field in ["body", "topology"] {
+        if let Some(inner) = json.get_mut(field) {
+            json = inner.take();
+        }
+    }
+    Ok(serde_json::from_value(json)?)
+}
+
+fn main() -> Result<()> {
+    let mut topology = match (path(), arg("--nodes")) {
+        (Some(path), None) => read(&path)?,
+        (None, Some(count)) => {
+            let count: usize = count
+                .parse()
+                .map_err(|e| anyhow!("Invalid --nodes {count}: {e}"))?;
+            mesh(&(0..count).map(|i| format!("n{i}")).collect::<Vec<_>>())
+        }
+        _ => return Err(anyhow!("Expected a topology file or --nodes N")),
+    };
+    if env::args().any(|arg| arg == "--tree") {
+        topology = tree(&NodeIndex::new(topology.into_keys()));
+    }
+    let latency: u64 = match arg("--latency-ms") {
+        None => 100,
+        Some(ms) => ms
+            .parse()
+            .map_err(|e| anyhow!("Invalid --latency-ms {ms}: {e}"))?,
+    };
+
+    let mut nodes: Vec<&String> = topology.keys().collect();
+    nodes.sort();
+    println!("Overlay:");
+    for node in nodes {
+        let mut neighbors = topology[node].clone();
+        neighbors.sort();
+        println!("  {node} -> {}", neighbors.join(", "));
+    }
+
+    let analysis = Analysis::of(&topology);
+    let degrees: Vec<usize> = analysis.degrees.values().copied().collect();
+    println!("Nodes: {}", degrees.len());
+    println!(
+        "Degree: min {}, max {}, mean {:.1}",
+        degrees.iter().min().unwrap_or(&0),
+        degrees.iter().max().unwrap_or(&0),
+        degrees.iter().sum::<usize>() as f64 / degrees.len().max(1) as f64,
+    );
+    let (Some(depth), Some(diameter)) = (analysis.depth, analysis.diameter) else {
+        println!("Not connected: some values never reach every node");
+        return Ok(());
+    };
+    println!("Depth: {depth}, diameter: {diameter}");
+
+    // Each hop waits for the next gossip round, half a round on average
+    let interval = GOSSIP_INTERVAL.as_millis() as u64;
+    println!(
+        "Propagation: ~{}ms typical, {}ms worst (gossip every {interval}ms, {latency}ms per message)",
+        diameter as u64 * (interval / 2 + latency),
+        diameter as u64 * (interval + latency),
+    );
+    Ok(())
+}
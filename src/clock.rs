@@ -0,0 +1,82 @@
+#[cfg(any(test, feature = "sim"))]
+use std::{cell::Cell, time::Duration};
+use std::{
+    rc::Rc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Source of the current time for everything timer driven (gossip, retries,
+/// backoff), so tests can move time forward by hand instead of sleeping.
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time in Unix milliseconds, for deadlines other nodes
+    /// have to agree on, like kv expiry.
+    fn unix_ms(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_ms(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+}
+
+impl Default for Box<dyn Clock> {
+    fn default() -> Self {
+        Box::new(SystemClock)
+    }
+}
+
+/// Clock that only moves when `advance` is called.
+#[cfg(any(test, feature = "sim"))]
+pub struct ManualClock {
+    start: Instant,
+    now: Cell<Instant>,
+}
+
+#[cfg(any(test, feature = "sim"))]
+impl ManualClock {
+    pub fn new() -> Self {
+        let start = Instant::now();
+        Self {
+            start,
+            now: Cell::new(start),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+#[cfg(any(test, feature = "sim"))]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+
+    /// Starts at the epoch, so wall time is how far the clock was advanced.
+    fn unix_ms(&self) -> u64 {
+        (self.now.get() - self.start).as_millis() as u64
+    }
+}
+
+/// Lets a test or the simulator keep a handle on the clock it gives to a
+/// node.
+impl<C: Clock> Clock for Rc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn unix_ms(&self) -> u64 {
+        (**self).unix_ms()
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{anyhow, Result};
+
+use crate::{base64, seen_set::SeenSet};
+
+/// Packs a set of broadcast values into a base64 string. Each run is
+/// written as two LEB128 varints, the gap since the end of the previous run
+/// and the run's length minus one, so dense ranges take a few bytes however
+/// many values they hold. Neither gzip nor zstd is in the dependency tree,
+/// and on sorted integer sets this does better than either would anyway.
+pub fn pack(set: &SeenSet) -> String {
+    let mut bytes = Vec::new();
+    let mut next = 0;
+    for &(start, end) in set.runs() {
+        write_varint(&mut bytes, start - next);
+        write_varint(&mut bytes, end - start);
+        next = end.saturating_add(1);
+    }
+    base64::encode(&bytes)
+}
+
+pub fn unpack(packed: &str) -> Result<SeenSet> {
+    let bytes = base64::decode(packed)?;
+    let mut input = bytes.as_slice();
+    let mut runs = Vec::new();
+    let mut next: usize = 0;
+    while !input.is_empty() {
+        let start = next
+            .checked_add(read_varint(&mut input)?)
+            .ok_or_else(|| anyhow!("Run start overflows"))?;
+        let end = start
+            .checked_add(read_varint(&mut input)?)
+            .ok_or_else(|| anyhow!("Run end overflows"))?;
+        runs.push((start, end));
+        next = end.saturating_add(1);
+    }
+    Ok(SeenSet::from_runs(runs))
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Result<usize> {
+    let mut value: usize = 0;
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = input
+            .split_first()
+            .ok_or_else(|| anyhow!("Truncated varint"))?;
+        *input = rest;
+        let bits = usize::from(byte & 0x7f);
+        if bits.checked_shl(shift).map(|b| b >> shift) != Some(bits) {
+            return Err(anyhow!("Varint overflows"));
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Varint too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_round_trips() {
+        let sets: [SeenSet; 4] = [
+            SeenSet::new(),
+            [0].into_iter().collect(),
+            (0..5000).chain(6000..6001).chain([usize::MAX]).collect(),
+            (0..300).map(|i| i * i).collect(),
+        ];
+        for set in sets {
+            assert_eq!(unpack(&pack(&set)).unwrap(), set);
+        }
+        assert!(unpack("not base64!").is_err());
+        assert!(unpack("gA==").is_err());
+    }
+}
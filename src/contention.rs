@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+/// How often CAS contention is logged, when any CAS was sent.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Weight of the latest outcome in the recent conflict rate.
+const SMOOTHING: f64 = 0.1;
+/// Most the backoff ceiling grows by when every recent CAS conflicted.
+const MAX_BACKOFF_FACTOR: f64 = 8.0;
+/// Recent conflict rate above which adds stop speculating on a cached value.
+const SPECULATION_LIMIT: f64 = 0.5;
+
+/// Counts how CAS requests to a storage service end, and keeps a smoothed
+/// rate of recent conflicts to adapt retries to how contended the key is.
+#[derive(Debug, Default)]
+pub struct Contention {
+    successes: u64,
+    conflicts: u64,
+    retries: u64,
+    recent: f64,
+    last_report: Option<Instant>,
+}
+
+impl Contention {
+    pub fn record_success(&mut self) {
+        self.successes += 1;
+        self.recent *= 1.0 - SMOOTHING;
+    }
+
+    /// Counts a CAS that failed because the value changed underneath it.
+    pub fn record_conflict(&mut self) {
+        self.conflicts += 1;
+        self.recent = self.recent * (1.0 - SMOOTHING) + SMOOTHING;
+    }
+
+    /// Counts an op sent again after backing off, for whatever reason.
+    pub fn record_retry(&mut self) {
+        self.retries += 1;
+    }
+
+    /// What to multiply the backoff ceiling by: 1 without contention, up to
+    /// `MAX_BACKOFF_FACTOR` when every recent CAS conflicted, so nodes
+    /// fighting over a hot key spread out instead of colliding again.
+    pub fn backoff_factor(&self) -> f64 {
+        1.0 + (MAX_BACKOFF_FACTOR - 1.0) * self.recent
+    }
+
+    /// Whether a CAS from a cached value is likely to go through.
+    pub fn speculate(&self) -> bool {
+        self.recent < SPECULATION_LIMIT
+    }
+
+    /// Summary line to log, at most once per `REPORT_INTERVAL`.
+    pub fn report(&mut self, now: Instant) -> Option<String> {
+        if self.successes + self.conflicts == 0
+            || self
+                .last_report
+                .is_some_and(|last| now.duration_since(last) < REPORT_INTERVAL)
+        {
+            return None;
+        }
+        self.last_report = Some(now);
+        Some(format!(
+            "CAS contention: {} succeeded, {} conflicted, {} retries ({:.0}% conflicts recently)",
+            self.successes,
+            self.conflicts,
+            self.retries,
+            self.recent * 100.0
+        ))
+    }
+}
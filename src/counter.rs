@@ -0,0 +1,373 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How many op ids are remembered per client above its watermark.
+const OP_WINDOW: usize = 1024;
+
+/// A node's share of the counter. Only the owning node changes it, bumping
+/// `version` on every change, so replicas merge by keeping the newest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contribution {
+    pub version: u64,
+    pub total: i64,
+}
+
+/// A client's op id. Shorter ids order first and ids of the same length
+/// by their text, so ids counting up in decimal order by their number.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct OpId(pub String);
+
+impl Ord for OpId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0.len(), &self.0).cmp(&(other.0.len(), &other.0))
+    }
+}
+
+impl PartialOrd for OpId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Op ids of one client known to be applied: the run counting up from
+/// `from` to `floor`, like `c3-2` to `c3-7`, and those in `ids` above the
+/// floor. Past `OP_WINDOW` ids the lowest are folded into the floor, and
+/// ids that count up from it move it up instead of being kept, so a client
+/// numbering its ops in order costs a single id. Ids below the floor and
+/// outside the run are forgotten, applied or not, so such an op is refused
+/// rather than counted twice or acknowledged without being counted.
+/// Merging two windows never takes an op as applied that was not, and
+/// replicas that exchange theirs converge on one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpWindow {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub floor: Option<OpId>,
+    /// First id of the run up to the floor, if it is not the floor itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<OpId>,
+    #[serde(default)]
+    pub ids: BTreeSet<OpId>,
+}
+
+impl OpWindow {
+    /// Whether `op_id` was applied, or `None` if it was forgotten.
+    fn applied(&self, op_id: &OpId) -> Option<bool> {
+        if self.ids.contains(op_id) || self.in_run(op_id) {
+            Some(true)
+        } else if self.floor.as_ref().is_some_and(|floor| op_id < floor) {
+            None
+        } else {
+            Some(false)
+        }
+    }
+
+    fn in_run(&self, op_id: &OpId) -> bool {
+        let (Some(floor), Some(start)) = (&self.floor, self.start()) else {
+            return false;
+        };
+        if op_id == floor {
+            return true;
+        }
+        match (start.number(), floor.number(), op_id.number()) {
+            (Some((prefix, from)), Some((_, to)), Some((id_prefix, n))) => {
+                prefix == id_prefix && (from..=to).contains(&n)
+            }
+            _ => false,
+        }
+    }
+
+    fn start(&self) -> Option<&OpId> {
+        self.from.as_ref().or(self.floor.as_ref())
+    }
+
+    fn insert(&mut self, op_id: OpId) {
+        self.ids.insert(op_id);
+        self.trim();
+    }
+
+    /// Folds in another replica's window, returning whether this one
+    /// changed.
+    fn merge(&mut self, mut other: OpWindow) -> bool {
+        let before = self.clone();
+        if other.floor > self.floor {
+            std::mem::swap(self, &mut other);
+        }
+        // The lower run carries on the higher one if it reaches it
+        if let (Some(floor), Some(start)) = (&other.floor, other.start()) {
+            let reaches = self.in_run(floor) || floor.next().as_ref() == self.start();
+            if reaches && Some(start) < self.start() {
+                self.from = Some(start.clone());
+            }
+        }
+        let floor = self.floor.clone();
+        self.ids.retain(|id| Some(id) > floor.as_ref());
+        self.ids
+            .extend(other.ids.into_iter().filter(|id| Some(id) > floor.as_ref()));
+        self.trim();
+        *self != before
+    }
+
+    // Raises the floor past the lowest ids until at most OP_WINDOW are left,
+    // then over the ids that follow on from it
+    fn trim(&mut self) {
+        while self.ids.len() > OP_WINDOW {
+            let lowest = self.ids.pop_first();
+            self.raise(lowest);
+        }
+        while let Some(next) = self.floor.as_ref().and_then(OpId::next) {
+            if !self.ids.remove(&next) {
+                break;
+            }
+            self.raise(Some(next));
+        }
+    }
+
+    // Moves the floor up to `op_id`, carrying the run on if it follows on
+    // from the floor and starting a new one otherwise
+    fn raise(&mut self, op_id: Option<OpId>) {
+        if self.floor.as_ref().and_then(OpId::next) == op_id {
+            self.from = self.from.take().or(self.floor.take());
+        } else {
+            self.from = None;
+        }
+        self.floor = op_id;
+    }
+}
+
+impl OpId {
+    /// The id after this one, counting up its trailing number, if it ends
+    /// in one.
+    fn next(&self) -> Option<OpId> {
+        let (prefix, number) = self.number()?;
+        Some(OpId(format!("{prefix}{}", number.checked_add(1)?)))
+    }
+
+    /// The id split into its prefix and trailing number, if it ends in one
+    /// written without leading zeros, as `next` writes them.
+    fn number(&self) -> Option<(&str, u64)> {
+        let prefix = self.0.trim_end_matches(|c: char| c.is_ascii_digit());
+        let digits = &self.0[prefix.len()..];
+        if digits.len() > 1 && digits.starts_with('0') {
+            return None;
+        }
+        Some((prefix, digits.parse().ok()?))
+    }
+}
+
+/// Why `Counter::add` did not apply a delta.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    /// Applying it would overflow the counter, whose value this is.
+    Overflow(i64),
+    /// Its op id is below the client's window and not known to be
+    /// applied, so whether it was cannot be told.
+    Forgotten,
+}
+
+/// Full replicated state, exchanged between nodes on every gossip tick.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CounterState {
+    pub totals: HashMap<String, Contribution>,
+    pub applied: HashMap<String, OpWindow>,
+}
+
+/// Signed G-counter made of per-node contributions, plus a replicated
+/// per-client window of applied op ids so a retried `add` is not counted
+/// twice even if the retry lands on another node. Two nodes receiving the
+/// same op before either has gossiped it can still both apply it.
+#[derive(Debug, Default)]
+pub struct Counter {
+    node_id: String,
+    totals: HashMap<String, Contribution>,
+    applied: HashMap<String, OpWindow>,
+}
+
+impl Counter {
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Sum of every node's contribution, or `None` if it does not fit in
+    /// an `i64`.
+    pub fn value(&self) -> Option<i64> {
+        self.totals
+            .values()
+            .try_fold(0i64, |sum, c| sum.checked_add(c.total))
+    }
+
+    /// Folds `delta` into this node's contribution unless `client` already
+    /// had `op_id` applied. Returns whether the delta was applied, or why
+    /// it was refused.
+    pub fn add(
+        &mut self,
+        client: &str,
+        op_id: Option<String>,
+        delta: i64,
+    ) -> Result<bool, Refused> {
+        let op_id = op_id.map(OpId);
+        if let Some(op_id) = &op_id {
+            let window = self.applied.get(client);
+            match window.map_or(Some(false), |window| window.applied(op_id)) {
+                Some(true) => return Ok(false),
+                Some(false) => {}
+                None => return Err(Refused::Forgotten),
+            }
+        }
+
+        let value = self.value().unwrap_or(i64::MAX);
+        let own = self.totals.get(&self.node_id).copied().unwrap_or_default();
+        let total = match (own.total.checked_add(delta), value.checked_add(delta)) {
+            (Some(total), Some(_)) => total,
+            _ => return Err(Refused::Overflow(value)),
+        };
+
+        self.totals.insert(
+            self.node_id.clone(),
+            Contribution {
+                version: own.version + 1,
+                total,
+            },
+        );
+        if let Some(op_id) = op_id {
+            self.applied
+                .entry(client.to_string())
+                .or_default()
+                .insert(op_id);
+        }
+        Ok(true)
+    }
+
+    /// Approximate bytes held by the totals and applied op ids.
+    pub fn memory(&self) -> usize {
+        let applied: usize = self
+            .applied
+            .iter()
+            .flat_map(|(client, window)| {
+                let ids = (window.floor.iter().chain(&window.from))
+                    .chain(&window.ids)
+                    .map(|id| &id.0);
+                std::iter::once(client).chain(ids)
+            })
+            .map(|id| id.len() + size_of::<String>())
+            .sum();
+        self.totals.len() * size_of::<(String, Contribution)>() + applied
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.totals.is_empty()
+    }
+
+    pub fn state(&self) -> CounterState {
+        CounterState {
+            totals: self.totals.clone(),
+            applied: self.applied.clone(),
+        }
+    }
+
+    /// Folds in another replica's state, returning whether anything here
+    /// changed.
+    pub fn merge(&mut self, state: CounterState) -> bool {
+        let mut changed = false;
+        for (node, theirs) in state.totals {
+            let ours = self.totals.entry(node).or_default();
+            if theirs.version > ours.version {
+                *ours = theirs;
+                changed = true;
+            }
+        }
+        for (client, window) in state.applied {
+            changed |= self.applied.entry(client).or_default().merge(window);
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(ids: std::ops::RangeInclusive<usize>) -> OpWindow {
+        let mut window = OpWindow::default();
+        for id in ids {
+            window.insert(OpId(id.to_string()));
+        }
+        window
+    }
+
+    #[test]
+    fn stale_window_merges_without_forgetting_newer_ops() {
+        let mut newer = Counter::new("n1");
+        newer.applied.insert("c1".to_string(), window(1..=1025));
+        let stale = CounterState {
+            totals: HashMap::new(),
+            applied: HashMap::from([("c1".to_string(), window(1..=1024))]),
+        };
+
+        assert!(!newer.merge(stale.clone()));
+        assert_eq!(newer.add("c1", Some("1025".to_string()), 1), Ok(false));
+        assert_eq!(newer.add("c1", Some("1".to_string()), 1), Ok(false));
+
+        // Either way round, both end up with the same window
+        let mut older = Counter::new("n2");
+        older.merge(stale);
+        assert!(older.merge(newer.state()));
+        assert_eq!(older.state().applied, newer.state().applied);
+        assert!(!older.merge(newer.state()));
+    }
+
+    #[test]
+    fn ops_counting_up_compact_into_the_floor_and_survive_a_restart() {
+        let mut counter = Counter::new("n1");
+        for op in 1..=OP_WINDOW + 100 {
+            assert_eq!(counter.add("c1", Some(format!("c1-{op}")), 1), Ok(true));
+        }
+        counter.add("c1", Some("c1-2000".to_string()), 1).unwrap();
+        let window = &counter.state().applied["c1"];
+        assert_eq!(window.floor, Some(OpId(format!("c1-{}", OP_WINDOW + 100))));
+        assert_eq!(window.ids.len(), 1);
+
+        // As restored from a journal snapshot
+        let json = serde_json::to_string(&counter.state()).unwrap();
+        let mut restored = Counter::new("n1");
+        restored.merge(serde_json::from_str(&json).unwrap());
+        assert_eq!(restored.add("c1", Some("c1-7".to_string()), 1), Ok(false));
+        assert_eq!(
+            restored.add("c1", Some("c1-2000".to_string()), 1),
+            Ok(false)
+        );
+        assert_eq!(restored.add("c1", Some("c1-1999".to_string()), 1), Ok(true));
+        assert_eq!(restored.value(), Some(OP_WINDOW as i64 + 102));
+    }
+
+    #[test]
+    fn ops_below_the_window_never_seen_are_refused() {
+        let mut counter = Counter::new("n1");
+        counter.applied.insert("c1".to_string(), window(10..=1034));
+        counter.add("c1", Some("c1-a".to_string()), 1).unwrap();
+        assert_eq!(counter.add("c1", Some("10".to_string()), 1), Ok(false));
+        assert_eq!(
+            counter.add("c1", Some("5".to_string()), 1),
+            Err(Refused::Forgotten)
+        );
+        assert_eq!(
+            counter.add("c1", Some("0010".to_string()), 1),
+            Err(Refused::Forgotten)
+        );
+        assert_eq!(counter.value(), Some(1));
+
+        // A run reaching another's floor carries it down
+        let mut lower = OpWindow::default();
+        lower.merge(window(1..=1025));
+        lower.merge(counter.state().applied["c1"].clone());
+        assert_eq!(lower.applied(&OpId("5".to_string())), Some(true));
+        assert_eq!(lower.from, Some(OpId("1".to_string())));
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::Bound,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The `seq`th change `node` made to a map. Every key remembers the dots
+/// that wrote it, and every replica the highest dot it has seen from each
+/// node, which is how a merge tells a key another replica removed from one
+/// it has not heard of yet.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Dot {
+    pub node: String,
+    pub seq: u64,
+}
+
+/// Combines two values of a key under `Resolution::Custom`.
+pub type Combine = Arc<dyn Fn(&Value, &Value) -> Value + Send + Sync>;
+
+/// How concurrent writes to the keys of a key-space are settled.
+#[derive(Clone, Default)]
+pub enum Resolution {
+    /// The write with the latest Lamport time wins.
+    #[default]
+    Lww,
+    /// The largest number written wins, so the value only grows. Values
+    /// that are not numbers lose to ones that are, and among themselves
+    /// fall back to `Lww`.
+    Max,
+    /// The key is a counter that `add` adds to, keeping every node's adds.
+    Add,
+    /// Concurrent values are combined with the function, which has to be
+    /// commutative, associative and idempotent for replicas to converge.
+    Custom(Combine),
+}
+
+impl Resolution {
+    /// Parses a policy name; `Custom` ones can only be set from code.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "lww" => Some(Resolution::Lww),
+            "max" => Some(Resolution::Max),
+            "add" => Some(Resolution::Add),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resolution::Lww => f.write_str("Lww"),
+            Resolution::Max => f.write_str("Max"),
+            Resolution::Add => f.write_str("Add"),
+            Resolution::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// The value of one key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Register {
+    /// Last writer wins, ordered by Lamport time and then by node.
+    Lww {
+        value: Value,
+        time: u64,
+        node: String,
+        /// Unix time in milliseconds from which the key reads as missing,
+        /// set by the latest write.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+    },
+    /// Each node's `(version, total)`, which only that node changes.
+    Counter {
+        totals: BTreeMap<String, (u64, i64)>,
+    },
+}
+
+impl Register {
+    pub fn value(&self) -> Value {
+        match self {
+            Register::Lww { value, .. } => value.clone(),
+            Register::Counter { totals } => totals
+                .values()
+                .fold(0i64, |sum, (_, total)| sum.saturating_add(*total))
+                .into(),
+        }
+    }
+
+    /// Whether the value expired by `now`, in Unix milliseconds. Counters
+    /// never do.
+    pub fn expired(&self, now: u64) -> bool {
+        matches!(self, Register::Lww { expires: Some(expires), .. } if *expires <= now)
+    }
+
+    /// Folds in another replica's value of the same key, settling two LWW
+    /// values with `resolution`, and returns whether this one changed. A
+    /// key written as both kinds at once keeps the LWW value, so every
+    /// replica picks the same one. A value expired by `now` counts as
+    /// absent, so it cannot outlive its ttl by winning the merge.
+    fn merge(&mut self, other: Register, resolution: &Resolution, now: u64) -> bool {
+        match (self.expired(now), other.expired(now)) {
+            (true, false) => {
+                *self = other;
+                return true;
+            }
+            (false, true) => return false,
+            _ => {}
+        }
+        match (&mut *self, other) {
+            (Register::Counter { totals }, Register::Counter { totals: theirs }) => {
+                let mut changed = false;
+                for (node, (version, total)) in theirs {
+                    let ours = totals.entry(node).or_default();
+                    if version > ours.0 {
+                        *ours = (version, total);
+                        changed = true;
+                    }
+                }
+                changed
+            }
+            (Register::Lww { .. }, Register::Counter { .. }) => false,
+            (Register::Counter { .. }, other) => {
+                *self = other;
+                true
+            }
+            (
+                Register::Lww {
+                    value,
+                    time,
+                    node,
+                    expires,
+                },
+                Register::Lww {
+                    value: their_value,
+                    time: their_time,
+                    node: their_node,
+                    expires: their_expires,
+                },
+            ) => {
+                let newer = (their_time, &their_node) > (*time, &*node);
+                let merged = match resolution {
+                    Resolution::Custom(combine) => combine(value, &their_value),
+                    Resolution::Max => match compare_max(&their_value, value) {
+                        Ordering::Greater => their_value,
+                        Ordering::Equal if newer => their_value,
+                        _ => value.clone(),
+                    },
+                    Resolution::Lww | Resolution::Add if newer => their_value,
+                    Resolution::Lww | Resolution::Add => value.clone(),
+                };
+                let changed = merged != *value || newer;
+                *value = merged;
+                if newer {
+                    (*time, *node, *expires) = (their_time, their_node, their_expires);
+                }
+                changed
+            }
+        }
+    }
+}
+
+/// Order of values under `Resolution::Max`: numbers above anything else.
+fn compare_max(a: &Value, b: &Value) -> Ordering {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => Ordering::Greater,
+        (None, Some(_)) => Ordering::Less,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub dots: BTreeSet<Dot>,
+    pub value: Register,
+}
+
+/// Full replicated state, exchanged between nodes on every gossip tick.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MapState {
+    /// Highest seq seen from each node.
+    pub context: BTreeMap<String, u64>,
+    pub entries: BTreeMap<String, Entry>,
+}
+
+/// Add-wins observed-remove map from string keys to LWW or counter values.
+/// Removing a key only removes the writes this replica has seen, so a write
+/// made concurrently with the remove keeps the key, with the value that
+/// write saw. Replicas converge on the same map once they have merged each
+/// other's state, in any order and without coordinating.
+#[derive(Debug, Default)]
+pub struct CrdtMap {
+    node: String,
+    // Lamport clock for LWW writes
+    time: u64,
+    context: BTreeMap<String, u64>,
+    entries: BTreeMap<String, Entry>,
+    // Policies by key prefix
+    resolutions: Vec<(String, Resolution)>,
+}
+
+impl CrdtMap {
+    pub fn new(node: &str) -> Self {
+        Self {
+            node: node.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.entries.get(key).map(|entry| entry.value.value())
+    }
+
+    pub fn register(&self, key: &str) -> Option<&Register> {
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    /// Keys between `from` and `to` in order, with their values. Nothing is
+    /// between bounds that cross.
+    pub fn range<'a>(
+        &'a self,
+        from: Bound<&str>,
+        to: Bound<&str>,
+    ) -> impl Iterator<Item = (&'a str, &'a Register)> {
+        // `BTreeMap::range` panics on them instead
+        let crossed = match (from, to) {
+            (Bound::Excluded(from), Bound::Excluded(to)) => from >= to,
+            (
+                Bound::Included(from) | Bound::Excluded(from),
+                Bound::Included(to) | Bound::Excluded(to),
+            ) => from > to,
+            _ => false,
+        };
+        (!crossed)
+            .then(|| self.entries.range::<str, _>((from, to)))
+            .into_iter()
+            .flatten()
+            .map(|(key, entry)| (key.as_str(), &entry.value))
+    }
+
+    /// Settles writes to keys starting with `prefix` with `resolution`. The
+    /// longest matching prefix decides, and keys no prefix matches use
+    /// `Lww`. Every replica needs the same policies to converge.
+    pub fn resolve(&mut self, prefix: &str, resolution: Resolution) {
+        self.resolutions.retain(|(other, _)| other != prefix);
+        self.resolutions.push((prefix.to_string(), resolution));
+    }
+
+    pub fn resolution(&self, key: &str) -> &Resolution {
+        self.resolutions
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(&Resolution::Lww, |(_, resolution)| resolution)
+    }
+
+    /// Forgets every key and write, keeping the policies.
+    pub fn clear(&mut self) {
+        self.time = 0;
+        self.context.clear();
+        self.entries.clear();
+    }
+
+    /// Approximate bytes held, taking each entry to be the size of its JSON.
+    pub fn memory(&self) -> usize {
+        let entries: usize = self
+            .entries
+            .iter()
+            .map(|(key, entry)| key.len() + serde_json::to_vec(entry).map_or(0, |json| json.len()))
+            .sum();
+        entries + self.context.len() * size_of::<(String, u64)>()
+    }
+
+    /// Whether nothing was ever written, not even since removed.
+    pub fn is_empty(&self) -> bool {
+        self.context.is_empty()
+    }
+
+    /// Writes `value` to `key`, replacing whatever it held under `Lww` and
+    /// combining the two under the other policies. Counters under `Add`
+    /// are changed with `add` instead.
+    pub fn put(&mut self, key: &str, value: Value) {
+        self.put_until(key, value, None, 0);
+    }
+
+    /// `put` at `now` that makes `key` read as missing from `expires`, in
+    /// Unix milliseconds, until it is written again. A value that expired
+    /// by `now` is replaced rather than combined with.
+    pub fn put_until(&mut self, key: &str, value: Value, expires: Option<u64>, now: u64) {
+        self.time += 1;
+        let dot = self.next_dot();
+        let mut value = Register::Lww {
+            value,
+            time: self.time,
+            node: self.node.clone(),
+            expires,
+        };
+        if let Some(Entry {
+            value: mut current, ..
+        }) = self.entries.remove(key)
+        {
+            current.merge(value, self.resolution(key), now);
+            value = current;
+        }
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                dots: BTreeSet::from([dot]),
+                value,
+            },
+        );
+    }
+
+    /// Adds `delta` to the counter at `key`, starting it at 0 if the key
+    /// is missing or holds an LWW value.
+    pub fn add(&mut self, key: &str, delta: i64) {
+        let dot = self.next_dot();
+        let mut totals = match self.entries.remove(key) {
+            Some(Entry {
+                value: Register::Counter { totals },
+                ..
+            }) => totals,
+            _ => BTreeMap::new(),
+        };
+        // Versions come from the dot, so they keep growing after a remove
+        let total = totals.get(&self.node).map_or(0, |(_, total)| *total);
+        totals.insert(self.node.clone(), (dot.seq, total.saturating_add(delta)));
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                dots: BTreeSet::from([dot]),
+                value: Register::Counter { totals },
+            },
+        );
+    }
+
+    /// Removes `key`, returning whether it was there.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    /// Removes the keys that expired by `now`, in Unix milliseconds,
+    /// returning how many. Every replica removes them in its own time, and
+    /// as with `remove` a write they have not seen keeps the key.
+    pub fn sweep(&mut self, now: u64) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| !entry.value.expired(now));
+        before - self.entries.len()
+    }
+
+    pub fn state(&self) -> MapState {
+        MapState {
+            context: self.context.clone(),
+            entries: self.entries.clone(),
+        }
+    }
+
+    /// Folds in another replica's state at `now`, in Unix milliseconds,
+    /// returning whether anything here changed. A key survives if either
+    /// side has a write to it the other has not seen.
+    pub fn merge(&mut self, state: MapState, now: u64) -> bool {
+        let mut changed = false;
+        let mut theirs = state.entries;
+        let keys: BTreeSet<String> = self.entries.keys().chain(theirs.keys()).cloned().collect();
+        for key in keys {
+            let ours = self.entries.remove(&key);
+            let their_entry = theirs.remove(&key);
+            let merged = match (ours, their_entry) {
+                (Some(mut ours), Some(their_entry)) => {
+                    let dots: BTreeSet<Dot> = ours
+                        .dots
+                        .iter()
+                        .filter(|dot| {
+                            their_entry.dots.contains(dot) || !covers(&state.context, dot)
+                        })
+                        .chain(
+                            their_entry
+                                .dots
+                                .iter()
+                                .filter(|dot| !covers(&self.context, dot)),
+                        )
+                        .cloned()
+                        .collect();
+                    self.time = self.time.max(lww_time(&their_entry.value));
+                    changed |= dots != ours.dots;
+                    changed |= ours
+                        .value
+                        .merge(their_entry.value, self.resolution(&key), now);
+                    ours.dots = dots;
+                    ours
+                }
+                (Some(mut ours), None) => {
+                    let before = ours.dots.len();
+                    ours.dots.retain(|dot| !covers(&state.context, dot));
+                    changed |= ours.dots.len() != before;
+                    ours
+                }
+                (None, Some(mut their_entry)) => {
+                    their_entry.dots.retain(|dot| !covers(&self.context, dot));
+                    self.time = self.time.max(lww_time(&their_entry.value));
+                    changed |= !their_entry.dots.is_empty();
+                    their_entry
+                }
+                // Every key came from one side or the other
+                (None, None) => continue,
+            };
+            if !merged.dots.is_empty() {
+                self.entries.insert(key, merged);
+            }
+        }
+        for (node, seq) in state.context {
+            let ours = self.context.entry(node).or_default();
+            if seq > *ours {
+                *ours = seq;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn next_dot(&mut self) -> Dot {
+        let seq = self.context.entry(self.node.clone()).or_default();
+        *seq += 1;
+        Dot {
+            node: self.node.clone(),
+            seq: *seq,
+        }
+    }
+}
+
+/// Whether a replica with `context` has seen `dot`.
+fn covers(context: &BTreeMap<String, u64>, dot: &Dot) -> bool {
+    context.get(&dot.node).is_some_and(|seq| *seq >= dot.seq)
+}
+
+fn lww_time(register: &Register) -> u64 {
+    match register {
+        Register::Lww { time, .. } => *time,
+        Register::Counter { .. } => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_write_wins_over_remove() {
+        let (mut a, mut b) = (CrdtMap::new("n1"), CrdtMap::new("n2"));
+        a.put("x", 1.into());
+        a.put("y", 2.into());
+        b.merge(a.state(), 0);
+
+        // n1 removes both keys while n2 writes one of them again
+        a.remove("x");
+        a.remove("y");
+        b.put("y", 3.into());
+        b.add("z", 5);
+
+        let state = a.state();
+        assert!(a.merge(b.state(), 0));
+        assert!(b.merge(state, 0));
+        for map in [&a, &b] {
+            assert_eq!(map.get("x"), None);
+            assert_eq!(map.get("y"), Some(3.into()));
+            assert_eq!(map.get("z"), Some(5.into()));
+        }
+        assert_eq!(a.state(), b.state());
+        assert!(!a.merge(b.state(), 0));
+    }
+
+    #[test]
+    fn policies_settle_concurrent_writes() {
+        let maps = ["n1", "n2"].map(|node| {
+            let mut map = CrdtMap::new(node);
+            map.resolve("max:", Resolution::Max);
+            map.resolve("hits:", Resolution::Add);
+            map.resolve(
+                "tags:",
+                Resolution::Custom(Arc::new(|a, b| {
+                    let mut tags: BTreeSet<String> = serde_json::from_value(a.clone()).unwrap();
+                    tags.extend(serde_json::from_value::<Vec<String>>(b.clone()).unwrap());
+                    serde_json::to_value(tags).unwrap()
+                })),
+            );
+            map
+        });
+        let [mut a, mut b] = maps;
+        a.put("max:x", 7.into());
+        b.put("max:x", 3.into());
+        a.put("lww", 1.into());
+        b.put("lww", 2.into());
+        a.add("hits:x", 2);
+        b.add("hits:x", 5);
+        a.put("tags:x", serde_json::json!(["a"]));
+        b.put("tags:x", serde_json::json!(["b"]));
+
+        let state = a.state();
+        a.merge(b.state(), 0);
+        b.merge(state, 0);
+        for map in [&a, &b] {
+            assert_eq!(map.get("max:x"), Some(7.into()));
+            assert_eq!(map.get("lww"), Some(2.into()));
+            assert_eq!(map.get("hits:x"), Some(7.into()));
+            assert_eq!(map.get("tags:x"), Some(serde_json::json!(["a", "b"])));
+        }
+
+        // A smaller write does not lower a max key
+        a.put("max:x", 1.into());
+        assert_eq!(a.get("max:x"), Some(7.into()));
+    }
+
+    #[test]
+    fn expired_values_lose_to_later_writes() {
+        let tags = Resolution::Custom(Arc::new(|a, b| match (a.as_array(), b.as_array()) {
+            (Some(a), Some(b)) => a.iter().chain(b).cloned().collect(),
+            _ => b.clone(),
+        }));
+        let maps = ["n1", "n2"].map(|node| {
+            let mut map = CrdtMap::new(node);
+            map.resolve("max:", Resolution::Max);
+            map.resolve("tags:", tags.clone());
+            map
+        });
+        let [mut a, mut b] = maps;
+        a.put_until("max:x", 10.into(), Some(10), 5);
+        a.put_until("tags:x", serde_json::json!(["a"]), Some(10), 5);
+        b.put_until("max:y", 10.into(), Some(10), 5);
+        b.merge(a.state(), 5);
+
+        // Written again after the ttl, before any sweep
+        a.put_until("max:x", 3.into(), None, 10);
+        a.put_until("tags:x", serde_json::json!(["b"]), None, 10);
+        assert_eq!(a.get("max:x"), Some(3.into()));
+        assert_eq!(a.get("tags:x"), Some(serde_json::json!(["b"])));
+
+        // Merging the expired value does not bring it back either way
+        a.put_until("max:y", 3.into(), None, 20);
+        let state = a.state();
+        a.merge(b.state(), 20);
+        b.merge(state, 20);
+        for map in [&a, &b] {
+            assert_eq!(map.get("max:x"), Some(3.into()));
+            assert_eq!(map.get("max:y"), Some(3.into()));
+            assert_eq!(map.get("tags:x"), Some(serde_json::json!(["b"])));
+        }
+    }
+}
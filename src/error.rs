@@ -0,0 +1,159 @@
+use std::fmt;
+
+use crate::{Body, Message, NodePayload, Payload};
+
+/// What can go wrong handling a message, by the Maelstrom error code it is
+/// reported with. Everything but `Storage` is sent back to the sender as an
+/// `error` reply; a storage failure leaves the journal behind the node's
+/// state, so it stops the node instead.
+#[derive(Debug)]
+pub enum Error {
+    Timeout(String),
+    NodeNotFound(String),
+    NotSupported(String),
+    TemporarilyUnavailable(String),
+    /// Sent to a node that cannot serve the request because another node
+    /// leads, which may be named.
+    NotLeader {
+        leader: Option<String>,
+    },
+    Malformed(String),
+    Crash(String),
+    Abort(String),
+    KeyDoesNotExist(String),
+    KeyAlreadyExists(String),
+    PreconditionFailed(String),
+    Conflict(String),
+    WrongDestination,
+    AlreadyInitialized,
+    Storage(anyhow::Error),
+    /// An error code from another node or service that is none of the
+    /// above.
+    Other {
+        code: usize,
+        text: String,
+    },
+}
+
+impl Error {
+    /// A field of the request that serde accepted but that is still wrong.
+    pub fn malformed(field: &str, problem: impl fmt::Display) -> Self {
+        Error::Malformed(format!("Malformed field `{field}`: {problem}"))
+    }
+
+    /// The error an `error` reply with `code` stands for.
+    pub fn from_wire(code: usize, text: String) -> Self {
+        match code {
+            0 => Error::Timeout(text),
+            1 => Error::NodeNotFound(text),
+            10 => Error::NotSupported(text),
+            11 => Error::TemporarilyUnavailable(text),
+            12 => Error::Malformed(text),
+            13 => Error::Crash(text),
+            14 => Error::Abort(text),
+            20 => Error::KeyDoesNotExist(text),
+            21 => Error::KeyAlreadyExists(text),
+            22 => Error::PreconditionFailed(text),
+            30 => Error::Conflict(text),
+            code => Error::Other { code, text },
+        }
+    }
+
+    /// Whether the request certainly did not take effect. Maelstrom treats
+    /// timeouts, crashes and codes it does not know as indefinite.
+    pub fn is_definite(&self) -> bool {
+        !matches!(
+            self,
+            Error::Timeout(_) | Error::Crash(_) | Error::Storage(_) | Error::Other { .. }
+        )
+    }
+
+    /// Whether the same request may well succeed if sent again later:
+    /// indefinite errors, and nodes that are only temporarily unable to
+    /// serve it. Definite errors like `PreconditionFailed` would just fail
+    /// again and are surfaced instead.
+    pub fn is_retryable(&self) -> bool {
+        !self.is_definite()
+            || matches!(
+                self,
+                Error::TemporarilyUnavailable(_) | Error::NotLeader { .. }
+            )
+    }
+
+    pub fn code(&self) -> usize {
+        match self {
+            Error::Timeout(_) => 0,
+            Error::NodeNotFound(_) => 1,
+            Error::NotSupported(_) => 10,
+            Error::TemporarilyUnavailable(_) | Error::NotLeader { .. } => 11,
+            Error::Malformed(_) => 12,
+            Error::Crash(_) | Error::Storage(_) => 13,
+            Error::Abort(_) => 14,
+            Error::KeyDoesNotExist(_) => 20,
+            Error::KeyAlreadyExists(_) => 21,
+            Error::PreconditionFailed(_) => 22,
+            Error::Conflict(_) => 30,
+            // 1000 and above are for our own uses
+            Error::WrongDestination => 1001,
+            Error::AlreadyInitialized => 1002,
+            Error::Other { code, .. } => *code,
+        }
+    }
+
+    /// Error reply from `src` to the request `in_reply_to` from `dst`.
+    pub(crate) fn reply(self, src: String, dst: String, in_reply_to: Option<usize>) -> Message {
+        Message {
+            src,
+            dst,
+            body: Body {
+                id: None,
+                in_reply_to,
+                payload: self.into(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Timeout(text)
+            | Error::NodeNotFound(text)
+            | Error::NotSupported(text)
+            | Error::TemporarilyUnavailable(text)
+            | Error::Malformed(text)
+            | Error::Crash(text)
+            | Error::Abort(text)
+            | Error::KeyDoesNotExist(text)
+            | Error::KeyAlreadyExists(text)
+            | Error::PreconditionFailed(text)
+            | Error::Conflict(text)
+            | Error::Other { text, .. } => f.write_str(text),
+            Error::NotLeader {
+                leader: Some(leader),
+            } => write!(f, "Not the leader, {leader} is"),
+            Error::NotLeader { leader: None } => f.write_str("Not the leader"),
+            Error::WrongDestination => f.write_str("Destination does not match this node_id"),
+            Error::AlreadyInitialized => f.write_str("Node already initialized"),
+            Error::Storage(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<Error> for Payload {
+    fn from(e: Error) -> Self {
+        NodePayload::Error {
+            code: e.code(),
+            text: e.to_string(),
+        }
+        .into()
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{workload::Context, Body, Message, Payload};
+
+/// How many replies a fan-out waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quorum {
+    /// Every peer asked, as for a 2PC vote.
+    All,
+    /// More than half of the peers asked. Callers that count their own
+    /// vote towards a cluster majority should use `Count` instead.
+    Majority,
+    Count(usize),
+}
+
+/// Where a fan-out stands after the replies seen so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Waiting,
+    /// Enough replies arrived.
+    Reached,
+    /// The deadline passed first.
+    TimedOut,
+}
+
+/// One request sent to many peers, collecting their replies until a quorum
+/// answers or a deadline passes. Replies are matched by `in_reply_to`, so
+/// duplicates and replies to other requests are ignored.
+#[derive(Debug)]
+pub struct FanOut<R> {
+    // Peer each outstanding request went to, by msg_id
+    sent: HashMap<usize, String>,
+    replies: Vec<(String, R)>,
+    needed: usize,
+    deadline: Instant,
+}
+
+impl<R> FanOut<R> {
+    /// Sends `payload` to every peer, returning the fan-out and the
+    /// requests to send.
+    pub(crate) fn send<P: Clone + Into<Payload>>(
+        ctx: &mut Context,
+        peers: &[String],
+        payload: P,
+        quorum: Quorum,
+        timeout: Duration,
+    ) -> (Self, Vec<Message>) {
+        let needed = match quorum {
+            Quorum::All => peers.len(),
+            Quorum::Majority => peers.len() / 2 + 1,
+            Quorum::Count(n) => n,
+        };
+        let mut sent = HashMap::new();
+        let msgs = peers
+            .iter()
+            .map(|peer| {
+                let id = ctx.next_msg_id();
+                sent.insert(id, peer.clone());
+                Message {
+                    src: ctx.id.clone(),
+                    dst: peer.clone(),
+                    body: Body {
+                        id: Some(id),
+                        in_reply_to: None,
+                        payload: payload.clone().into(),
+                    },
+                }
+            })
+            .collect();
+        let fanout = Self {
+            sent,
+            replies: Vec::new(),
+            needed,
+            deadline: ctx.clock.now() + timeout,
+        };
+        (fanout, msgs)
+    }
+
+    /// Records a reply, returning whether it was one we were waiting on.
+    pub fn record(&mut self, in_reply_to: Option<usize>, reply: R) -> bool {
+        match in_reply_to.and_then(|id| self.sent.remove(&id)) {
+            Some(peer) => {
+                self.replies.push((peer, reply));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn status(&self, now: Instant) -> Status {
+        if self.replies.len() >= self.needed {
+            Status::Reached
+        } else if now >= self.deadline {
+            Status::TimedOut
+        } else {
+            Status::Waiting
+        }
+    }
+
+    /// Peers that have not replied yet, in order.
+    pub fn waiting(&self) -> Vec<&str> {
+        let mut peers: Vec<&str> = self.sent.values().map(String::as_str).collect();
+        peers.sort();
+        peers
+    }
+
+    /// The replies collected, by peer, in the order they arrived.
+    pub fn into_replies(self) -> Vec<(String, R)> {
+        self.replies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, ManualClock},
+        NodePayload,
+    };
+
+    #[test]
+    fn majority_ignores_duplicates_and_times_out() {
+        let clock = Rc::new(ManualClock::new());
+        let mut ctx = Context {
+            id: "n1".to_string(),
+            clock: Box::new(clock.clone()),
+            ..Default::default()
+        };
+        let now = clock.now();
+        let peers = ["n2", "n3", "n4"].map(String::from);
+        let (mut fanout, msgs) = FanOut::send(
+            &mut ctx,
+            &peers,
+            NodePayload::InitOk {},
+            Quorum::Majority,
+            Duration::from_millis(100),
+        );
+        assert_eq!(msgs.len(), 3);
+        let ids: Vec<_> = msgs.iter().map(|msg| msg.body.id).collect();
+
+        assert!(fanout.record(ids[0], 1));
+        assert!(!fanout.record(ids[0], 1));
+        assert!(!fanout.record(Some(99), 1));
+        assert_eq!(fanout.status(now), Status::Waiting);
+        assert_eq!(
+            fanout.status(now + Duration::from_millis(100)),
+            Status::TimedOut
+        );
+
+        assert!(fanout.record(ids[2], 3));
+        assert_eq!(fanout.status(now), Status::Reached);
+        assert_eq!(fanout.waiting(), ["n3"]);
+        assert_eq!(
+            fanout.into_replies(),
+            [("n2".to_string(), 1), ("n4".to_string(), 3)]
+        );
+    }
+}
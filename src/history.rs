@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::{Context as _, Result};
+use serde_json::{json, Value};
+
+use crate::{Error, Message, RawMessage};
+
+/// Key-value operations clients ran against a node, written as JSON lines
+/// for a linearizability checker: an `invoke` line when a request arrives,
+/// and an `ok`, `fail` or `info` line when it is answered, as in a Jepsen
+/// history. Values are `[key, value]`, or `[key, [from, to]]` for a `cas`,
+/// so each key can be checked as a register of its own, as Knossos'
+/// independent checker and porcupine's partitioned models do. Times are
+/// Unix nanoseconds, to the millisecond, so the histories of every node
+/// can be merged by time.
+pub struct History {
+    file: File,
+    node: String,
+    // Requests not answered yet, by client and msg_id, with the operation
+    // and its value
+    open: HashMap<(String, usize), (String, Value)>,
+}
+
+impl History {
+    /// Appends to `path`, so a restarted node carries on the same history.
+    pub fn open(path: &Path, node: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Opening history {}", path.display()))?;
+        Ok(Self {
+            file,
+            node: node.to_string(),
+            open: HashMap::new(),
+        })
+    }
+
+    /// Records `msg` being invoked, if it is a key-value operation.
+    pub fn invoke(&mut self, msg: &RawMessage, unix_ms: u64) -> Result<()> {
+        let Some(id) = msg.body.id else {
+            return Ok(());
+        };
+        let field = |name: &str| msg.body.payload.get(name).cloned().unwrap_or_default();
+        let kind = msg.body.payload.get("type").and_then(Value::as_str);
+        let value = match kind {
+            Some("read" | "delete") => json!([field("key"), null]),
+            Some("write") => json!([field("key"), field("value")]),
+            Some("cas") => json!([field("key"), [field("from"), field("to")]]),
+            _ => return Ok(()),
+        };
+        let f = kind.unwrap_or_default().to_string();
+        self.write("invoke", &f, &msg.src, &value, unix_ms)?;
+        self.open.insert((msg.src.clone(), id), (f, value));
+        Ok(())
+    }
+
+    /// Records how `reply` completed the operation it answers, if one is
+    /// open: `fail` for an error that says it did not take effect, `info`
+    /// for one that leaves it unknown.
+    pub fn complete(&mut self, reply: &Message, unix_ms: u64) -> Result<()> {
+        let Some(in_reply_to) = reply.body.in_reply_to else {
+            return Ok(());
+        };
+        let Some((f, mut value)) = self.open.remove(&(reply.dst.clone(), in_reply_to)) else {
+            return Ok(());
+        };
+        let payload = serde_json::to_value(&reply.body.payload)?;
+        let kind = match payload["type"].as_str() {
+            Some("error") => {
+                let code = payload["code"].as_u64().unwrap_or_default() as usize;
+                match Error::from_wire(code, String::new()).is_definite() {
+                    true => "fail",
+                    false => "info",
+                }
+            }
+            _ => {
+                if f == "read" {
+                    value[1] = payload["value"].clone();
+                }
+                "ok"
+            }
+        };
+        self.write(kind, &f, &reply.dst, &value, unix_ms)
+    }
+
+    fn write(
+        &mut self,
+        kind: &str,
+        f: &str,
+        process: &str,
+        value: &Value,
+        unix_ms: u64,
+    ) -> Result<()> {
+        let line = json!({
+            "type": kind,
+            "f": f,
+            "value": value,
+            "process": process,
+            "node": self.node,
+            "time": unix_ms * 1_000_000,
+        });
+        writeln!(self.file, "{line}").context("Writing history")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(src: &str, body: Value) -> RawMessage {
+        serde_json::from_value(json!({ "src": src, "dest": "n1", "body": body })).unwrap()
+    }
+
+    fn reply(dst: &str, payload: crate::Payload) -> Message {
+        Message {
+            src: "n1".to_string(),
+            dst: dst.to_string(),
+            body: crate::Body {
+                id: None,
+                in_reply_to: Some(1),
+                payload,
+            },
+        }
+    }
+
+    #[test]
+    fn pairs_invocations_with_their_outcome() {
+        let path = std::env::temp_dir().join(format!("history-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut history = History::open(&path, "n1").unwrap();
+        let cas = json!({ "type": "cas", "msg_id": 1, "key": 3, "from": 1, "to": 2 });
+        history.invoke(&request("c1", cas), 5).unwrap();
+        let read = json!({ "type": "read", "msg_id": 1, "key": 3 });
+        history.invoke(&request("c2", read), 6).unwrap();
+        let read_ok = crate::workload::kv::Payload::ReadOk { value: json!(1) };
+        history.complete(&reply("c2", read_ok.into()), 7).unwrap();
+        let timeout = Error::Timeout(String::new()).reply("n1".into(), "c1".into(), Some(1));
+        history.complete(&timeout, 8).unwrap();
+
+        let lines: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let _ = std::fs::remove_file(&path);
+        let events: Vec<_> = lines
+            .iter()
+            .map(|line| (line["type"].as_str().unwrap(), line["value"].clone()))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("invoke", json!([3, [1, 2]])),
+                ("invoke", json!([3, null])),
+                ("ok", json!([3, 1])),
+                ("info", json!([3, [1, 2]])),
+            ]
+        );
+        assert_eq!(lines[2]["time"], 7_000_000);
+    }
+}
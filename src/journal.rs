@@ -0,0 +1,321 @@
+#[cfg(feature = "broadcast")]
+use std::collections::BTreeMap;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "counter")]
+use crate::counter::CounterState;
+#[cfg(feature = "kv")]
+use crate::crdt_map::MapState;
+#[cfg(feature = "broadcast")]
+use crate::workload::broadcast::Overlay;
+use crate::{Message, Workload};
+
+/// Entries after which the journal is folded into a single checkpoint.
+const CHECKPOINT_EVERY: usize = 10_000;
+
+/// Everything a node needs to pick up where a journal left off, written as
+/// a checkpoint in place of the changes it summarizes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub node_id: String,
+    pub node_ids: Vec<String>,
+    pub workload: Option<Workload>,
+    #[cfg(feature = "broadcast")]
+    pub neighbors: Vec<String>,
+    #[cfg(feature = "broadcast")]
+    pub messages: Vec<(usize, usize)>,
+    #[cfg(feature = "broadcast")]
+    #[serde(default)]
+    pub overlay: Overlay,
+    /// Broadcast values not yet acknowledged by every peer, in the order
+    /// first seen, as runs, so that the offsets in `acked` still hold once
+    /// restored.
+    #[cfg(feature = "broadcast")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stream: Vec<(usize, usize)>,
+    /// Each peer's ack watermark in the broadcast stream.
+    #[cfg(feature = "broadcast")]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub acked: BTreeMap<String, usize>,
+    #[cfg(feature = "counter")]
+    pub counter: CounterState,
+    #[cfg(feature = "kv")]
+    #[serde(default)]
+    pub kv: MapState,
+}
+
+/// A change to the replicated state of a node. Folding a journal's changes
+/// in order over an empty node rebuilds that state; gossip bookkeeping other
+/// than broadcast ack watermarks, and requests in flight to lin-kv, are not
+/// journaled and start over.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Change {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    Workload {
+        workload: Workload,
+    },
+    #[cfg(feature = "broadcast")]
+    Topology {
+        neighbors: Vec<String>,
+    },
+    /// Topology proposed by a node, replacing older ones.
+    #[cfg(feature = "broadcast")]
+    Overlay {
+        overlay: Overlay,
+    },
+    /// Broadcast values first seen, as inclusive runs.
+    #[cfg(feature = "broadcast")]
+    Seen {
+        runs: Vec<(usize, usize)>,
+    },
+    /// Offset in the broadcast stream before which `peer` acknowledged
+    /// every value, so a restarted node gossips it only what it lacks.
+    #[cfg(feature = "broadcast")]
+    Acked {
+        peer: String,
+        watermark: usize,
+    },
+    #[cfg(feature = "counter")]
+    CounterAdd {
+        client: String,
+        op_id: Option<String>,
+        delta: i64,
+    },
+    #[cfg(feature = "counter")]
+    CounterMerge {
+        state: CounterState,
+    },
+    #[cfg(feature = "kv")]
+    KvPut {
+        key: String,
+        value: serde_json::Value,
+        /// Unix milliseconds the value expires at, fixed when it was
+        /// written so a replay expires it at the same time.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        /// Unix milliseconds it was written at, which decides whether the
+        /// value it replaces had expired, the same in a replay.
+        #[serde(default)]
+        at: u64,
+    },
+    /// Added to a counter key.
+    #[cfg(feature = "kv")]
+    KvAdd {
+        key: String,
+        delta: i64,
+    },
+    #[cfg(feature = "kv")]
+    KvRemove {
+        key: String,
+    },
+    #[cfg(feature = "kv")]
+    KvMerge {
+        state: MapState,
+        /// Unix milliseconds it was merged at, as for `KvPut`.
+        #[serde(default)]
+        at: u64,
+    },
+    Checkpoint {
+        snapshot: Box<Snapshot>,
+    },
+    /// Changes made handling one message, written together with the
+    /// messages sent in response, so a crash before they went out is
+    /// recovered by sending them again.
+    Batch {
+        changes: Vec<Change>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        outbox: Vec<serde_json::Value>,
+    },
+    /// The outboxes written so far were sent, but for `held`, replies held
+    /// back to go out later.
+    Sent {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        held: Vec<serde_json::Value>,
+    },
+}
+
+/// Append-only log of `Change`s, kept in memory and, with `--journal`, in a
+/// file of one JSON change per line.
+#[derive(Debug, Default)]
+pub struct Journal {
+    entries: Vec<Change>,
+    file: Option<(PathBuf, BufWriter<File>)>,
+    // Changes appended since `begin`, written as one batch on `commit`
+    batch: Option<Vec<Change>>,
+    // An outbox was written and not yet marked sent
+    unsent: bool,
+    // Replies still held back when last marked sent
+    held: usize,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if needed, and returns it
+    /// with the changes already in it. A torn last line, left by a crash
+    /// halfway through a write, is dropped.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let mut entries = Vec::new();
+        match File::open(&path) {
+            Ok(file) => {
+                let lines: Vec<String> = BufReader::new(file)
+                    .lines()
+                    .collect::<Result<_, _>>()
+                    .with_context(|| format!("Reading journal {}", path.display()))?;
+                for (i, line) in lines.iter().enumerate() {
+                    match serde_json::from_str(line) {
+                        Ok(change) => entries.push(change),
+                        Err(e) if i + 1 == lines.len() => {
+                            eprintln!("Dropping torn journal entry {line}: {e}");
+                        }
+                        Err(e) => {
+                            return Err(e).with_context(|| {
+                                format!("Journal {} line {}", path.display(), i + 1)
+                            })
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Opening journal {}", path.display())),
+        }
+
+        let mut journal = Self {
+            entries,
+            ..Default::default()
+        };
+        journal.unsent = !journal.outbox().is_empty();
+        // Rewriting drops any torn line, so appends start on a fresh one
+        journal.rewrite(path)?;
+        Ok(journal)
+    }
+
+    pub fn entries(&self) -> &[Change] {
+        &self.entries
+    }
+
+    pub fn append(&mut self, change: Change) -> Result<()> {
+        if let Some(batch) = &mut self.batch {
+            batch.push(change);
+            return Ok(());
+        }
+        self.write(change)
+    }
+
+    /// Starts collecting appends into a batch, if the journal is written to
+    /// a file; kept only in memory there is nothing to recover.
+    pub fn begin(&mut self) {
+        if self.file.is_some() {
+            self.batch = Some(Vec::new());
+        }
+    }
+
+    /// Writes the changes appended since `begin` as one entry, along with
+    /// `outbox`, the messages sent in response. Messages that changed
+    /// nothing are not written, and are lost if the node crashes first.
+    pub fn commit<'a>(&mut self, outbox: impl IntoIterator<Item = &'a Message>) -> Result<()> {
+        let Some(changes) = self.batch.take() else {
+            return Ok(());
+        };
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let outbox: Vec<serde_json::Value> = outbox
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()?;
+        self.unsent |= !outbox.is_empty();
+        self.write(Change::Batch { changes, outbox })
+    }
+
+    /// Marks the outboxes written so far as sent, except for `held`,
+    /// replies that were journaled but are held back to go out later.
+    pub fn sent<'a>(&mut self, held: impl IntoIterator<Item = &'a Message>) -> Result<()> {
+        let held: Vec<serde_json::Value> = held
+            .into_iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()?;
+        if !self.unsent && held.len() == self.held {
+            return Ok(());
+        }
+        self.unsent = false;
+        self.held = held.len();
+        self.write(Change::Sent { held })
+    }
+
+    /// Messages of outboxes not marked sent.
+    pub fn outbox(&self) -> Vec<serde_json::Value> {
+        let mut outbox = Vec::new();
+        for change in &self.entries {
+            match change {
+                Change::Batch { outbox: sent, .. } => outbox.extend(sent.iter().cloned()),
+                Change::Sent { held } => outbox.clone_from(held),
+                _ => {}
+            }
+        }
+        outbox
+    }
+
+    fn write(&mut self, change: Change) -> Result<()> {
+        if let Some((path, file)) = &mut self.file {
+            serde_json::to_writer(&mut *file, &change)?;
+            writeln!(file)
+                .and_then(|()| file.flush())
+                .with_context(|| format!("Appending to journal {}", path.display()))?;
+        }
+        self.entries.push(change);
+        Ok(())
+    }
+
+    /// Whether enough changes piled up since the last checkpoint.
+    pub fn wants_checkpoint(&self) -> bool {
+        self.entries.len() >= CHECKPOINT_EVERY
+    }
+
+    /// Replaces every entry with a checkpoint holding `snapshot`, keeping
+    /// any outbox not yet sent.
+    pub fn checkpoint(&mut self, snapshot: Snapshot) -> Result<()> {
+        let outbox = self.outbox();
+        self.entries = vec![Change::Checkpoint {
+            snapshot: Box::new(snapshot),
+        }];
+        if !outbox.is_empty() {
+            self.entries.push(Change::Batch {
+                changes: Vec::new(),
+                outbox,
+            });
+        }
+        match self.file.take() {
+            Some((path, _)) => self.rewrite(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the entries to a new file that atomically replaces `path`,
+    /// then appends to that.
+    fn rewrite(&mut self, path: PathBuf) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut file = BufWriter::new(
+            File::create(&tmp).with_context(|| format!("Creating {}", tmp.display()))?,
+        );
+        for change in &self.entries {
+            serde_json::to_writer(&mut file, change)?;
+            writeln!(file)?;
+        }
+        file.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &path).with_context(|| format!("Replacing {}", path.display()))?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        self.file = Some((path, BufWriter::new(file)));
+        Ok(())
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{contention::Contention, rng::Rng, workload::counter::Payload, Body, Error, Message};
+
+const SERVICE: &str = "lin-kv";
+const KEY: &str = "counter";
+
+/// How long to wait for lin-kv before assuming the request was lost.
+const RPC_TIMEOUT: Duration = Duration::from_millis(1000);
+/// How long lin-kv has to answer the read a node sends it on startup.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const BACKOFF_BASE: Duration = Duration::from_millis(5);
+const BACKOFF_MAX: Duration = Duration::from_millis(320);
+
+#[derive(Debug, Clone, Copy)]
+enum OpKind {
+    Add(i64),
+    Read,
+}
+
+/// A client request being carried out against lin-kv.
+#[derive(Debug, Clone)]
+struct Op {
+    client: String,
+    client_msg_id: Option<usize>,
+    kind: OpKind,
+    attempt: u32,
+    // Other clients' adds batched into this one, which get the same reply
+    riders: Vec<(String, Option<usize>)>,
+    // The value a cas in flight sets, and whether its `from` came from the
+    // cache rather than a read
+    cas: Option<(i64, bool)>,
+}
+
+/// Counter kept as a single integer under one lin-kv key. Adds read the
+/// current value and CAS it to the new one, backing off and starting over
+/// when another node got there first.
+///
+/// Only one add goes to lin-kv at a time. Adds arriving meanwhile wait, and
+/// are summed into a single read and CAS on the next tick after it
+/// finishes, so a busy node needs far fewer round trips than adds.
+///
+/// Adds are speculative: while the node knows the counter's value from its
+/// last read or CAS, an add goes straight to CAS from that value. If
+/// another node changed it since, the CAS fails, the cached value is
+/// rolled back and the add starts over from a read, so an uncontended add
+/// takes one round trip instead of two.
+///
+/// How often CASes conflict is tracked; the more they do, the longer the
+/// backoff before trying again, and adds stop speculating while most fail.
+#[derive(Debug, Default)]
+pub struct KvCounter {
+    // Ops waiting on a lin-kv reply, by the msg_id of the request, with the
+    // time the request was sent
+    pending: HashMap<usize, (Instant, Op)>,
+    // Ops sleeping before their next attempt, with the time to retry at
+    backoff: Vec<(Instant, Op)>,
+    // Adds waiting for the one in flight to finish
+    waiting: Vec<Op>,
+    // Replies to batched adds, sent on the next tick
+    replies: Vec<Message>,
+    // Value of the counter as last seen, if nothing since cast doubt on it
+    cached: Option<i64>,
+    contention: Contention,
+    // The startup read of the counter, by msg_id with when it was sent,
+    // until lin-kv answers it, and how it failed if it did
+    probe: Option<(usize, Instant)>,
+    probe_error: Option<String>,
+    // Draws backoff jitter
+    rng: Rng,
+}
+
+impl KvCounter {
+    /// Counter for node `node`, whose backoff jitter is seeded from its id,
+    /// so nodes back off differently but a simulated run does the same
+    /// each time.
+    pub fn new(node: &str) -> Self {
+        let seed = node.bytes().fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+        });
+        Self {
+            rng: Rng(seed),
+            ..Default::default()
+        }
+    }
+
+    /// Approximate bytes held by ops in progress and replies not sent yet.
+    pub fn memory(&self) -> usize {
+        let ops = self.pending.len() + self.backoff.len() + self.waiting.len();
+        ops * size_of::<(Instant, Op)>() + self.replies.len() * size_of::<Message>()
+    }
+
+    pub fn add(
+        &mut self,
+        node: &str,
+        next_msg_id: &mut usize,
+        now: Instant,
+        client: String,
+        client_msg_id: Option<usize>,
+        delta: i64,
+    ) -> Option<Message> {
+        let op = Op {
+            client,
+            client_msg_id,
+            kind: OpKind::Add(delta),
+            attempt: 0,
+            riders: Vec::new(),
+            cas: None,
+        };
+        if self.adding() {
+            self.waiting.push(op);
+            return None;
+        }
+        Some(self.start(node, next_msg_id, now, op))
+    }
+
+    pub fn read(
+        &mut self,
+        node: &str,
+        next_msg_id: &mut usize,
+        now: Instant,
+        client: String,
+        client_msg_id: Option<usize>,
+    ) -> Message {
+        let op = Op {
+            client,
+            client_msg_id,
+            kind: OpKind::Read,
+            attempt: 0,
+            riders: Vec::new(),
+            cas: None,
+        };
+        self.start(node, next_msg_id, now, op)
+    }
+
+    /// Whether an add is out to lin-kv or backing off.
+    fn adding(&self) -> bool {
+        let is_add = |op: &Op| matches!(op.kind, OpKind::Add(_));
+        self.pending.values().any(|(_, op)| is_add(op))
+            || self.backoff.iter().any(|(_, op)| is_add(op))
+    }
+
+    /// Sums the waiting adds into one, leaving any that would overflow the
+    /// sum for a later batch.
+    fn batch(&mut self) -> Option<Op> {
+        let mut waiting = std::mem::take(&mut self.waiting).into_iter();
+        let mut batch = waiting.next()?;
+        for op in waiting {
+            let (OpKind::Add(total), OpKind::Add(delta)) = (&mut batch.kind, op.kind) else {
+                continue;
+            };
+            match total.checked_add(delta) {
+                Some(sum) if self.waiting.is_empty() => {
+                    *total = sum;
+                    batch.riders.push((op.client, op.client_msg_id));
+                    batch.riders.extend(op.riders);
+                }
+                _ => self.waiting.push(op),
+            }
+        }
+        Some(batch)
+    }
+
+    /// Every attempt starts by reading the current value, unless it is an
+    /// add and the value is cached.
+    fn start(&mut self, node: &str, next_msg_id: &mut usize, now: Instant, mut op: Op) -> Message {
+        op.cas = None;
+        let cached = self.cached.filter(|_| self.contention.speculate());
+        if let (OpKind::Add(delta), Some(from)) = (op.kind, cached) {
+            if let Some(to) = from.checked_add(delta) {
+                op.cas = Some((to, true));
+                return self.send(node, next_msg_id, now, op, cas_request(from, to));
+            }
+        }
+        self.send(
+            node,
+            next_msg_id,
+            now,
+            op,
+            Payload::Read {
+                key: Some(KEY.to_string()),
+            },
+        )
+    }
+
+    fn send(
+        &mut self,
+        node: &str,
+        next_msg_id: &mut usize,
+        now: Instant,
+        op: Op,
+        payload: Payload,
+    ) -> Message {
+        *next_msg_id += 1;
+        self.pending.insert(*next_msg_id, (now, op));
+        Message {
+            src: node.to_string(),
+            dst: SERVICE.to_string(),
+            body: Body {
+                id: Some(*next_msg_id),
+                in_reply_to: None,
+                payload: payload.into(),
+            },
+        }
+    }
+
+    /// Reads the counter to check lin-kv is there, before any client needs
+    /// it to be.
+    pub fn probe(&mut self, node: &str, next_msg_id: &mut usize, now: Instant) -> Message {
+        *next_msg_id += 1;
+        self.probe = Some((*next_msg_id, now));
+        Message {
+            src: node.to_string(),
+            dst: SERVICE.to_string(),
+            body: Body {
+                id: Some(*next_msg_id),
+                in_reply_to: None,
+                payload: Payload::Read {
+                    key: Some(KEY.to_string()),
+                }
+                .into(),
+            },
+        }
+    }
+
+    /// Fails if lin-kv did not answer the startup read in time, or answered
+    /// it with an error other than the counter not existing yet.
+    pub fn check_probe(&self, now: Instant) -> Result<()> {
+        if let Some(e) = &self.probe_error {
+            return Err(anyhow!("{SERVICE} failed the startup read: {e}"));
+        }
+        match self.probe {
+            Some((_, sent)) if now.duration_since(sent) >= PROBE_TIMEOUT => Err(anyhow!(
+                "{SERVICE} did not answer the startup read within {PROBE_TIMEOUT:?}; \
+                 is the workload run with the service?"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `msg` is a lin-kv reply, which is always to one of our
+    /// requests, if maybe one that was already answered or gave up on.
+    pub fn owns(&self, msg: &Message<Payload>) -> bool {
+        msg.src == SERVICE && msg.body.in_reply_to.is_some()
+    }
+
+    /// Advances the op a lin-kv reply belongs to. Returns the next request
+    /// to lin-kv or the reply to the client, if any.
+    pub fn handle(
+        &mut self,
+        node: &str,
+        next_msg_id: &mut usize,
+        now: Instant,
+        msg: Message<Payload>,
+    ) -> Option<Message> {
+        let id = msg.body.in_reply_to?;
+        if self.probe.is_some_and(|(probe, _)| probe == id) {
+            self.probe = None;
+            if let Payload::Error { code, text } = msg.body.payload {
+                match Error::from_wire(code, text) {
+                    Error::KeyDoesNotExist(_) => {}
+                    e => self.probe_error = Some(e.to_string()),
+                }
+            }
+            return None;
+        }
+        // A second reply to a request, or one that came after it timed out
+        // and was sent again under a new msg_id, says nothing about the op
+        let Some((_, op)) = self.pending.remove(&id) else {
+            eprintln!("Ignoring late or duplicate lin-kv reply to msg {id}");
+            return None;
+        };
+        if let Payload::ReadOk { value, .. } = msg.body.payload {
+            self.cached = Some(value);
+        }
+        match (msg.body.payload, op.kind) {
+            (Payload::ReadOk { value, .. }, OpKind::Read) => {
+                self.finish(node, op, Payload::ReadOk { value, debug: None }.into())
+            }
+            (Payload::ReadOk { value, .. }, OpKind::Add(delta)) => {
+                self.cas(node, next_msg_id, now, op, value, delta)
+            }
+            (Payload::CasOk {}, OpKind::Add(_)) => {
+                self.contention.record_success();
+                self.cached = op.cas.map(|(to, _)| to);
+                self.finish(node, op, Payload::AddOk {}.into())
+            }
+            (Payload::Error { code, text }, kind) => match (Error::from_wire(code, text), kind) {
+                // A missing key has never been added to
+                (Error::KeyDoesNotExist(_), OpKind::Read) => self.finish(
+                    node,
+                    op,
+                    Payload::ReadOk {
+                        value: 0,
+                        debug: None,
+                    }
+                    .into(),
+                ),
+                (Error::KeyDoesNotExist(_), OpKind::Add(delta)) => {
+                    self.cas(node, next_msg_id, now, op, 0, delta)
+                }
+                // The cached value was stale; start over from a read right
+                // away, as there was no race to lose
+                (Error::PreconditionFailed(_), OpKind::Add(_))
+                    if op.cas.is_some_and(|(_, speculative)| speculative) =>
+                {
+                    self.contention.record_conflict();
+                    self.rollback();
+                    Some(self.start(node, next_msg_id, now, op))
+                }
+                // Lost the race to another node's cas; start over from a
+                // fresh read
+                (Error::PreconditionFailed(_), OpKind::Add(_)) => {
+                    self.contention.record_conflict();
+                    self.rollback();
+                    self.retry_later(now, op);
+                    None
+                }
+                // A cas that timed out or crashed may have been applied,
+                // and doing it again could count the delta twice, so the
+                // client hears that the outcome is unknown instead
+                (e, OpKind::Add(_)) if op.cas.is_some() && !e.is_definite() => {
+                    self.rollback();
+                    self.finish(node, op, e.into())
+                }
+                (e, _) if e.is_retryable() => {
+                    self.retry_later(now, op);
+                    None
+                }
+                (e, _) => self.finish(node, op, e.into()),
+            },
+            // A reply of the wrong kind says nothing about the op
+            _ => {
+                self.retry_later(now, op);
+                None
+            }
+        }
+    }
+
+    fn cas(
+        &mut self,
+        node: &str,
+        next_msg_id: &mut usize,
+        now: Instant,
+        mut op: Op,
+        from: i64,
+        delta: i64,
+    ) -> Option<Message> {
+        match from.checked_add(delta) {
+            Some(to) => {
+                op.cas = Some((to, false));
+                Some(self.send(node, next_msg_id, now, op, cas_request(from, to)))
+            }
+            None => self.finish(
+                node,
+                op,
+                Error::PreconditionFailed(format!(
+                    "Adding {delta} to {from} overflows the counter"
+                ))
+                .into(),
+            ),
+        }
+    }
+
+    /// Forgets the cached value once a cas shows it stale or leaves the
+    /// counter's value unknown, so the next add reads it again.
+    fn rollback(&mut self) {
+        self.cached = None;
+    }
+
+    /// Replies to the client `op` came from, queueing the same reply to
+    /// the clients whose adds it carried.
+    fn finish(&mut self, node: &str, op: Op, payload: crate::Payload) -> Option<Message> {
+        for (client, client_msg_id) in op.riders {
+            self.replies
+                .push(Self::reply(node, client, client_msg_id, payload.clone()));
+        }
+        Some(Self::reply(node, op.client, op.client_msg_id, payload))
+    }
+
+    fn reply(
+        node: &str,
+        client: String,
+        client_msg_id: Option<usize>,
+        payload: crate::Payload,
+    ) -> Message {
+        Message {
+            src: node.to_string(),
+            dst: client,
+            body: Body {
+                id: None,
+                in_reply_to: client_msg_id,
+                payload,
+            },
+        }
+    }
+
+    /// Exponential backoff with full jitter, stretched while CASes keep
+    /// conflicting.
+    fn retry_later(&mut self, now: Instant, mut op: Op) {
+        let ceiling = BACKOFF_BASE
+            .saturating_mul(1 << op.attempt.min(16))
+            .mul_f64(self.contention.backoff_factor())
+            .min(BACKOFF_MAX);
+        let jitter = ceiling.mul_f64(self.rng.next_f64());
+
+        op.attempt += 1;
+        self.backoff.push((now + jitter, op));
+    }
+
+    /// Contention summary to log, at most every few seconds.
+    pub fn report(&mut self, now: Instant) -> Option<String> {
+        self.contention.report(now)
+    }
+
+    /// Restarts ops whose backoff elapsed or whose read timed out, and
+    /// sends the waiting adds as one once no other is in flight. A timed
+    /// out `cas` may still have been applied, so its add is not tried
+    /// again: its clients get a `timeout` error, leaving the outcome
+    /// unknown rather than risking counting it twice.
+    pub fn tick(&mut self, node: &str, next_msg_id: &mut usize, now: Instant) -> Vec<Message> {
+        let timed_out: Vec<usize> = self
+            .pending
+            .iter()
+            .filter(|(_, (sent, _))| now.duration_since(*sent) >= RPC_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut out = Vec::new();
+        for id in timed_out {
+            if let Some((_, op)) = self.pending.remove(&id) {
+                if op.cas.is_none() {
+                    self.retry_later(now, op);
+                    continue;
+                }
+                self.rollback();
+                let e = Error::Timeout(format!("{SERVICE} did not answer the cas in time"));
+                out.extend(self.finish(node, op, e.into()));
+            }
+        }
+
+        let (due, waiting) = self.backoff.drain(..).partition(|(at, _)| *at <= now);
+        self.backoff = waiting;
+        out.append(&mut self.replies);
+        for (_, op) in due {
+            self.contention.record_retry();
+            out.push(self.start(node, next_msg_id, now, op));
+        }
+        if !self.adding() {
+            if let Some(batch) = self.batch() {
+                out.push(self.start(node, next_msg_id, now, batch));
+            }
+        }
+        out
+    }
+}
+
+fn cas_request(from: i64, to: i64) -> Payload {
+    Payload::Cas {
+        key: KEY.to_string(),
+        from,
+        to,
+        create_if_not_exists: true,
+    }
+}
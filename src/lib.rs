@@ -0,0 +1,2858 @@
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+pub mod ack_stream;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+pub mod adaptive;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+mod auth;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+mod base64;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+mod clock;
+#[cfg(feature = "broadcast")]
+mod codec;
+#[cfg(feature = "counter")]
+mod contention;
+#[cfg(feature = "counter")]
+mod counter;
+#[cfg(feature = "kv")]
+pub mod crdt_map;
+pub mod error;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+pub mod fanout;
+#[cfg(feature = "kv")]
+mod history;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+mod journal;
+#[cfg(feature = "counter")]
+mod kv_counter;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+mod memory;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+mod msgpack;
+pub mod node_index;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+pub mod overload;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+pub mod rate_limit;
+#[cfg(feature = "broadcast")]
+mod redundancy;
+#[cfg(any(
+    feature = "counter",
+    all(feature = "sim", any(feature = "broadcast", feature = "kv"))
+))]
+mod rng;
+#[cfg(feature = "broadcast")]
+mod seen_set;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+pub mod sequence;
+#[cfg(all(
+    feature = "sim",
+    any(feature = "broadcast", feature = "counter", feature = "kv")
+))]
+pub mod sim;
+pub mod supervisor;
+#[cfg(feature = "broadcast")]
+pub mod topology;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+mod trace;
+pub mod transport;
+mod validate;
+pub mod workload;
+
+#[cfg(feature = "broadcast")]
+use std::sync::mpsc::RecvTimeoutError;
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    io::{BufRead, Write},
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::{self, Receiver, Sender},
+};
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use std::{
+    fs::OpenOptions,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use adaptive::{AdaptiveGossip, GossipController};
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use anyhow::Context as _;
+use anyhow::{anyhow, Result};
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use auth::Signer;
+pub use error::Error;
+#[cfg(feature = "kv")]
+use history::History;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use fanout::{FanOut, Quorum, Status};
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use journal::{Change, Journal, Snapshot};
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use memory::{MemoryGuard, Usage};
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use overload::{Overload, OverloadLimits, SHED_FACTOR};
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use rate_limit::{PeerLimiter, RateLimit};
+use supervisor::{Supervisor, TaskHealth};
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use trace::Tracer;
+use transport::Transport;
+#[cfg(feature = "broadcast")]
+use workload::broadcast::Broadcast;
+#[cfg(feature = "counter")]
+use workload::counter::GCounter;
+#[cfg(feature = "echo")]
+use workload::echo::Echo;
+#[cfg(feature = "kv")]
+use workload::kv::Kv;
+#[cfg(feature = "unique-ids")]
+use workload::unique_ids::UniqueIds;
+pub use workload::Workload;
+use workload::{Context, Handler};
+
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+/// How often a node gossips when not adapting its pace.
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+pub const GOSSIP_INTERVAL: Duration = Duration::from_millis(200);
+/// How long peers have to answer a node's hello before it logs those
+/// that did not.
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a node with a memory limit adds up what it holds.
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Client requests that make a node hold more, which it turns away while
+/// over its memory limit.
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+const GROWING: [&str; 5] = ["broadcast", "add", "write", "cas", "watch"];
+
+#[derive(Default)]
+struct Node {
+    ctx: Context,
+    workload: Option<Workload>,
+    #[cfg(feature = "echo")]
+    echo: Echo,
+    #[cfg(feature = "unique-ids")]
+    unique_ids: UniqueIds,
+    #[cfg(feature = "broadcast")]
+    broadcast: Broadcast,
+    #[cfg(feature = "counter")]
+    counter: GCounter,
+    #[cfg(feature = "kv")]
+    kv: Kv,
+    supervisor: Supervisor,
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    last_gossip: Option<Instant>,
+    // Set from `Config::gossip_interval`, `GOSSIP_INTERVAL` if not
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    gossip_interval: Option<Duration>,
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    limiter: PeerLimiter,
+    // Set with `--adaptive-gossip`, tuning the interval and fan-out
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    adaptive: Option<GossipController>,
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    gossip_round: usize,
+    // Peers to gossip to each round, set with `reconfigure`; all of them if
+    // not
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fanout: Option<usize>,
+    // Set with `--allow-any-source`, skipping the sender checks
+    allow_any_source: bool,
+    // Set with `--trace`
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    tracer: Option<Tracer>,
+    // Traces of the message being handled, which its reply carries
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    traces: Vec<String>,
+    // Set with a gossip key, verifying messages from other nodes
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    signer: Option<Signer>,
+    // Sequence numbers of the messages to and from each peer
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    outgoing: sequence::Outgoing<Message>,
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    incoming: sequence::Incoming,
+    // Resend requests and messages sent again, for the next tick
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    resends: Vec<Message>,
+    // Set with `--msgpack`, packing messages to the peers in `packs`, which
+    // said they read them
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    msgpack: bool,
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    packs: HashSet<String>,
+    // Set with `--memory-limit-mb`, and when it was last checked
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    memory: Option<MemoryGuard>,
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    last_memory_check: Option<Instant>,
+    // What each peer that said hello can do
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    peers: BTreeMap<String, Capabilities>,
+    // Our hello to every peer, until they all answered or it timed out
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    hellos: Option<FanOut<()>>,
+    // Set with `--history`
+    #[cfg(feature = "kv")]
+    history: Option<History>,
+    // Set with `--overload-queue-depth` or `--overload-latency-ms`
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    overload: Option<Overload>,
+}
+
+impl Node {
+    // Without gossiping workloads the context has nothing but the ids
+    #[cfg_attr(
+        not(any(feature = "broadcast", feature = "counter", feature = "kv")),
+        allow(clippy::needless_update)
+    )]
+    fn from_init(msg: RawMessage) -> Result<(Message, Option<Self>)> {
+        let msg: Message<NodePayload> = match parse(&msg) {
+            Ok(msg) => msg,
+            Err(_) => return Err(anyhow!("Message is not init type")),
+        };
+        let NodePayload::Init { node_id, node_ids } = msg.body.payload else {
+            return Err(anyhow!("Message is not init type"));
+        };
+        if let Err(e) = validate::init(&node_id, &node_ids) {
+            return Ok((e.reply(msg.dst, msg.src, msg.body.id), None));
+        }
+        let mut node = Self {
+            #[cfg(feature = "counter")]
+            counter: GCounter::new(&node_id),
+            #[cfg(feature = "kv")]
+            kv: Kv::new(&node_id),
+            ctx: Context {
+                id: node_id,
+                node_ids: node_ids.into_iter().collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let reply = node.ctx.stamp(Message {
+            src: msg.dst,
+            dst: msg.src,
+            body: Body {
+                id: None,
+                in_reply_to: msg.body.id,
+                payload: NodePayload::InitOk {}.into(),
+            },
+        });
+        Ok((reply, Some(node)))
+    }
+
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn gossip_peers(&self) -> Vec<String> {
+        // Knowing what every member has is what makes values readable
+        #[cfg(feature = "broadcast")]
+        if self.broadcast.view_sync {
+            let mut members = self.broadcast.members(&self.ctx);
+            members.retain(|id| *id != self.ctx.id);
+            return members;
+        }
+        #[cfg(feature = "broadcast")]
+        // Nodes left out of the overlay are still gossiped to, so one that
+        // comes back answers and is taken back in
+        if !self.broadcast.neighbors().is_empty() {
+            let mut peers = self.broadcast.neighbors().to_vec();
+            peers.extend(self.broadcast.left_out(&self.ctx));
+            return peers;
+        }
+        self.ctx
+            .node_ids
+            .iter()
+            .filter(|id| **id != self.ctx.id)
+            .cloned()
+            .collect()
+    }
+
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn tick(&mut self) -> Result<Vec<Message>> {
+        let now = self.ctx.clock.now();
+        let mut out = Vec::new();
+        #[cfg(feature = "counter")]
+        self.counter.check_probe(now)?;
+        self.check_hellos(now);
+        #[cfg(feature = "counter")]
+        out.extend(self.counter.tick(&mut self.ctx, now));
+        #[cfg(feature = "kv")]
+        out.extend(self.kv.tick(&self.ctx));
+        #[cfg(feature = "broadcast")]
+        if self.workload == Some(Workload::Broadcast) {
+            self.broadcast.repair(&mut self.ctx)?;
+        }
+        let mut interval = self
+            .adaptive
+            .as_ref()
+            .map_or(self.gossip_interval(), |adaptive| adaptive.interval());
+        if self.overload.as_ref().is_some_and(Overload::shedding) {
+            interval *= SHED_FACTOR;
+        }
+        let round = self
+            .last_gossip
+            .is_none_or(|last| now.duration_since(last) >= interval);
+        if round {
+            self.last_gossip = Some(now);
+            out.extend(self.gossip(now));
+        }
+        #[cfg(feature = "broadcast")]
+        if let Some(report) = self.broadcast.report(now) {
+            eprintln!("{report}");
+        }
+        #[cfg(feature = "counter")]
+        if let Some(report) = self.counter.report(now) {
+            eprintln!("{report}");
+        }
+        self.checkpoint_if_due()?;
+        self.check_memory(now);
+        let traces = match &mut self.tracer {
+            Some(tracer) if round => {
+                let traces = tracer.pending();
+                tracer.round();
+                traces
+            }
+            Some(tracer) => tracer.pending(),
+            None => Vec::new(),
+        };
+        let mut out: Vec<Message> = out
+            .into_iter()
+            .map(|msg| {
+                let msg = self.ctx.stamp(msg);
+                let msg = self.traced(msg, &traces);
+                let msg = self.number(msg);
+                self.packed(msg)
+            })
+            .collect();
+        // Replies held back were stamped when journaled, and go out once a
+        // round carried their values
+        #[cfg(feature = "broadcast")]
+        out.extend(self.broadcast.due_replies(now, round));
+        let now = self.ctx.clock.now();
+        for (peer, from, to) in self.incoming.overdue(now) {
+            self.ask_resend(peer, from, to);
+        }
+        // Messages sent again share the gossip budget, so a gap after a long
+        // partition does not flood the peer, and those over it wait for the
+        // next tick. Asking for them costs little and is what closes the
+        // gap, so it is never held back.
+        let mut waiting = Vec::new();
+        for msg in self.resends.drain(..) {
+            let asking = matches!(msg.body.payload, Payload::Node(NodePayload::Resend { .. }));
+            let bytes = serde_json::to_string(&msg).map_or(0, |line| line.len());
+            if asking || self.limiter.allow(&msg.dst, bytes, now) {
+                out.push(msg);
+            } else {
+                waiting.push(msg);
+            }
+        }
+        self.resends = waiting;
+        Ok(out)
+    }
+
+    /// Attaches `traces` to `msg` if tracing.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn traced(&mut self, msg: Message, traces: &[String]) -> Message {
+        match &mut self.tracer {
+            Some(tracer) => {
+                let peer = self.ctx.node_ids.contains(&msg.dst);
+                let now = self.ctx.clock.unix_ms();
+                tracer.send(&self.ctx.id, msg, traces, peer, now)
+            }
+            None => msg,
+        }
+    }
+
+    /// Gives a message to another node that is not a reply the next
+    /// sequence number to that node, keeping it in case it has to be sent
+    /// again.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn number(&mut self, msg: Message) -> Message {
+        if msg.body.in_reply_to.is_some() || !self.ctx.node_ids.contains(&msg.dst) {
+            return msg;
+        }
+        let Ok(serde_json::Value::Object(payload)) = serde_json::to_value(&msg.body.payload) else {
+            return msg;
+        };
+        let dst = msg.dst.clone();
+        let incarnation = self.outgoing.incarnation(self.ctx.clock.unix_ms());
+        self.outgoing.number(&dst, |seq| {
+            let mut payload = payload;
+            payload.insert(sequence::SEQ_FIELD.to_string(), seq.into());
+            payload.insert(sequence::INCARNATION_FIELD.to_string(), incarnation.into());
+            Message {
+                body: Body {
+                    payload: Payload::Raw(payload),
+                    ..msg.body
+                },
+                ..msg
+            }
+        })
+    }
+
+    /// Takes the sequence number off a message from another node, asking
+    /// for whatever it shows missing to be sent again. Returns false if
+    /// the message was received before.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn sequenced(&mut self, msg: &mut RawMessage) -> bool {
+        if !self.ctx.node_ids.contains(&msg.src) {
+            return true;
+        }
+        let incarnation = msg
+            .body
+            .payload
+            .remove(sequence::INCARNATION_FIELD)
+            .and_then(|incarnation| incarnation.as_u64());
+        let Some(seq) = msg
+            .body
+            .payload
+            .remove(sequence::SEQ_FIELD)
+            .and_then(|seq| seq.as_u64())
+        else {
+            return true;
+        };
+        let now = self.ctx.clock.now();
+        let receipt = self.incoming.receive(&msg.src, incarnation, seq, now);
+        if let Some((from, to)) = receipt.missing {
+            self.ask_resend(msg.src.clone(), from, to);
+        }
+        receipt.fresh
+    }
+
+    /// Asks `peer` to send its messages from `from` up to but not including
+    /// `to` again.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn ask_resend(&mut self, peer: String, from: u64, to: u64) {
+        let request = Message {
+            src: self.ctx.id.clone(),
+            dst: peer,
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: NodePayload::Resend { from, to }.into(),
+            },
+        };
+        let request = self.ctx.stamp(request);
+        self.resends.push(request);
+    }
+
+    /// Unpacks a packed message from another node, noting which peers read
+    /// packed messages. Returns false if it does not unpack.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn unpacked(&mut self, msg: &mut RawMessage) -> bool {
+        if !self.ctx.node_ids.contains(&msg.src) {
+            return true;
+        }
+        let packed = msg.body.payload.contains_key(msgpack::MSGPACK_FIELD);
+        if let Err(e) = msgpack::unpack(&mut msg.body.payload) {
+            eprintln!(
+                "Dropping message from {} that does not unpack: {e}",
+                msg.src
+            );
+            return false;
+        }
+        let wire = msg
+            .body
+            .payload
+            .remove(msgpack::WIRE_FIELD)
+            .and_then(|wire| wire.as_u64());
+        if packed || wire.is_some_and(|wire| wire >= msgpack::WIRE_VERSION) {
+            self.packs.insert(msg.src.clone());
+        }
+        true
+    }
+
+    /// With `--msgpack`, packs a message to a peer known to read packed
+    /// messages, and tells the others it reads them.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn packed(&self, msg: Message) -> Message {
+        if !self.msgpack || !self.ctx.node_ids.contains(&msg.dst) {
+            return msg;
+        }
+        let Ok(serde_json::Value::Object(mut payload)) = serde_json::to_value(&msg.body.payload)
+        else {
+            return msg;
+        };
+        match self.packs.contains(&msg.dst) {
+            true => msgpack::pack(&mut payload),
+            false => {
+                payload.insert(
+                    msgpack::WIRE_FIELD.to_string(),
+                    msgpack::WIRE_VERSION.into(),
+                );
+            }
+        }
+        Message {
+            body: Body {
+                payload: Payload::Raw(payload),
+                ..msg.body
+            },
+            ..msg
+        }
+    }
+
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            workloads: Workload::ALL.iter().map(|w| w.name().to_string()).collect(),
+            serving: self.workload.map(|w| w.name().to_string()),
+            signed: self.signer.is_some(),
+        }
+    }
+
+    /// Tells every peer what this node can do.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn hello(&mut self) -> Vec<Message> {
+        let hello = NodePayload::Hello(self.capabilities());
+        let mut peers: Vec<_> = self.ctx.node_ids.iter().cloned().collect();
+        peers.retain(|id| *id != self.ctx.id);
+        peers.sort();
+        let (hellos, msgs) = FanOut::send(&mut self.ctx, &peers, hello, Quorum::All, HELLO_TIMEOUT);
+        self.hellos = Some(hellos);
+        msgs
+    }
+
+    /// Logs the peers that did not answer our hello in time.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn check_hellos(&mut self, now: Instant) {
+        match self.hellos.as_ref().map(|hellos| hellos.status(now)) {
+            Some(Status::Reached) => self.hellos = None,
+            Some(Status::TimedOut) => {
+                if let Some(hellos) = self.hellos.take() {
+                    let waiting = hellos.waiting().join(", ");
+                    let answered = hellos.into_replies().len();
+                    eprintln!(
+                        "{answered} peers answered hello within {HELLO_TIMEOUT:?}, \
+                         but not {waiting}"
+                    );
+                }
+            }
+            Some(Status::Waiting) | None => {}
+        }
+    }
+
+    /// Records what a peer that said hello, or answered ours, can do,
+    /// logging how it would disagree with this node. Answers a `hello`.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn greeted(&mut self, msg: RawMessage) -> Option<Message> {
+        let msg = parse::<NodePayload>(&msg).ok()?;
+        let (theirs, answer) = match msg.body.payload {
+            NodePayload::Hello(theirs) => (theirs, true),
+            NodePayload::HelloOk(theirs) => (theirs, false),
+            _ => return None,
+        };
+        if let Some(hellos) = &mut self.hellos {
+            hellos.record(msg.body.in_reply_to, ());
+        }
+        let mine = self.capabilities();
+        for conflict in mine.conflicts(&theirs) {
+            eprintln!("Peer {} {conflict}", msg.src);
+        }
+        self.peers.insert(msg.src.clone(), theirs);
+        answer.then(|| Message {
+            src: self.ctx.id.clone(),
+            dst: msg.src,
+            body: Body {
+                id: None,
+                in_reply_to: msg.body.id,
+                payload: NodePayload::HelloOk(mine).into(),
+            },
+        })
+    }
+
+    /// Records how far behind the node is on its input, shedding gossip
+    /// while overloaded. Client replies are never shed.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn observe_load(&mut self, depth: usize, latency: Duration) {
+        let Some(overload) = &mut self.overload else {
+            return;
+        };
+        #[cfg_attr(not(feature = "broadcast"), allow(unused_variables))]
+        let shedding = overload.observe(depth, latency, self.ctx.clock.now());
+        #[cfg(feature = "broadcast")]
+        {
+            self.broadcast.shedding = shedding;
+        }
+    }
+
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn gossip_interval(&self) -> Duration {
+        self.gossip_interval.unwrap_or(GOSSIP_INTERVAL)
+    }
+
+    /// Applies the tunables a `reconfigure` request sets, replying with
+    /// all of them as they now are.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn reconfigure(&mut self, msg: RawMessage) -> Option<Message> {
+        let request = match parse::<NodePayload>(&msg) {
+            Ok(Message {
+                body:
+                    Body {
+                        payload: NodePayload::Reconfigure(request),
+                        ..
+                    },
+                ..
+            }) => request,
+            Ok(_) => return reject(&self.ctx.id, msg, unsupported()),
+            Err(e) => return reject(&self.ctx.id, msg, Error::Malformed(e.to_string())),
+        };
+        if request.gossip_interval_ms == Some(0)
+            || request.fanout == Some(0)
+            || request.batch_size == Some(0)
+        {
+            let e = Error::Malformed("Tunables must be positive".to_string());
+            return reject(&self.ctx.id, msg, e);
+        }
+        if let Some(ms) = request.gossip_interval_ms {
+            self.gossip_interval = Some(Duration::from_millis(ms));
+            if let Some(adaptive) = &mut self.adaptive {
+                adaptive.restart(Duration::from_millis(ms));
+            }
+        }
+        if request.fanout.is_some() {
+            self.fanout = request.fanout;
+        }
+        #[cfg(feature = "broadcast")]
+        if request.batch_size.is_some() {
+            self.broadcast.gossip_chunk = request.batch_size;
+        }
+        eprintln!("Reconfigured: {request:?}");
+        Some(Message {
+            src: self.ctx.id.clone(),
+            dst: msg.src,
+            body: Body {
+                id: None,
+                in_reply_to: msg.body.id,
+                payload: NodePayload::ReconfigureOk(Tunables {
+                    gossip_interval_ms: Some(self.gossip_interval().as_millis() as u64),
+                    fanout: self.fanout,
+                    #[cfg(feature = "broadcast")]
+                    batch_size: self.broadcast.gossip_chunk,
+                    #[cfg(not(feature = "broadcast"))]
+                    batch_size: None,
+                })
+                .into(),
+            },
+        })
+    }
+
+    /// Gossips the state of whichever workload is being served, within
+    /// each peer's rate limit. Whatever is held back goes out on a later
+    /// round.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn gossip(&mut self, now: Instant) -> Vec<Message> {
+        let mut peers = self.gossip_peers();
+        #[cfg(feature = "broadcast")]
+        self.broadcast.start_round(&peers);
+
+        let fanout = match &mut self.adaptive {
+            Some(adaptive) => {
+                adaptive.adjust(now, peers.len());
+                adaptive.fanout(peers.len())
+            }
+            None => self.fanout.unwrap_or(peers.len()),
+        };
+        // Rotate through the peers so each still hears from us regularly
+        if fanout < peers.len() {
+            peers.sort();
+            let start = self.gossip_round * fanout % peers.len();
+            peers.rotate_left(start);
+            peers.truncate(fanout);
+        }
+        self.gossip_round += 1;
+
+        let mut out = Vec::new();
+        for peer in peers {
+            match self.workload {
+                #[cfg(feature = "counter")]
+                Some(Workload::Counter) => out.extend(self.counter.gossip(&self.ctx, &peer)),
+                #[cfg(feature = "kv")]
+                Some(Workload::Kv) => out.extend(self.kv.gossip(&self.ctx, &peer)),
+                #[cfg(feature = "broadcast")]
+                Some(Workload::Broadcast) => out.extend(self.broadcast.gossip(&mut self.ctx, peer)),
+                _ => {}
+            }
+        }
+        #[cfg(feature = "broadcast")]
+        if self.workload == Some(Workload::Broadcast) {
+            out.extend(self.broadcast.announce(&mut self.ctx));
+        }
+        out.retain(|msg| {
+            let bytes = serde_json::to_string(msg).map_or(0, |line| line.len());
+            self.limiter.allow(&msg.dst, bytes, now)
+        });
+        if let Some(adaptive) = &mut self.adaptive {
+            adaptive.record_msgs(now, out.len());
+        }
+        out
+    }
+
+    /// Folds one journaled change into the node's state, returning whether
+    /// it changed anything.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn apply(&mut self, change: &Change) -> Result<bool> {
+        match change {
+            Change::Init { node_id, node_ids } => {
+                if *node_id != self.ctx.id {
+                    return Err(anyhow!(
+                        "Journal belongs to node {node_id}, not {}",
+                        self.ctx.id
+                    ));
+                }
+                self.ctx.node_ids = node_ids.iter().cloned().collect();
+            }
+            Change::Workload { workload } => {
+                if self.workload == Some(*workload) {
+                    return Ok(false);
+                }
+                self.workload = Some(*workload);
+            }
+            #[cfg(feature = "broadcast")]
+            Change::Topology { .. }
+            | Change::Overlay { .. }
+            | Change::Seen { .. }
+            | Change::Acked { .. } => return Ok(self.broadcast.apply(&self.ctx.id, change)),
+            #[cfg(feature = "counter")]
+            Change::CounterAdd { .. } | Change::CounterMerge { .. } => {
+                return Ok(self.counter.apply(change))
+            }
+            #[cfg(feature = "kv")]
+            Change::KvPut { .. }
+            | Change::KvAdd { .. }
+            | Change::KvRemove { .. }
+            | Change::KvMerge { .. } => return Ok(self.kv.apply(change)),
+            Change::Batch { changes, .. } => {
+                let mut changed = false;
+                for change in changes {
+                    changed |= self.apply(change)?;
+                }
+                return Ok(changed);
+            }
+            Change::Sent { .. } => return Ok(false),
+            Change::Checkpoint { snapshot } => {
+                if snapshot.node_id != self.ctx.id {
+                    return Err(anyhow!(
+                        "Journal belongs to node {}, not {}",
+                        snapshot.node_id,
+                        self.ctx.id
+                    ));
+                }
+                self.ctx.node_ids = snapshot.node_ids.iter().cloned().collect();
+                self.workload = snapshot.workload.or(self.workload);
+                #[cfg(feature = "broadcast")]
+                self.broadcast.restore(snapshot);
+                #[cfg(feature = "counter")]
+                self.counter.restore(snapshot);
+                #[cfg(feature = "kv")]
+                self.kv.restore(snapshot);
+            }
+        }
+        Ok(true)
+    }
+
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn snapshot(&self) -> Snapshot {
+        let mut node_ids: Vec<String> = self.ctx.node_ids.iter().cloned().collect();
+        node_ids.sort();
+        Snapshot {
+            node_id: self.ctx.id.clone(),
+            node_ids,
+            workload: self.workload,
+            #[cfg(feature = "broadcast")]
+            neighbors: self.broadcast.neighbors().to_vec(),
+            #[cfg(feature = "broadcast")]
+            messages: self.broadcast.values().to_vec(),
+            #[cfg(feature = "broadcast")]
+            overlay: self.broadcast.overlay().clone(),
+            #[cfg(feature = "broadcast")]
+            stream: self.broadcast.stream_runs(),
+            #[cfg(feature = "broadcast")]
+            acked: self.broadcast.acked(),
+            #[cfg(feature = "counter")]
+            counter: self.counter.state(),
+            #[cfg(feature = "kv")]
+            kv: self.kv.state(),
+        }
+    }
+
+    /// Approximate bytes held by each part of the node.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn memory_usage(&self) -> Usage {
+        let mut usage = Usage::new();
+        #[cfg(feature = "broadcast")]
+        usage.insert("broadcast".to_string(), self.broadcast.memory());
+        #[cfg(feature = "counter")]
+        usage.insert("counter".to_string(), self.counter.memory());
+        #[cfg(feature = "kv")]
+        usage.insert("kv".to_string(), self.kv.memory());
+        let held: usize = self
+            .outgoing
+            .held()
+            .map(|msg| serde_json::to_string(msg).map_or(0, |line| line.len()))
+            .sum();
+        usage.insert("resend_window".to_string(), held);
+        usage
+    }
+
+    /// Checks the memory limit once `MEMORY_CHECK_INTERVAL` has passed.
+    /// Over it, the messages kept to be sent again are dropped, as peers
+    /// missing them catch up through gossip anyway.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn check_memory(&mut self, now: Instant) {
+        if self.memory.is_none()
+            || self
+                .last_memory_check
+                .is_some_and(|last| now.duration_since(last) < MEMORY_CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.last_memory_check = Some(now);
+        let usage = self.memory_usage();
+        if let Some(guard) = &mut self.memory {
+            if guard.check(&usage) {
+                self.outgoing.forget();
+            }
+        }
+    }
+
+    /// Folds the journal into a checkpoint once it has grown long enough.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn checkpoint_if_due(&mut self) -> Result<()> {
+        if self.ctx.journal.wants_checkpoint() {
+            let snapshot = self.snapshot();
+            self.ctx.journal.checkpoint(snapshot)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the node's state from `journal` and journals from then on
+    /// into it, returning the messages in its outbox that may not have been
+    /// sent before the node stopped. A fresh journal starts with the node's
+    /// init.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn restore(&mut self, journal: Journal) -> Result<Vec<Message>> {
+        for change in journal.entries() {
+            self.apply(change)?;
+        }
+        let outbox = journal
+            .outbox()
+            .into_iter()
+            .map(|msg| {
+                let msg: RawMessage = serde_json::from_value(msg)?;
+                Ok(Message {
+                    src: msg.src,
+                    dst: msg.dst,
+                    body: Body {
+                        id: msg.body.id,
+                        in_reply_to: msg.body.in_reply_to,
+                        payload: Payload::Raw(msg.body.payload),
+                    },
+                })
+            })
+            .collect::<Result<_>>()?;
+        let fresh = journal.entries().is_empty();
+        self.ctx.journal = journal;
+        if fresh {
+            self.ctx.journal.append(Change::Init {
+                node_id: self.ctx.id.clone(),
+                node_ids: self.ctx.node_ids.iter().cloned().collect(),
+            })?;
+        }
+        Ok(outbox)
+    }
+
+    /// Workload of a message whose type does not identify one, while none
+    /// has been detected: the first whose payloads include that type.
+    #[cfg_attr(
+        not(any(
+            feature = "echo",
+            feature = "unique-ids",
+            feature = "broadcast",
+            feature = "counter",
+            feature = "kv"
+        )),
+        allow(unused_variables)
+    )]
+    fn guess(msg: &RawMessage) -> Option<Workload> {
+        #[cfg(feature = "echo")]
+        if parse::<workload::echo::Payload>(msg).is_ok() {
+            return Some(Workload::Echo);
+        }
+        #[cfg(feature = "unique-ids")]
+        if parse::<workload::unique_ids::Payload>(msg).is_ok() {
+            return Some(Workload::UniqueIds);
+        }
+        #[cfg(feature = "broadcast")]
+        if parse::<workload::broadcast::Payload>(msg).is_ok() {
+            return Some(Workload::Broadcast);
+        }
+        #[cfg(feature = "counter")]
+        if parse::<workload::counter::Payload>(msg).is_ok() {
+            return Some(Workload::Counter);
+        }
+        #[cfg(feature = "kv")]
+        if parse::<workload::kv::Payload>(msg).is_ok() {
+            return Some(Workload::Kv);
+        }
+        None
+    }
+
+    // Built without any workload there is nothing to dispatch to
+    #[cfg_attr(
+        not(any(
+            feature = "echo",
+            feature = "unique-ids",
+            feature = "broadcast",
+            feature = "counter",
+            feature = "kv"
+        )),
+        allow(unreachable_code, unused_variables)
+    )]
+    // Only verifying a signature takes anything out of `msg`
+    #[cfg_attr(
+        not(any(feature = "broadcast", feature = "counter", feature = "kv")),
+        allow(unused_mut)
+    )]
+    fn receive(&mut self, mut msg: RawMessage) -> Result<Option<Message>> {
+        if msg.dst != self.ctx.id {
+            return Ok(Some(Error::WrongDestination.reply(
+                self.ctx.id.clone(),
+                msg.src,
+                msg.body.id,
+            )));
+        }
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        if let Some(signer) = &self.signer {
+            if self.ctx.node_ids.contains(&msg.src) && !signer.verify(&mut msg) {
+                eprintln!("Dropping message from {} with a bad signature", msg.src);
+                return Ok(None);
+            }
+        }
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        if !self.unpacked(&mut msg) {
+            return Ok(None);
+        }
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        if !self.sequenced(&mut msg) {
+            return Ok(None);
+        }
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        if let Some(tracer) = &mut self.tracer {
+            let peer = self.ctx.node_ids.contains(&msg.src);
+            let now = self.ctx.clock.unix_ms();
+            self.traces = tracer.receive(&self.ctx.id, &mut msg, peer, now);
+        }
+        // Anything that is neither from a node nor a reply is a client
+        // operation, which is what the message budget is measured against
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        if let Some(adaptive) = &mut self.adaptive {
+            if msg.body.in_reply_to.is_none() && !self.ctx.node_ids.contains(&msg.src) {
+                adaptive.record_op(self.ctx.clock.now());
+            }
+        }
+
+        let kind = msg.body.payload.get("type").and_then(|kind| kind.as_str());
+        if kind == Some("init") {
+            return Ok(Some(Error::AlreadyInitialized.reply(
+                self.ctx.id.clone(),
+                msg.src,
+                msg.body.id,
+            )));
+        }
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        if kind == Some("resend") && self.ctx.node_ids.contains(&msg.src) {
+            if let Ok(Message {
+                body:
+                    Body {
+                        payload: NodePayload::Resend { from, to },
+                        ..
+                    },
+                ..
+            }) = parse(&msg)
+            {
+                // Asked again, what is still waiting to go out is replaced
+                self.resends.retain(|queued| {
+                    queued.dst != msg.src
+                        || sequence_number(queued).is_none_or(|seq| !(from..to).contains(&seq))
+                });
+                let resent = self.outgoing.resend(&msg.src, from, to);
+                self.resends.extend(resent);
+            }
+            return Ok(None);
+        }
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        if kind == Some("reconfigure") {
+            return Ok(self.reconfigure(msg));
+        }
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        if matches!(kind, Some("hello" | "hello_ok")) && self.ctx.node_ids.contains(&msg.src) {
+            return Ok(self.greeted(msg));
+        }
+        if kind == Some("debug_state") {
+            return Ok(Some(Message {
+                src: self.ctx.id.clone(),
+                dst: msg.src,
+                body: Body {
+                    id: None,
+                    in_reply_to: msg.body.id,
+                    payload: NodePayload::DebugStateOk {
+                        workload: self.workload,
+                        tasks: self.supervisor.health(),
+                        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+                        memory: self.memory_usage(),
+                        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+                        peers: self.peers.clone(),
+                    }
+                    .into(),
+                },
+            }));
+        }
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        if self.memory.as_ref().is_some_and(MemoryGuard::over)
+            && kind.is_some_and(|kind| GROWING.contains(&kind))
+            && !self.ctx.node_ids.contains(&msg.src)
+        {
+            let e = Error::TemporarilyUnavailable("Node is over its memory limit".to_string());
+            return Ok(reject(&self.ctx.id, msg, e));
+        }
+        if let Some(workload) = kind.and_then(Workload::of) {
+            match self.workload {
+                None => {
+                    self.workload = Some(workload);
+                    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+                    self.ctx.journal.append(Change::Workload { workload })?;
+                }
+                Some(current) if current != workload => {
+                    let e =
+                        Error::NotSupported(format!("Node is serving the {current:?} workload"));
+                    return Ok(Some(e.reply(self.ctx.id.clone(), msg.src, msg.body.id)));
+                }
+                Some(_) => {}
+            }
+        }
+
+        let workload = match self.workload {
+            Some(workload) => workload,
+            // Only kv reads name a key
+            #[cfg(feature = "kv")]
+            None if kind == Some("read") && msg.body.payload.contains_key("key") => Workload::Kv,
+            // Until the workload is known a read could be for either, and
+            // the node may already hold data from gossip or its journal, so
+            // the client is asked to try again rather than told it is empty
+            #[cfg(any(feature = "broadcast", feature = "counter"))]
+            None if kind == Some("read") => {
+                let e = Error::TemporarilyUnavailable(
+                    "Workload not known yet; try again after a write".to_string(),
+                );
+                return Ok(reject(&self.ctx.id, msg, e));
+            }
+            None => match Self::guess(&msg) {
+                Some(workload) => workload,
+                None => return Ok(reject(&self.ctx.id, msg, unsupported())),
+            },
+        };
+
+        let check = !self.allow_any_source;
+        let reply = match workload {
+            #[cfg(feature = "echo")]
+            Workload::Echo => dispatch(&mut self.echo, &mut self.ctx, msg, check)?,
+            #[cfg(feature = "unique-ids")]
+            Workload::UniqueIds => dispatch(&mut self.unique_ids, &mut self.ctx, msg, check)?,
+            #[cfg(feature = "broadcast")]
+            Workload::Broadcast => dispatch(&mut self.broadcast, &mut self.ctx, msg, check)?,
+            #[cfg(feature = "counter")]
+            Workload::Counter => dispatch(&mut self.counter, &mut self.ctx, msg, check)?,
+            #[cfg(feature = "kv")]
+            Workload::Kv => dispatch(&mut self.kv, &mut self.ctx, msg, check)?,
+        };
+
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        {
+            // Replies to other nodes count against the gossip budget
+            if let (Some(adaptive), Some(reply)) = (&mut self.adaptive, &reply) {
+                if self.ctx.node_ids.contains(&reply.dst) {
+                    adaptive.record_msgs(self.ctx.clock.now(), 1);
+                }
+            }
+        }
+        Ok(reply)
+    }
+
+    /// Handles one message. With a journal file, what it changed is
+    /// journaled as one entry together with the reply, which stays in the
+    /// journal's outbox until marked sent.
+    fn process(&mut self, msg: RawMessage) -> Result<Option<Message>> {
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        self.ctx.journal.begin();
+        #[cfg(feature = "kv")]
+        let client = msg.body.in_reply_to.is_none()
+            && !self.ctx.node_ids.contains(&msg.src)
+            && self
+                .workload
+                .is_none_or(|workload| workload == Workload::Kv);
+        #[cfg(feature = "kv")]
+        if let Some(history) = self.history.as_mut().filter(|_| client) {
+            history.invoke(&msg, self.ctx.clock.unix_ms())?;
+        }
+        let reply = self.receive(msg)?.map(|reply| self.ctx.stamp(reply));
+        #[cfg(feature = "kv")]
+        if let (Some(history), Some(reply)) = (&mut self.history, &reply) {
+            history.complete(reply, self.ctx.clock.unix_ms())?;
+        }
+        #[cfg(feature = "broadcast")]
+        let hold = reply
+            .as_ref()
+            .is_some_and(|reply| self.broadcast.defers(reply));
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        let reply = reply.map(|reply| {
+            let traces = std::mem::take(&mut self.traces);
+            let reply = self.traced(reply, &traces);
+            self.packed(reply)
+        });
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        {
+            self.ctx.journal.commit(&reply)?;
+            self.checkpoint_if_due()?;
+        }
+        // Journaled like any reply, so a crash before it goes out does not
+        // lose it
+        #[cfg(feature = "broadcast")]
+        if let (true, Some(reply)) = (hold, &reply) {
+            let now = self.ctx.clock.now();
+            self.broadcast.hold(reply.clone(), now);
+            return Ok(None);
+        }
+        Ok(reply)
+    }
+
+    /// Marks what the journal's outbox holds as sent, but for replies held
+    /// back to go out later.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn sent(&mut self) -> Result<()> {
+        #[cfg(feature = "broadcast")]
+        return self.ctx.journal.sent(self.broadcast.held());
+        #[cfg(not(feature = "broadcast"))]
+        self.ctx.journal.sent([])
+    }
+}
+
+/// The sequence number `msg` was sent under, if numbered.
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+fn sequence_number(msg: &Message) -> Option<u64> {
+    match &msg.body.payload {
+        Payload::Raw(payload) => payload.get(sequence::SEQ_FIELD)?.as_u64(),
+        _ => None,
+    }
+}
+
+/// Parses the payload of `msg` as `P`.
+fn parse<P: DeserializeOwned>(msg: &RawMessage) -> serde_json::Result<Message<P>> {
+    let payload = serde_json::Value::Object(msg.body.payload.clone());
+    Ok(Message {
+        src: msg.src.clone(),
+        dst: msg.dst.clone(),
+        body: Body {
+            id: msg.body.id,
+            in_reply_to: msg.body.in_reply_to,
+            payload: serde_json::from_value(payload)?,
+        },
+    })
+}
+
+/// Hands `msg` to the workload `handler`, after checking its sender if
+/// `check_source` is set, turning whatever error it fails
+/// with into the reply. Only a storage failure is returned, to stop the
+/// node; a handler that panics gets a `crash` reply instead, and whatever
+/// it changed before panicking stays changed.
+#[cfg_attr(
+    not(any(
+        feature = "echo",
+        feature = "unique-ids",
+        feature = "broadcast",
+        feature = "counter",
+        feature = "kv"
+    )),
+    allow(dead_code)
+)]
+fn dispatch<H: Handler>(
+    handler: &mut H,
+    ctx: &mut Context,
+    msg: RawMessage,
+    check_source: bool,
+) -> Result<Option<Message>> {
+    let result = match parse::<H::Payload>(&msg) {
+        Ok(parsed) if check_source => validate::source::<H>(&parsed, &ctx.node_ids)
+            .and_then(|()| handle(handler, ctx, parsed)),
+        Ok(parsed) => handle(handler, ctx, parsed),
+        Err(e) if e.to_string().starts_with("unknown variant") => Err(unsupported()),
+        Err(e) => Err(Error::Malformed(e.to_string())),
+    };
+    match result {
+        Ok(reply) => Ok(reply),
+        Err(Error::Storage(e)) => Err(e),
+        Err(e) => Ok(reject(&ctx.id, msg, e)),
+    }
+}
+
+/// Runs `handler` on `msg`, turning a panic into a `crash` error.
+fn handle<H: Handler>(
+    handler: &mut H,
+    ctx: &mut Context,
+    msg: Message<H::Payload>,
+) -> Result<Option<Message>, Error> {
+    // The panic hook has already logged where it happened
+    panic::catch_unwind(AssertUnwindSafe(|| handler.handle(ctx, msg))).unwrap_or_else(|panic| {
+        Err(Error::Crash(format!(
+            "Handler panicked: {}",
+            supervisor::panic_message(panic.as_ref())
+        )))
+    })
+}
+
+/// Error for a message of a type the node does not handle.
+fn unsupported() -> Error {
+    Error::NotSupported("Unsupported message type".to_string())
+}
+
+/// Reply to a message that failed with `e`: nothing if it is a reply, since
+/// nothing is waiting for it any more, and the error if not.
+fn reject<P>(id: &str, msg: Message<P>, e: Error) -> Option<Message> {
+    if msg.body.in_reply_to.is_some() {
+        return None;
+    }
+    Some(e.reply(id.to_string(), msg.src, msg.body.id))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message<P = Payload> {
+    pub src: String,
+    #[serde(rename = "dest")]
+    pub dst: String,
+    pub body: Body<P>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Body<P = Payload> {
+    #[serde(rename = "msg_id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<usize>,
+    #[serde(flatten)]
+    pub payload: P,
+}
+
+/// A message as read off the wire, before its payload is parsed by the
+/// workload it belongs to.
+type RawMessage = Message<serde_json::Map<String, serde_json::Value>>;
+
+/// Payloads every node handles whatever its workload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum NodePayload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk {},
+    DebugState {},
+    /// The node's workload, the health of its background tasks, the
+    /// approximate bytes held by each part of it and what each peer that
+    /// said hello can do.
+    DebugStateOk {
+        workload: Option<Workload>,
+        tasks: BTreeMap<String, TaskHealth>,
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        memory: BTreeMap<String, usize>,
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        peers: BTreeMap<String, Capabilities>,
+    },
+    /// Asks a peer to send again its messages numbered from `from` up to
+    /// but not including `to`.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    Resend {
+        from: u64,
+        to: u64,
+    },
+    /// Changes the given tunables of a running node.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    Reconfigure(Tunables),
+    /// The node's tunables after a `reconfigure`, leaving out those left
+    /// at their defaults.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    ReconfigureOk(Tunables),
+    /// Tells a peer what this node can do, on startup.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    Hello(Capabilities),
+    /// What the node that a `hello` was sent to can do.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    HelloOk(Capabilities),
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+/// Gossip settings a node can change while running.
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tunables {
+    /// How often to gossip. Adaptive gossip starts over from it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gossip_interval_ms: Option<u64>,
+    /// Peers to gossip to each round, rotating through all of them. Adaptive
+    /// gossip picks its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fanout: Option<usize>,
+    /// Most broadcast values per gossip message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_size: Option<usize>,
+}
+
+/// What a node can do, which it tells its peers on startup so that nodes
+/// built or started differently show up in the logs before they disagree
+/// mid-test.
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Workloads compiled in, by name, so that those of a peer built with
+    /// more can still be read.
+    pub workloads: Vec<String>,
+    /// Workload served, if not left to detect.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serving: Option<String>,
+    /// Whether the node signs messages to other nodes.
+    #[serde(default)]
+    pub signed: bool,
+}
+
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+impl Capabilities {
+    /// Ways a peer with `theirs` and this node would disagree.
+    pub fn conflicts(&self, theirs: &Capabilities) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        if let Some(serving) = &self.serving {
+            match &theirs.serving {
+                Some(other) if other != serving => {
+                    conflicts.push(format!("serves {other}, not {serving}"));
+                }
+                _ if !theirs.workloads.contains(serving) => {
+                    conflicts.push(format!("was built without {serving}"));
+                }
+                _ => {}
+            }
+        }
+        if self.signed != theirs.signed {
+            let signs = if theirs.signed {
+                "signs"
+            } else {
+                "does not sign"
+            };
+            conflicts.push(format!("{signs} its messages"));
+        }
+        conflicts
+    }
+}
+
+/// Any payload a node sends or receives. Nodes parse incoming payloads with
+/// the enum of the workload they serve, so the same `type` in two workloads
+/// is not ambiguous to them; deserializing a `Payload` directly picks the
+/// first enum that accepts it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Payload {
+    Node(NodePayload),
+    #[cfg(feature = "echo")]
+    Echo(workload::echo::Payload),
+    #[cfg(feature = "unique-ids")]
+    UniqueIds(workload::unique_ids::Payload),
+    #[cfg(feature = "broadcast")]
+    Broadcast(workload::broadcast::Payload),
+    #[cfg(feature = "counter")]
+    Counter(workload::counter::Payload),
+    #[cfg(feature = "kv")]
+    Kv(workload::kv::Payload),
+    /// A payload kept as the JSON it was sent as, such as a reply sent
+    /// again from the journal's outbox. Deserializing never picks it over
+    /// another payload, only instead of failing.
+    Raw(serde_json::Map<String, serde_json::Value>),
+}
+
+impl From<NodePayload> for Payload {
+    fn from(payload: NodePayload) -> Self {
+        Payload::Node(payload)
+    }
+}
+
+#[cfg(feature = "echo")]
+impl From<workload::echo::Payload> for Payload {
+    fn from(payload: workload::echo::Payload) -> Self {
+        Payload::Echo(payload)
+    }
+}
+
+#[cfg(feature = "unique-ids")]
+impl From<workload::unique_ids::Payload> for Payload {
+    fn from(payload: workload::unique_ids::Payload) -> Self {
+        Payload::UniqueIds(payload)
+    }
+}
+
+#[cfg(feature = "broadcast")]
+impl From<workload::broadcast::Payload> for Payload {
+    fn from(payload: workload::broadcast::Payload) -> Self {
+        Payload::Broadcast(payload)
+    }
+}
+
+#[cfg(feature = "counter")]
+impl From<workload::counter::Payload> for Payload {
+    fn from(payload: workload::counter::Payload) -> Self {
+        Payload::Counter(payload)
+    }
+}
+
+#[cfg(feature = "kv")]
+impl From<workload::kv::Payload> for Payload {
+    fn from(payload: workload::kv::Payload) -> Self {
+        Payload::Kv(payload)
+    }
+}
+
+/// Startup options a node cannot learn from its messages.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Workload to serve, or `None` to detect it.
+    pub workload: Option<Workload>,
+    /// Keep the counter as a single lin-kv key instead of a gossiped CRDT.
+    #[cfg(feature = "counter")]
+    pub kv_counter: bool,
+    /// Tell readers of the gossiped counter how long since each peer was
+    /// last heard from, bounding how stale the value read may be.
+    #[cfg(feature = "counter")]
+    pub read_staleness: bool,
+    /// Per-peer budget for gossip.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub gossip_limit: RateLimit,
+    /// How often to gossip, or `None` for `GOSSIP_INTERVAL`. Adaptive
+    /// gossip starts from it.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub gossip_interval: Option<Duration>,
+    /// Tune the gossip interval and fan-out to a message budget instead of
+    /// gossiping to every peer at a fixed interval.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub adaptive_gossip: Option<AdaptiveGossip>,
+    /// Pack large broadcast gossip for peers that support it.
+    #[cfg(feature = "broadcast")]
+    pub compress_gossip: bool,
+    /// Propose a new broadcast topology when neighbors stop responding.
+    #[cfg(feature = "broadcast")]
+    pub repair_topology: bool,
+    /// Make broadcast values readable only once every member of the view
+    /// has them.
+    #[cfg(feature = "broadcast")]
+    pub view_sync: bool,
+    /// How long a `broadcast_ok` may be held back waiting for the next
+    /// gossip round, so that broadcasts arriving together are answered in
+    /// one write once it carried them.
+    #[cfg(feature = "broadcast")]
+    pub defer_broadcast_ok: Option<Duration>,
+    /// Called once with each broadcast value the node learns of. Values
+    /// restored from the journal were delivered before the restart, and
+    /// are not delivered again.
+    #[cfg(feature = "broadcast")]
+    pub on_deliver: Option<workload::broadcast::OnDeliver>,
+    /// Policies for settling concurrent kv writes, by the prefix of the
+    /// string keys they apply to.
+    #[cfg(feature = "kv")]
+    pub kv_resolution: Vec<(String, crdt_map::Resolution)>,
+    /// How `unique-ids` makes ids.
+    #[cfg(feature = "unique-ids")]
+    pub id_strategy: workload::unique_ids::IdStrategy,
+    /// Accept messages from anyone, not just other nodes, clients and the
+    /// workload's services.
+    pub allow_any_source: bool,
+    /// Follow client requests through the cluster, logging every message
+    /// sent or received on their behalf to stderr.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub trace: bool,
+    /// Pack messages to other nodes that read them as base64 MessagePack.
+    /// Every node reads packed messages, but only those with this set send
+    /// them, and tell their peers they can.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub msgpack: bool,
+    /// Key to sign messages to other nodes with, and to verify theirs.
+    /// Only the JSON lines transports sign; embedded nodes exchange typed
+    /// messages that never cross a network.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub gossip_key: Option<Vec<u8>>,
+    /// File to journal state changes to, and to restore them from when it
+    /// already exists.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub journal: Option<PathBuf>,
+    /// Approximate bytes of state past which the node refuses client
+    /// writes and drops what it keeps to send again, until it is back
+    /// under 90% of it.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub memory_limit: Option<usize>,
+    /// File to append the kv operations clients run to, as a history for
+    /// a linearizability checker.
+    #[cfg(feature = "kv")]
+    pub history: Option<PathBuf>,
+    /// Input backlog or handling time past which the node gossips less
+    /// often in larger batches, until it catches up.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub overload: Option<OverloadLimits>,
+}
+
+impl Config {
+    /// Fails on settings the node could only misbehave with, before it
+    /// answers init rather than mid-test.
+    pub fn check(&self) -> Result<()> {
+        #[cfg(feature = "counter")]
+        if self.kv_counter && self.workload.is_some_and(|w| w != Workload::Counter) {
+            return Err(anyhow!(
+                "The lin-kv counter only serves g-counter, not {}",
+                self.workload.map_or("", Workload::name)
+            ));
+        }
+        #[cfg(feature = "counter")]
+        if self.kv_counter && self.read_staleness {
+            return Err(anyhow!("Reads of the lin-kv counter are never stale"));
+        }
+        #[cfg(feature = "broadcast")]
+        if self.view_sync && !self.repair_topology {
+            return Err(anyhow!(
+                "View sync needs topology repair to change the view, \
+                 or one member going down holds back every read"
+            ));
+        }
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        {
+            if self
+                .gossip_interval
+                .is_some_and(|interval| interval.is_zero())
+            {
+                return Err(anyhow!("The gossip interval must be positive"));
+            }
+            if self.memory_limit == Some(0) {
+                return Err(anyhow!("The memory limit must be positive"));
+            }
+            if self.gossip_key.as_ref().is_some_and(Vec::is_empty) {
+                return Err(anyhow!("The gossip key is empty"));
+            }
+            if let Some(path) = &self.journal {
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .with_context(|| format!("Journal {} is not writable", path.display()))?;
+            }
+        }
+        #[cfg(feature = "kv")]
+        if let Some(path) = &self.history {
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .with_context(|| format!("History {} is not writable", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+enum Event {
+    Input(String),
+    Message(Message),
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    Tick,
+    Eof,
+}
+
+/// Error reply for a line that could not be parsed as a message, if enough
+/// of it is valid JSON to tell who sent it and which msg_id to answer.
+fn malformed_reply(line: &str, error: &serde_json::Error) -> Option<Message> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    let field = |name: &str| value.get(name)?.as_str().map(str::to_string);
+    let msg_id = value.get("body")?.get("msg_id")?.as_u64()?;
+    let e = Error::Malformed(error.to_string());
+    Some(e.reply(field("dest")?, field("src")?, Some(msg_id as usize)))
+}
+
+/// Runs a node over `transport` until its input ends.
+pub fn run(config: Config, transport: Box<dyn Transport>) -> Result<()> {
+    let (mut input, mut output) = transport.split()?;
+
+    let (tx, rx) = mpsc::channel();
+    let input_tx = tx.clone();
+    let supervisor = Supervisor::default();
+    supervisor.spawn("input", move || {
+        for line in (&mut input).lines() {
+            let Ok(line) = line else { break };
+            if input_tx.send(Event::Input(line)).is_err() {
+                return;
+            }
+        }
+        let _ = input_tx.send(Event::Eof);
+    });
+
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    let signer = config.gossip_key.as_deref().map(Signer::new);
+    #[cfg_attr(
+        not(any(feature = "broadcast", feature = "counter", feature = "kv")),
+        allow(unused_variables)
+    )]
+    let emit = |out: Vec<Message>, peers: &HashSet<String>| -> Result<()> {
+        for msg in out {
+            #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+            if let Some(signer) = signer.as_ref().filter(|_| peers.contains(&msg.dst)) {
+                let mut value = serde_json::to_value(&msg)?;
+                signer.sign(&mut value);
+                writeln!(output, "{value}")?;
+                continue;
+            }
+            writeln!(output, "{}", serde_json::to_string(&msg)?)?;
+        }
+        output.flush()?;
+        Ok(())
+    };
+    event_loop(config, supervisor, tx, rx, emit)
+}
+
+/// Runs a node in-process, for harnesses that embed it instead of talking
+/// JSON lines: every message sent on `incoming` is handled and everything
+/// the node emits is sent on `outgoing`. Returns once `incoming` is closed.
+pub fn run_embedded(
+    config: Config,
+    incoming: Receiver<Message>,
+    outgoing: Sender<Message>,
+) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let input_tx = tx.clone();
+    let supervisor = Supervisor::default();
+    supervisor.spawn("input", move || {
+        for msg in incoming.iter() {
+            if input_tx.send(Event::Message(msg)).is_err() {
+                return;
+            }
+        }
+        let _ = input_tx.send(Event::Eof);
+    });
+
+    event_loop(config, supervisor, tx, rx, |out, _| {
+        for msg in out {
+            // Nobody listening is not an error for the node
+            let _ = outgoing.send(msg);
+        }
+        Ok(())
+    })
+}
+
+/// Feeds events to the node, handing whatever it emits for each one to
+/// `emit` along with the other nodes in the cluster, until an `Eof` event.
+fn event_loop(
+    config: Config,
+    supervisor: Supervisor,
+    #[cfg_attr(
+        not(any(feature = "broadcast", feature = "counter", feature = "kv")),
+        allow(unused_variables)
+    )]
+    tx: Sender<Event>,
+    rx: Receiver<Event>,
+    mut emit: impl FnMut(Vec<Message>, &HashSet<String>) -> Result<()>,
+) -> Result<()> {
+    config.check()?;
+    let no_peers = HashSet::new();
+    // Only gossiping workloads need timers
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    supervisor.spawn("ticker", move || loop {
+        thread::sleep(TICK_INTERVAL);
+        if tx.send(Event::Tick).is_err() {
+            return;
+        }
+    });
+
+    let mut node: Option<Node> = None;
+    // Events taken off `rx` early, to tell how many are waiting
+    let mut queued: VecDeque<Event> = VecDeque::new();
+    loop {
+        // Replies held back past their cap go out, however much input waits
+        #[cfg(feature = "broadcast")]
+        let event = match node.as_ref().and_then(|node| node.broadcast.flush_at()) {
+            Some(due) => match due.checked_duration_since(Instant::now()) {
+                Some(wait) if !wait.is_zero() => match queued.pop_front() {
+                    Some(event) => Ok(event),
+                    None => match rx.recv_timeout(wait) {
+                        Err(RecvTimeoutError::Timeout) => Ok(Event::Tick),
+                        event => event.map_err(|_| ()),
+                    },
+                },
+                _ => Ok(Event::Tick),
+            },
+            None => queued
+                .pop_front()
+                .ok_or(())
+                .or_else(|()| rx.recv().map_err(|_| ())),
+        };
+        #[cfg(not(feature = "broadcast"))]
+        let event = queued
+            .pop_front()
+            .ok_or(())
+            .or_else(|()| rx.recv().map_err(|_| ()));
+        let Ok(event) = event else { break };
+        let msg = match event {
+            Event::Input(line) => match serde_json::from_str(&line) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    eprintln!("Ignoring malformed message {line}: {e}");
+                    emit(malformed_reply(&line, &e).into_iter().collect(), &no_peers)?;
+                    continue;
+                }
+            },
+            // Embedders hand over typed messages, which the node parses
+            // by workload like any other
+            Event::Message(msg) => serde_json::from_value(serde_json::to_value(msg)?)?,
+            #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+            Event::Tick => {
+                if let Some(node) = &mut node {
+                    // An idle node is not overloaded, however slow its
+                    // last message was
+                    queued.extend(rx.try_iter());
+                    if queued.is_empty() {
+                        node.observe_load(0, Duration::ZERO);
+                    }
+                    let out = node.tick()?;
+                    emit(out, &node.ctx.node_ids)?;
+                    node.sent()?;
+                }
+                continue;
+            }
+            Event::Eof => break,
+        };
+
+        let out = match &mut node {
+            Some(node) => {
+                #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+                let started = Instant::now();
+                let reply = node.process(msg)?;
+                #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+                {
+                    queued.extend(rx.try_iter());
+                    node.observe_load(queued.len(), started.elapsed());
+                }
+                reply.into_iter().collect()
+            }
+            None => {
+                // A peer that started first may say hello before init
+                let (resp, new_node) = match Node::from_init(msg) {
+                    Ok(init) => init,
+                    Err(e) => {
+                        eprintln!("Ignoring message before init: {e}");
+                        continue;
+                    }
+                };
+                // Only a restored journal adds to the init reply
+                #[cfg_attr(
+                    not(any(feature = "broadcast", feature = "counter", feature = "kv")),
+                    allow(unused_mut)
+                )]
+                let mut out = vec![resp];
+                if let Some(mut new_node) = new_node {
+                    new_node.workload = config.workload;
+                    new_node.supervisor = supervisor.clone();
+                    new_node.allow_any_source = config.allow_any_source;
+                    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+                    {
+                        new_node.tracer = config.trace.then(Tracer::default);
+                        new_node.memory = config.memory_limit.map(MemoryGuard::new);
+                        new_node.overload = config.overload.map(Overload::new);
+                        new_node.msgpack = config.msgpack;
+                    }
+                    #[cfg(feature = "unique-ids")]
+                    {
+                        new_node.unique_ids.strategy = config.id_strategy;
+                    }
+                    #[cfg(feature = "broadcast")]
+                    {
+                        new_node.broadcast.compress_gossip = config.compress_gossip;
+                        new_node.broadcast.repair_topology = config.repair_topology;
+                        new_node.broadcast.view_sync = config.view_sync;
+                        new_node.broadcast.defer_ok = config.defer_broadcast_ok;
+                    }
+                    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+                    {
+                        new_node.limiter = PeerLimiter::new(config.gossip_limit);
+                        new_node.signer = config.gossip_key.as_deref().map(Signer::new);
+                        new_node.gossip_interval = config.gossip_interval;
+                        new_node.adaptive = config.adaptive_gossip.map(|target| {
+                            GossipController::new(target, new_node.gossip_interval())
+                        });
+                    }
+                    #[cfg(feature = "kv")]
+                    for (prefix, resolution) in &config.kv_resolution {
+                        new_node.kv.resolve(prefix, resolution.clone());
+                    }
+                    #[cfg(feature = "kv")]
+                    if let Some(path) = &config.history {
+                        new_node.history = Some(History::open(path, &new_node.ctx.id)?);
+                    }
+                    // The lin-kv counter has nothing to detect
+                    #[cfg(feature = "counter")]
+                    {
+                        new_node.counter.read_staleness = config.read_staleness;
+                    }
+                    #[cfg(feature = "counter")]
+                    if config.kv_counter {
+                        new_node.workload = new_node.workload.or(Some(Workload::Counter));
+                        new_node.counter.use_lin_kv(&new_node.ctx.id);
+                    }
+                    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+                    out.extend(new_node.restore(match &config.journal {
+                        Some(path) => Journal::open(path.clone())?,
+                        None => Journal::default(),
+                    })?);
+                    #[cfg(feature = "broadcast")]
+                    {
+                        new_node.broadcast.on_deliver = config.on_deliver.clone();
+                    }
+                    #[cfg(feature = "counter")]
+                    {
+                        let now = new_node.ctx.clock.now();
+                        out.extend(new_node.counter.probe(&mut new_node.ctx, now));
+                    }
+                    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+                    out.extend(new_node.hello());
+                    node = Some(new_node);
+                }
+                out
+            }
+        };
+        emit(
+            out,
+            node.as_ref().map_or(&no_peers, |node| &node.ctx.node_ids),
+        )?;
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        if let Some(node) = &mut node {
+            node.sent()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, any(feature = "broadcast", feature = "counter", feature = "kv")))]
+mod tests {
+    use std::rc::Rc;
+
+    use serde_json::json;
+
+    use super::*;
+    use clock::ManualClock;
+
+    fn message(src: &str, dst: &str, body: serde_json::Value) -> RawMessage {
+        serde_json::from_value(json!({ "src": src, "dest": dst, "body": body })).unwrap()
+    }
+
+    fn node_with_clock(workload: Workload) -> (Node, Rc<ManualClock>) {
+        let init = message(
+            "c0",
+            "n1",
+            json!({ "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"] }),
+        );
+        let (_, node) = Node::from_init(init).unwrap();
+        let mut node = node.unwrap();
+        let clock = Rc::new(ManualClock::new());
+        node.ctx.clock = Box::new(clock.clone());
+        node.workload = Some(workload);
+        (node, clock)
+    }
+
+    #[cfg(feature = "echo")]
+    #[test]
+    fn handler_panic_becomes_crash_reply() {
+        struct Panics;
+        impl Handler for Panics {
+            type Payload = workload::echo::Payload;
+
+            fn handle(
+                &mut self,
+                _: &mut Context,
+                _: Message<Self::Payload>,
+            ) -> Result<Option<Message>, Error> {
+                panic!("boom")
+            }
+        }
+
+        let msg = message(
+            "c1",
+            "n1",
+            json!({ "type": "echo", "msg_id": 2, "echo": "x" }),
+        );
+        let reply = dispatch(&mut Panics, &mut Context::default(), msg, true)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Node(NodePayload::Error { code: 13, ref text }) if text == "Handler panicked: boom"
+        ));
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn reconfigure_changes_gossip_interval() {
+        let (mut node, clock) = node_with_clock(Workload::Broadcast);
+        let reply = node.process(message(
+            "c9",
+            "n1",
+            json!({ "type": "reconfigure", "msg_id": 1, "fanout": 0 }),
+        ));
+        assert!(matches!(
+            reply.unwrap().unwrap().body.payload,
+            Payload::Node(NodePayload::Error { code: 12, .. })
+        ));
+        let reply = node.process(message(
+            "c9",
+            "n1",
+            json!({ "type": "reconfigure", "msg_id": 2, "gossip_interval_ms": 50 }),
+        ));
+        assert!(matches!(
+            reply.unwrap().unwrap().body.payload,
+            Payload::Node(NodePayload::ReconfigureOk(Tunables {
+                gossip_interval_ms: Some(50),
+                fanout: None,
+                batch_size: None,
+            }))
+        ));
+
+        node.process(message(
+            "c1",
+            "n1",
+            json!({ "type": "broadcast", "msg_id": 3, "message": 7 }),
+        ))
+        .unwrap();
+        assert_eq!(node.tick().unwrap().len(), 1);
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(node.tick().unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn traces_follow_a_request_into_gossip() {
+        let (mut node, clock) = node_with_clock(Workload::Broadcast);
+        node.tracer = Some(Tracer::default());
+        let broadcast = json!({ "type": "broadcast", "msg_id": 2, "message": 7 });
+        node.process(message("c1", "n1", broadcast)).unwrap();
+        let gossip = serde_json::to_value(node.tick().unwrap()).unwrap();
+        assert_eq!(gossip[0]["body"]["trace"], json!(["n1-1"]));
+
+        // The trace spreads once, not on every later round
+        let ack = json!({ "type": "gossip_ok", "in_reply_to": gossip[0]["body"]["msg_id"],
+                          "trace": ["n1-1"] });
+        node.process(message("n2", "n1", ack)).unwrap();
+        let broadcast = json!({ "type": "broadcast", "msg_id": 3, "message": 8 });
+        node.process(message("c1", "n1", broadcast)).unwrap();
+        clock.advance(GOSSIP_INTERVAL);
+        let gossip = serde_json::to_value(node.tick().unwrap()).unwrap();
+        assert_eq!(gossip[0]["body"]["trace"], json!(["n1-2"]));
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn packs_gossip_once_peer_reads_msgpack() {
+        let (mut node, clock) = node_with_clock(Workload::Broadcast);
+        node.msgpack = true;
+        let broadcast = json!({ "type": "broadcast", "msg_id": 2, "message": 7 });
+        node.process(message("c1", "n1", broadcast)).unwrap();
+        let gossip = serde_json::to_value(node.tick().unwrap()).unwrap();
+        assert_eq!(gossip[0]["body"]["wire"], 2);
+        assert_eq!(gossip[0]["body"]["messages"], json!([7]));
+
+        let mut body = serde_json::Map::new();
+        body.insert("type".to_string(), "gossip".into());
+        body.insert("messages".to_string(), json!([8]));
+        msgpack::pack(&mut body);
+        body.insert("msg_id".to_string(), 3.into());
+        node.process(message("n2", "n1", body.into())).unwrap();
+        assert_eq!(node.broadcast.values(), [(7, 8)]);
+        clock.advance(GOSSIP_INTERVAL);
+        let gossip = serde_json::to_value(node.tick().unwrap()).unwrap();
+        let mut body = gossip[0]["body"].as_object().unwrap().clone();
+        assert!(body.contains_key("msgpack") && !body.contains_key("messages"));
+        msgpack::unpack(&mut body).unwrap();
+        assert_eq!(body["messages"], json!([7]));
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn refuses_client_writes_over_memory_limit() {
+        let (mut node, clock) = node_with_clock(Workload::Broadcast);
+        node.memory = Some(MemoryGuard::new(64));
+        for n in 0..20 {
+            let broadcast = json!({ "type": "broadcast", "msg_id": n, "message": n });
+            node.process(message("c1", "n1", broadcast)).unwrap();
+        }
+        node.tick().unwrap();
+        let broadcast = json!({ "type": "broadcast", "msg_id": 21, "message": 21 });
+        let reply = node
+            .process(message("c1", "n1", broadcast))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Node(NodePayload::Error { code: 11, .. })
+        ));
+        // Reads, and gossip from peers, still go through
+        let read = json!({ "type": "read", "msg_id": 22 });
+        let reply = node.process(message("c1", "n1", read)).unwrap().unwrap();
+        assert!(!matches!(
+            reply.body.payload,
+            Payload::Node(NodePayload::Error { .. })
+        ));
+        let gossip = json!({ "type": "gossip", "msg_id": 3, "messages": [99] });
+        node.process(message("n2", "n1", gossip)).unwrap();
+        assert!(node.broadcast.values().contains(&(99, 99)));
+
+        node.memory = Some(MemoryGuard::new(1 << 20));
+        clock.advance(MEMORY_CHECK_INTERVAL);
+        node.tick().unwrap();
+        let broadcast = json!({ "type": "broadcast", "msg_id": 23, "message": 23 });
+        let reply = node
+            .process(message("c1", "n1", broadcast))
+            .unwrap()
+            .unwrap();
+        assert!(!matches!(
+            reply.body.payload,
+            Payload::Node(NodePayload::Error { .. })
+        ));
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn answers_hello_and_notes_peer_capabilities() {
+        let (mut node, _) = node_with_clock(Workload::Broadcast);
+        let ours = node.hello();
+        let hello = json!({
+            "type": "hello", "msg_id": 1, "workloads": ["broadcast"],
+            "serving": "kv", "signed": true,
+        });
+        let reply = node.process(message("n2", "n1", hello)).unwrap().unwrap();
+        let Payload::Node(NodePayload::HelloOk(mine)) = reply.body.payload else {
+            panic!("Expected hello_ok, got {reply:?}");
+        };
+        assert_eq!(mine.serving.as_deref(), Some("broadcast"));
+        let conflicts = mine.conflicts(&node.peers["n2"]);
+        assert_eq!(
+            conflicts,
+            ["serves kv, not broadcast", "signs its messages"]
+        );
+        // Answers are not answered
+        let hello_ok = json!({
+            "type": "hello_ok", "msg_id": 2, "in_reply_to": ours[0].body.id, "workloads": [],
+        });
+        assert!(node
+            .process(message("n2", "n1", hello_ok))
+            .unwrap()
+            .is_none());
+        assert!(node.peers["n2"].workloads.is_empty());
+        // Every peer answered ours
+        node.tick().unwrap();
+        assert!(node.hellos.is_none());
+    }
+
+    #[test]
+    fn config_check_rejects_unwritable_journal() {
+        let config = Config {
+            journal: Some(PathBuf::from("/nonexistent/journal")),
+            ..Default::default()
+        };
+        assert!(config.check().is_err());
+        let config = Config {
+            memory_limit: Some(0),
+            ..Default::default()
+        };
+        assert!(config.check().is_err());
+        assert!(Config::default().check().is_ok());
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn config_check_rejects_view_sync_without_repair() {
+        let mut config = Config {
+            view_sync: true,
+            ..Default::default()
+        };
+        assert!(config.check().is_err());
+        config.repair_topology = true;
+        assert!(config.check().is_ok());
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn counter_reads_tell_how_stale_they_may_be() {
+        let (mut node, clock) = node_with_clock(Workload::Counter);
+        node.counter.read_staleness = true;
+        let read = json!({ "type": "read", "msg_id": 2 });
+        let reply = node.process(message("c1", "n1", read.clone())).unwrap();
+        let reply = serde_json::to_value(reply).unwrap();
+        assert_eq!(
+            reply["body"]["debug"],
+            json!({ "staleness_ms": { "n2": null }, "bound_ms": null })
+        );
+
+        let state = serde_json::to_value(node.counter.state()).unwrap();
+        let gossip = json!({ "type": "counter_gossip", "msg_id": 3, "state": state });
+        node.process(message("n2", "n1", gossip)).unwrap();
+        clock.advance(Duration::from_millis(250));
+        let reply = node.process(message("c1", "n1", read)).unwrap();
+        let reply = serde_json::to_value(reply).unwrap();
+        assert_eq!(reply["body"]["debug"]["bound_ms"], 250);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn deferred_broadcast_oks_wait_for_the_next_gossip_round() {
+        let (mut node, clock) = node_with_clock(Workload::Broadcast);
+        let ms = Duration::from_millis;
+        node.broadcast.defer_ok = Some(ms(150));
+        node.tick().unwrap();
+        let broadcast = |msg_id, value| {
+            let body = json!({ "type": "broadcast", "msg_id": msg_id, "message": value });
+            message("c1", "n1", body)
+        };
+        for (msg_id, value) in [(2, 7), (3, 8)] {
+            assert!(node.process(broadcast(msg_id, value)).unwrap().is_none());
+        }
+
+        // No round within the cap, so the replies go out on their own
+        clock.advance(ms(149));
+        assert!(node.tick().unwrap().is_empty());
+        clock.advance(ms(1));
+        let out = serde_json::to_value(node.tick().unwrap()).unwrap();
+        assert_eq!(out[0]["body"]["in_reply_to"], 2);
+        assert_eq!(out[1]["body"]["in_reply_to"], 3);
+        assert_eq!(out.as_array().unwrap().len(), 2);
+
+        // The next regular round takes the replies held since along
+        assert!(node.process(broadcast(4, 9)).unwrap().is_none());
+        clock.advance(GOSSIP_INTERVAL - ms(150));
+        let out = serde_json::to_value(node.tick().unwrap()).unwrap();
+        assert_eq!(out[0]["dest"], "n2");
+        assert_eq!(out[0]["body"]["messages"], json!([7, 8, 9]));
+        assert_eq!(out[1]["body"]["in_reply_to"], 4);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn held_broadcast_ok_is_resent_after_restart() {
+        let path = std::env::temp_dir().join(format!("held-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (mut node, _) = node_with_clock(Workload::Broadcast);
+        node.restore(Journal::open(path.clone()).unwrap()).unwrap();
+        node.broadcast.defer_ok = Some(Duration::from_millis(150));
+        let broadcast = json!({ "type": "broadcast", "msg_id": 2, "message": 7 });
+        assert!(node
+            .process(message("c1", "n1", broadcast))
+            .unwrap()
+            .is_none());
+        node.sent().unwrap();
+
+        // The node stopped while the reply was held
+        let (mut restarted, _) = node_with_clock(Workload::Broadcast);
+        let resent = restarted
+            .restore(Journal::open(path.clone()).unwrap())
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(resent).unwrap(),
+            serde_json::to_value(node.broadcast.held()).unwrap()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn overloaded_node_gossips_less_often() {
+        let (mut node, clock) = node_with_clock(Workload::Broadcast);
+        node.overload = Some(Overload::new(OverloadLimits {
+            queue_depth: Some(10),
+            latency: None,
+        }));
+        let broadcast = json!({ "type": "broadcast", "msg_id": 2, "message": 7 });
+        let reply = node.process(message("c1", "n1", broadcast)).unwrap();
+        assert!(reply.is_some());
+        node.observe_load(11, Duration::ZERO);
+        assert!(node.broadcast.shedding);
+
+        assert_eq!(node.tick().unwrap().len(), 1);
+        clock.advance(GOSSIP_INTERVAL);
+        assert!(node.tick().unwrap().is_empty());
+        clock.advance(GOSSIP_INTERVAL * (SHED_FACTOR - 1));
+        assert_eq!(node.tick().unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn gossip_waits_for_interval() {
+        let (mut node, clock) = node_with_clock(Workload::Broadcast);
+        node.process(message(
+            "c1",
+            "n1",
+            json!({ "type": "broadcast", "msg_id": 2, "message": 7 }),
+        ))
+        .unwrap();
+
+        assert_eq!(node.tick().unwrap().len(), 1);
+        clock.advance(GOSSIP_INTERVAL / 2);
+        assert!(node.tick().unwrap().is_empty());
+        clock.advance(GOSSIP_INTERVAL / 2);
+        assert_eq!(node.tick().unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn rejects_gossip_from_outside_cluster() {
+        let (mut node, _) = node_with_clock(Workload::Broadcast);
+        let gossip = |src| {
+            message(
+                src,
+                "n1",
+                json!({ "type": "gossip", "msg_id": 2, "messages": [7] }),
+            )
+        };
+        let reply = node.process(gossip("n9")).unwrap().unwrap();
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Node(NodePayload::Error { code: 1, .. })
+        ));
+        assert!(node.broadcast.values().is_empty());
+
+        node.allow_any_source = true;
+        node.process(gossip("n9")).unwrap();
+        assert_eq!(node.broadcast.values(), [(7, 7)]);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn journal_replay_rebuilds_state() {
+        let (mut node, _) = node_with_clock(Workload::Broadcast);
+        node.restore(Journal::default()).unwrap();
+        for (msg_id, value) in [(2, 7), (3, 8), (4, 7)] {
+            node.process(message(
+                "c1",
+                "n1",
+                json!({ "type": "broadcast", "msg_id": msg_id, "message": value }),
+            ))
+            .unwrap();
+        }
+        // The repeated 7 changed nothing and is not journaled
+        assert_eq!(node.ctx.journal.entries().len(), 3);
+
+        let (mut replayed, _) = node_with_clock(Workload::Broadcast);
+        for change in node.ctx.journal.entries() {
+            replayed.apply(change).unwrap();
+        }
+        assert_eq!(replayed.snapshot(), node.snapshot());
+
+        let (mut restored, _) = node_with_clock(Workload::Broadcast);
+        restored
+            .apply(&Change::Checkpoint {
+                snapshot: Box::new(node.snapshot()),
+            })
+            .unwrap();
+        assert_eq!(restored.broadcast.values(), [(7, 8)]);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn restart_gossips_only_what_peers_did_not_ack() {
+        let (mut node, _) = node_with_clock(Workload::Broadcast);
+        node.restore(Journal::default()).unwrap();
+        for (msg_id, value) in [(2, 8), (3, 7)] {
+            let broadcast = json!({ "type": "broadcast", "msg_id": msg_id, "message": value });
+            node.process(message("c1", "n1", broadcast)).unwrap();
+        }
+        let gossip = serde_json::to_value(node.tick().unwrap()).unwrap();
+        let ack = json!({ "type": "gossip_ok", "in_reply_to": gossip[0]["body"]["msg_id"] });
+        node.process(message("n2", "n1", ack)).unwrap();
+        // Acknowledged to the client, but not yet gossiped
+        let broadcast = json!({ "type": "broadcast", "msg_id": 4, "message": 9 });
+        node.process(message("c1", "n1", broadcast)).unwrap();
+
+        let (mut replayed, _) = node_with_clock(Workload::Broadcast);
+        for change in node.ctx.journal.entries() {
+            replayed.apply(change).unwrap();
+        }
+        let (mut restored, _) = node_with_clock(Workload::Broadcast);
+        restored
+            .apply(&Change::Checkpoint {
+                snapshot: Box::new(node.snapshot()),
+            })
+            .unwrap();
+        for mut restarted in [replayed, restored] {
+            let gossip = serde_json::to_value(restarted.tick().unwrap()).unwrap();
+            assert_eq!(gossip[0]["body"]["messages"], json!([9]));
+        }
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn unsent_reply_is_resent_after_restart() {
+        let path = std::env::temp_dir().join(format!("outbox-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (mut node, _) = node_with_clock(Workload::Broadcast);
+        node.restore(Journal::open(path.clone()).unwrap()).unwrap();
+        let reply = node
+            .process(message(
+                "c1",
+                "n1",
+                json!({ "type": "broadcast", "msg_id": 2, "message": 7 }),
+            ))
+            .unwrap();
+
+        // The node stopped before the reply went out
+        let (mut restarted, _) = node_with_clock(Workload::Broadcast);
+        let resent = restarted
+            .restore(Journal::open(path.clone()).unwrap())
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(resent).unwrap(),
+            serde_json::to_value([reply]).unwrap()
+        );
+        assert_eq!(restarted.broadcast.values(), [(7, 7)]);
+
+        restarted.sent().unwrap();
+        let (mut again, _) = node_with_clock(Workload::Broadcast);
+        let resent = again.restore(Journal::open(path.clone()).unwrap()).unwrap();
+        assert!(resent.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn kv_journal_replays_to_same_dots() {
+        let (mut node, _) = node_with_clock(Workload::Kv);
+        node.restore(Journal::default()).unwrap();
+        let mut peer = crdt_map::CrdtMap::new("n2");
+        peer.put("\"b\"", json!(2));
+        let bodies = [
+            json!({ "type": "write", "msg_id": 2, "key": "a", "value": 1 }),
+            json!({ "type": "kv_gossip", "state": peer.state() }),
+            json!({ "type": "cas", "msg_id": 3, "key": "a", "from": 1, "to": 3 }),
+            json!({ "type": "delete", "msg_id": 4, "key": "b" }),
+        ];
+        for body in bodies {
+            let src = if body["type"] == "kv_gossip" {
+                "n2"
+            } else {
+                "c1"
+            };
+            node.process(message(src, "n1", body)).unwrap();
+        }
+
+        let (mut replayed, _) = node_with_clock(Workload::Kv);
+        for change in node.ctx.journal.entries() {
+            replayed.apply(change).unwrap();
+        }
+        assert_eq!(replayed.snapshot(), node.snapshot());
+        assert_eq!(replayed.kv.state().entries.len(), 1);
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn watchers_hear_of_gossiped_changes() {
+        let (mut node, _) = node_with_clock(Workload::Kv);
+        node.restore(Journal::default()).unwrap();
+        let watch = json!({ "type": "watch", "msg_id": 1, "key": "a" });
+        node.process(message("c1", "n1", watch)).unwrap();
+        assert!(node.tick().unwrap().iter().all(|msg| msg.dst != "c1"));
+
+        let mut peer = crdt_map::CrdtMap::new("n2");
+        peer.put("\"a\"", json!(5));
+        let gossip = json!({ "type": "kv_gossip", "state": peer.state() });
+        node.process(message("n2", "n1", gossip.clone())).unwrap();
+        // The same state again is no change
+        node.process(message("n2", "n1", gossip)).unwrap();
+        let events: Vec<_> = node
+            .tick()
+            .unwrap()
+            .into_iter()
+            .filter(|msg| msg.dst == "c1")
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0].body.payload,
+            Payload::Kv(workload::kv::Payload::WatchEvent { value: Some(value), .. })
+                if *value == json!(5)
+        ));
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn expired_lease_can_be_taken_over() {
+        let (mut node, clock) = node_with_clock(Workload::Kv);
+        node.restore(Journal::default()).unwrap();
+        let request = |node: &mut Node, body| {
+            let reply = node.process(message("c1", "n1", body)).unwrap().unwrap();
+            serde_json::to_value(reply.body.payload).unwrap()
+        };
+        let take = json!({ "type": "cas", "msg_id": 1, "key": "lease", "from": null,
+                           "to": "c2", "create_if_not_exists": true, "ttl_ms": 100 });
+        request(
+            &mut node,
+            json!({ "type": "write", "msg_id": 2, "key": "lease",
+                                   "value": "c1", "ttl_ms": 100 }),
+        );
+        assert_eq!(request(&mut node, take.clone())["code"], 22);
+
+        clock.advance(Duration::from_millis(100));
+        let read = json!({ "type": "read", "msg_id": 3, "key": "lease" });
+        assert_eq!(request(&mut node, read.clone())["code"], 20);
+        // A replica that never swept expires the key at the same time
+        let (mut replica, replica_clock) = node_with_clock(Workload::Kv);
+        replica.restore(Journal::default()).unwrap();
+        replica_clock.advance(Duration::from_millis(100));
+        let gossip = json!({ "type": "kv_gossip", "state": node.kv.state() });
+        replica.process(message("n2", "n1", gossip)).unwrap();
+        assert_eq!(request(&mut replica, read.clone())["code"], 20);
+
+        assert_eq!(request(&mut node, take)["type"], "cas_ok");
+        node.tick().unwrap();
+        assert_eq!(request(&mut node, read)["value"], "c2");
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn scan_pages_through_a_key_range() {
+        let (mut node, _) = node_with_clock(Workload::Kv);
+        node.restore(Journal::default()).unwrap();
+        for (msg_id, key) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            let write = json!({ "type": "write", "msg_id": msg_id, "key": key, "value": msg_id });
+            node.process(message("c1", "n1", write)).unwrap();
+        }
+        let delete = json!({ "type": "delete", "msg_id": 9, "key": "b" });
+        node.process(message("c1", "n1", delete)).unwrap();
+
+        let mut scan = json!({ "type": "scan", "msg_id": 10, "from": "a", "to": "d", "limit": 1 });
+        let mut pages = Vec::new();
+        loop {
+            let reply = node.process(message("c1", "n1", scan.clone())).unwrap();
+            let reply = serde_json::to_value(reply.unwrap().body.payload).unwrap();
+            pages.push(reply["entries"].clone());
+            match reply.get("next") {
+                Some(next) => scan["from"] = next.clone(),
+                None => break,
+            }
+        }
+        assert_eq!(pages, [json!([["a", 0]]), json!([["c", 2]])]);
+
+        // A range that ends before it starts is empty
+        let scan = json!({ "type": "scan", "msg_id": 11, "from": "d", "to": "a" });
+        let reply = node.process(message("c1", "n1", scan)).unwrap();
+        let reply = serde_json::to_value(reply.unwrap().body.payload).unwrap();
+        assert_eq!(reply["entries"], json!([]));
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn replies_get_msg_ids_of_their_own() {
+        let (mut node, _) = node_with_clock(Workload::Broadcast);
+        let mut ids = Vec::new();
+        for client in ["c1", "c2"] {
+            let body = json!({ "type": "broadcast", "msg_id": 1, "message": 5 });
+            let reply = node.process(message(client, "n1", body)).unwrap().unwrap();
+            assert_eq!(reply.body.in_reply_to, Some(1));
+            ids.push(reply.body.id.unwrap());
+        }
+        assert!(ids[0] < ids[1]);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn topology_updates_converge_on_newest_overlay() {
+        let (mut node, clock) = node_with_clock(Workload::Broadcast);
+        let update = |msg_id, origin| {
+            message(
+                "n2",
+                "n1",
+                json!({ "type": "topology_update", "msg_id": msg_id, "epoch": 1,
+                        "origin": origin, "topology": { "n1": ["n2"], "n2": ["n1"] } }),
+            )
+        };
+        node.process(update(2, "n2")).unwrap();
+        // Same epoch from a lower node id loses the tie
+        let reply = node.process(update(3, "n0")).unwrap().unwrap();
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Broadcast(workload::broadcast::Payload::TopologyUpdateOk { epoch: 1, ref origin })
+                if origin == "n2"
+        ));
+        assert_eq!(node.broadcast.neighbors(), ["n2"]);
+
+        // The update is passed on until n2 acknowledges it
+        assert_eq!(node.tick().unwrap().len(), 1);
+        node.process(message(
+            "n2",
+            "n1",
+            json!({ "type": "topology_update_ok", "in_reply_to": 1, "epoch": 1, "origin": "n2" }),
+        ))
+        .unwrap();
+        clock.advance(GOSSIP_INTERVAL);
+        assert!(node.tick().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn repair_routes_around_silent_neighbor() {
+        let (mut node, clock) = node_with_clock(Workload::Broadcast);
+        node.broadcast.repair_topology = true;
+        for msg in [
+            json!({ "type": "topology", "msg_id": 2, "topology": { "n1": ["n2"] } }),
+            json!({ "type": "broadcast", "msg_id": 3, "message": 7 }),
+        ] {
+            node.process(message("c1", "n1", msg)).unwrap();
+        }
+        for _ in 0..workload::broadcast::SUSPECT_AFTER_ROUNDS + 2 {
+            node.tick().unwrap();
+            clock.advance(GOSSIP_INTERVAL);
+        }
+        assert_eq!(node.broadcast.overlay().epoch, 1);
+        assert!(node.broadcast.neighbors().is_empty());
+
+        // Hearing from n2 again brings it back into the overlay
+        node.process(message(
+            "n2",
+            "n1",
+            json!({ "type": "gossip", "msg_id": 9, "messages": [8] }),
+        ))
+        .unwrap();
+        node.tick().unwrap();
+        assert_eq!(node.broadcast.overlay().epoch, 2);
+        assert_eq!(node.broadcast.neighbors(), ["n2"]);
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn reads_wait_for_the_workload_to_be_known() {
+        let (mut node, _) = node_with_clock(Workload::Counter);
+        node.workload = None;
+        let read = json!({ "type": "read", "msg_id": 2 });
+        let reply = node
+            .process(message("c1", "n1", read.clone()))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Node(NodePayload::Error { code: 11, .. })
+        ));
+
+        let add = json!({ "type": "add", "msg_id": 3, "delta": 2 });
+        node.process(message("c1", "n1", add)).unwrap();
+        let reply = node.process(message("c1", "n1", read)).unwrap().unwrap();
+        let payload = serde_json::to_value(&reply.body.payload).unwrap();
+        assert_eq!(
+            (&payload["type"], &payload["value"]),
+            (&json!("read_ok"), &json!(2))
+        );
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn lin_kv_add_retries_after_timeout() {
+        let (mut node, clock) = node_with_clock(Workload::Counter);
+        node.counter.use_lin_kv("n1");
+        let read = node
+            .process(message(
+                "c1",
+                "n1",
+                json!({ "type": "add", "msg_id": 2, "delta": 3 }),
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.dst, "lin-kv");
+
+        clock.advance(Duration::from_millis(999));
+        assert!(node.tick().unwrap().iter().all(|msg| msg.dst != "lin-kv"));
+        // The timeout schedules a retry, which may be due right away
+        clock.advance(Duration::from_millis(1));
+        node.tick().unwrap();
+        clock.advance(Duration::from_secs(1));
+        let retried: Vec<_> = node
+            .tick()
+            .unwrap()
+            .into_iter()
+            .filter(|msg| msg.dst == "lin-kv")
+            .collect();
+        assert_eq!(retried.len(), 1);
+        assert!(matches!(
+            retried[0].body.payload,
+            Payload::Counter(workload::counter::Payload::Read { .. })
+        ));
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn lin_kv_cas_with_unknown_outcome_is_not_redone() {
+        let (mut node, clock) = node_with_clock(Workload::Counter);
+        node.counter.use_lin_kv("n1");
+        let add = |msg_id: usize| json!({ "type": "add", "msg_id": msg_id, "delta": 3 });
+        let read = node.process(message("c1", "n1", add(1))).unwrap().unwrap();
+        let read_ok = json!({ "type": "read_ok", "in_reply_to": read.body.id, "value": 4 });
+        let cas = node
+            .process(message("lin-kv", "n1", read_ok))
+            .unwrap()
+            .unwrap();
+
+        // The cas times out, and the client hears so instead of it being
+        // sent again
+        clock.advance(Duration::from_secs(1));
+        let out = node.tick().unwrap();
+        assert!(out.iter().all(|msg| msg.dst != "lin-kv"));
+        let reply = out.iter().find(|msg| msg.dst == "c1").unwrap();
+        assert_eq!(reply.body.in_reply_to, Some(1));
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Node(NodePayload::Error { code: 0, .. })
+        ));
+        // Its late cas_ok changes nothing, and the next add reads the value
+        // again
+        let cas_ok = json!({ "type": "cas_ok", "in_reply_to": cas.body.id });
+        assert!(node
+            .process(message("lin-kv", "n1", cas_ok))
+            .unwrap()
+            .is_none());
+        let read = node.process(message("c1", "n1", add(2))).unwrap().unwrap();
+        let read_ok = json!({ "type": "read_ok", "in_reply_to": read.body.id, "value": 7 });
+        let cas = node
+            .process(message("lin-kv", "n1", read_ok))
+            .unwrap()
+            .unwrap();
+        // A crash leaves the outcome as unknown
+        let crash = json!({ "type": "error", "in_reply_to": cas.body.id, "code": 13, "text": "" });
+        let reply = node
+            .process(message("lin-kv", "n1", crash))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.dst, "c1");
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Node(NodePayload::Error { code: 13, .. })
+        ));
+        let read = node.process(message("c1", "n1", add(3))).unwrap().unwrap();
+        let payload = serde_json::to_value(&read.body.payload).unwrap();
+        assert_eq!(payload["type"], "read");
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn lin_kv_rejects_op_ids() {
+        let (mut node, _) = node_with_clock(Workload::Counter);
+        node.counter.use_lin_kv("n1");
+        let reply = node
+            .process(message(
+                "c1",
+                "n1",
+                json!({ "type": "add", "msg_id": 1, "delta": 3, "op_id": "c1-1" }),
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.dst, "c1");
+        let payload = serde_json::to_value(&reply.body.payload).unwrap();
+        assert_eq!(payload["code"], 10);
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn lin_kv_retries_only_indefinite_errors() {
+        // A node each, as a second add would wait for the first to finish
+        let kv_error = |msg_id: usize, code: usize| {
+            let (mut node, _) = node_with_clock(Workload::Counter);
+            node.counter.use_lin_kv("n1");
+            let read = node
+                .process(message(
+                    "c1",
+                    "n1",
+                    json!({ "type": "add", "msg_id": msg_id, "delta": 3 }),
+                ))
+                .unwrap()
+                .unwrap();
+            node.process(message(
+                "lin-kv",
+                "n1",
+                json!({ "type": "error", "in_reply_to": read.body.id, "code": code, "text": "" }),
+            ))
+            .unwrap()
+        };
+
+        // temporarily-unavailable is retried later, malformed-request is not
+        assert!(kv_error(2, 11).is_none());
+        let reply = kv_error(3, 12).unwrap();
+        assert_eq!(reply.dst, "c1");
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Node(NodePayload::Error { code: 12, .. })
+        ));
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn lin_kv_adds_are_batched_and_speculative() {
+        let (mut node, _) = node_with_clock(Workload::Counter);
+        node.counter.use_lin_kv("n1");
+        let add = |client: &str, msg_id: usize, delta: i64| {
+            message(
+                client,
+                "n1",
+                json!({ "type": "add", "msg_id": msg_id, "delta": delta }),
+            )
+        };
+        let kv_reply = |request: &Message, body: serde_json::Value| {
+            let mut body = body;
+            body["in_reply_to"] = request.body.id.into();
+            message("lin-kv", "n1", body)
+        };
+
+        let read = node.process(add("c1", 1, 1)).unwrap().unwrap();
+        assert!(node.process(add("c2", 1, 2)).unwrap().is_none());
+        assert!(node.process(add("c3", 1, 3)).unwrap().is_none());
+        let cas = node
+            .process(kv_reply(&read, json!({ "type": "read_ok", "value": 10 })))
+            .unwrap()
+            .unwrap();
+        let reply = node
+            .process(kv_reply(&cas, json!({ "type": "cas_ok" })))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.dst, "c1");
+
+        // The two waiting adds go out as one, speculating that the value is
+        // still the one the last cas set
+        let out = node.tick().unwrap();
+        let cas = out.iter().find(|msg| msg.dst == "lin-kv").unwrap();
+        let payload = serde_json::to_value(&cas.body.payload).unwrap();
+        assert_eq!((&payload["from"], &payload["to"]), (&json!(11), &json!(16)));
+
+        // Another node changed it, so the batch starts over from a read
+        let read = node
+            .process(kv_reply(
+                cas,
+                json!({ "type": "error", "code": 22, "text": "" }),
+            ))
+            .unwrap()
+            .unwrap();
+        let payload = serde_json::to_value(&read.body.payload).unwrap();
+        assert_eq!(payload["type"], "read");
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn lin_kv_drops_late_and_duplicate_replies() {
+        let (mut node, clock) = node_with_clock(Workload::Counter);
+        node.counter.use_lin_kv("n1");
+        let read_ok = |request: &Message, value: i64| {
+            let body = json!({ "type": "read_ok", "in_reply_to": request.body.id, "value": value });
+            message("lin-kv", "n1", body)
+        };
+        let add = json!({ "type": "add", "msg_id": 1, "delta": 1 });
+        let read = node.process(message("c1", "n1", add)).unwrap().unwrap();
+
+        // The read times out and is sent again under a new msg_id
+        clock.advance(Duration::from_secs(1));
+        assert!(node.tick().unwrap().is_empty());
+        clock.advance(Duration::from_millis(320));
+        let retry = node.tick().unwrap().pop().unwrap();
+        assert_ne!(retry.body.id, read.body.id);
+
+        // The first read's reply arrives late, and does not move the retry
+        assert!(node.process(read_ok(&read, 5)).unwrap().is_none());
+        let cas = node.process(read_ok(&retry, 7)).unwrap().unwrap();
+        let payload = serde_json::to_value(&cas.body.payload).unwrap();
+        assert_eq!(payload["from"], 7);
+        // A duplicate of the reply that was used is dropped too
+        assert!(node.process(read_ok(&retry, 7)).unwrap().is_none());
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn gaps_in_peer_sequence_are_sent_again() {
+        let (mut node, clock) = node_with_clock(Workload::Counter);
+        let add = json!({ "type": "add", "msg_id": 1, "delta": 1 });
+        node.process(message("c1", "n1", add)).unwrap();
+        let seq = |msg: &Message| serde_json::to_value(&msg.body.payload).unwrap()["seq"].clone();
+        let first = node.tick().unwrap().pop().unwrap();
+        assert_eq!(seq(&first), 1);
+
+        // n2's second message went missing
+        let state = json!({ "totals": {}, "applied": {} });
+        for n in [1, 3] {
+            let gossip = json!({ "type": "counter_gossip", "state": state, "seq": n });
+            node.process(message("n2", "n1", gossip)).unwrap();
+        }
+        // n2 missed our first one
+        let resend = json!({ "type": "resend", "msg_id": 5, "from": 1, "to": 2 });
+        node.process(message("n2", "n1", resend)).unwrap();
+
+        clock.advance(GOSSIP_INTERVAL);
+        let out = node.tick().unwrap();
+        let payloads: Vec<_> = out
+            .iter()
+            .map(|msg| serde_json::to_value(&msg.body.payload).unwrap())
+            .collect();
+        assert!(payloads
+            .iter()
+            .any(|p| p["type"] == "resend" && p["from"] == 2 && p["to"] == 3));
+        assert!(out.iter().any(|msg| msg.body.id == first.body.id));
+        assert!(payloads
+            .iter()
+            .any(|p| p["type"] == "counter_gossip" && p["seq"] == 2));
+
+        // The resend request got lost as well, so it is made again
+        clock.advance(sequence::ASK_AGAIN_AFTER);
+        let asked = node.tick().unwrap().into_iter().any(|msg| {
+            let payload = serde_json::to_value(&msg.body.payload).unwrap();
+            payload["type"] == "resend" && payload["from"] == 2
+        });
+        assert!(asked);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn values_are_delivered_once_whichever_way_they_arrive() {
+        let (mut node, _) = node_with_clock(Workload::Broadcast);
+        use std::sync::{Arc, Mutex};
+
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let sink = delivered.clone();
+        node.broadcast.on_deliver = Some(workload::broadcast::OnDeliver(Arc::new(move |value| {
+            sink.lock().unwrap().push(value)
+        })));
+        let inputs = [
+            (
+                "c1",
+                json!({ "type": "broadcast", "msg_id": 1, "message": 5 }),
+            ),
+            (
+                "n2",
+                json!({ "type": "gossip", "msg_id": 2, "messages": [5, 6] }),
+            ),
+            (
+                "c1",
+                json!({ "type": "broadcast", "msg_id": 3, "message": 6 }),
+            ),
+            (
+                "n2",
+                json!({ "type": "gossip", "msg_id": 4, "messages": [5, 6] }),
+            ),
+        ];
+        for (src, body) in inputs {
+            node.process(message(src, "n1", body)).unwrap();
+        }
+        assert_eq!(*delivered.lock().unwrap(), [5, 6]);
+    }
+}
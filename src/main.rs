@@ -1,259 +1,333 @@
-use std::{
-    collections::{HashMap, HashSet},
-    io,
-    sync::atomic::{AtomicUsize, Ordering},
+use std::{collections::HashMap, env, fs};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use std::{path::PathBuf, time::Duration};
+
+#[cfg(feature = "kv")]
+use distributed_systems_challenges::crdt_map::Resolution;
+#[cfg(feature = "unique-ids")]
+use distributed_systems_challenges::workload::unique_ids::IdStrategy;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use distributed_systems_challenges::{
+    adaptive::AdaptiveGossip, overload::OverloadLimits, rate_limit::RateLimit,
 };
+use distributed_systems_challenges::{run, transport, Config, Workload};
 
-use anyhow::{anyhow, Result};
-use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+/// Every option, whether or not the workload that reads it is compiled
+/// in, so one config file serves any build.
+const OPTIONS: &[&str] = &[
+    "adaptive-gossip",
+    "allow-any-source",
+    "compress-gossip",
+    "counter",
+    "defer-broadcast-ok-ms",
+    "gossip-bytes-per-sec",
+    "gossip-interval-ms",
+    "gossip-msgs-per-sec",
+    "history",
+    "ids",
+    "journal",
+    "kv-resolve",
+    "max-gossip-interval-ms",
+    "memory-limit-mb",
+    "msgpack",
+    "overload-latency-ms",
+    "overload-queue-depth",
+    "read-staleness",
+    "repair-topology",
+    "target-msgs-per-op",
+    "trace",
+    "transport",
+    "view-sync",
+    "workload",
+];
 
-struct Node {
-    id: String,
-    node_ids: HashSet<String>,
-    messages: HashSet<usize>,
-    g_counter: AtomicUsize,
+/// Startup options, from the command line, then the environment, then
+/// the `--config` file.
+///
+/// The file holds one `name = value` per line, named as the flags without
+/// their dashes. Values are strings, numbers, booleans or arrays of
+/// strings, written as in JSON; `#` starts a comment. It looks like TOML
+/// but is not: there are no sections, tables, dotted keys or single-quoted
+/// strings. A flag `--some-name`
+/// is overridden from the environment as `NODE_SOME_NAME`, with arrays
+/// comma-separated.
+#[derive(Debug, Default)]
+struct Options {
+    file: HashMap<String, Value>,
 }
 
-impl Node {
-    fn from_init(msg: Message) -> Result<(Message, Self)> {
-        match msg.body.payload {
-            Payload::Init { node_id, node_ids } => Ok((
-                Message {
-                    src: msg.dst,
-                    dst: msg.src,
-                    body: Body {
-                        id: None,
-                        in_reply_to: msg.body.id,
-                        payload: Payload::InitOk {},
-                    },
-                },
-                Self {
-                    id: node_id,
-                    node_ids: node_ids.into_iter().collect(),
-                    messages: HashSet::<usize>::new(),
-                    g_counter: AtomicUsize::new(0),
-                },
-            )),
-            _ => Err(anyhow!("Message is not init type")),
-        }
-    }
-
-    fn generate_uuid(&mut self) -> String {
-        Uuid::new_v4().hyphenated().to_string()
+impl Options {
+    fn load() -> Result<Self> {
+        let Some(path) = Self::arg("--config") else {
+            return Ok(Self::default());
+        };
+        let text = fs::read_to_string(&path).with_context(|| format!("Reading {path}"))?;
+        let file = Self::parse(&text).with_context(|| format!("Parsing {path}"))?;
+        Ok(Self { file })
     }
 
-    fn process(&mut self, msg: Message) -> Result<Message> {
-        // if !self.node_ids.contains(&msg.src) || !self.node_ids.contains(&msg.dst) {
-        //     return Err(anyhow!("Src or Dst not in node_ids"));
-        // }
-        if msg.dst != self.id {
-            return Ok(Message {
-                src: self.id.clone(),
-                dst: msg.src,
-                body: Body {
-                    id: None,
-                    in_reply_to: msg.body.id,
-                    payload: Payload::Error {
-                        code: 1001, // 1000 and above are for our own uses
-                        text: "Destination does not match this node_id".to_string(),
-                    },
-                },
-            });
-        }
-        match msg.body.payload {
-            Payload::Init {
-                node_id: _,
-                node_ids: _,
-            } => Ok(Message {
-                src: self.id.clone(),
-                dst: msg.src,
-                body: Body {
-                    id: None,
-                    in_reply_to: msg.body.id,
-                    payload: Payload::Error {
-                        code: 1002,
-                        text: "Node already initialized".to_string(),
-                    },
-                },
-            }),
-            Payload::Echo { echo } => Ok(Message {
-                src: self.id.clone(),
-                dst: msg.src,
-                body: Body {
-                    id: msg.body.id,
-                    in_reply_to: msg.body.id,
-                    payload: Payload::EchoOk { echo },
-                },
-            }),
-            Payload::Generate {} => {
-                let uuid = self.generate_uuid();
-                Ok(Message {
-                    src: self.id.clone(),
-                    dst: msg.src,
-                    body: Body {
-                        id: msg.body.id,
-                        in_reply_to: msg.body.id,
-                        payload: Payload::GenerateOk { id: uuid },
-                    },
-                })
+    fn parse(text: &str) -> Result<HashMap<String, Value>> {
+        let mut file = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = uncomment(line).trim();
+            if line.is_empty() {
+                continue;
             }
-            Payload::Broadcast { message } => {
-                self.messages.insert(message);
-                Ok(Message {
-                    src: self.id.clone(),
-                    dst: msg.src,
-                    body: Body {
-                        id: msg.body.id,
-                        in_reply_to: msg.body.id,
-                        payload: Payload::BroadcastOk {},
-                    },
-                })
+            if line.starts_with('[') {
+                return Err(anyhow!(
+                    "Line {}: sections are not supported, only name = value",
+                    n + 1
+                ));
             }
-            // // This read is for topology
-            // Payload::Read {} => Ok(Message {
-            //     src: self.id.clone(),
-            //     dst: msg.src,
-            //     body: Body {
-            //         id: msg.body.id,
-            //         in_reply_to: msg.body.id,
-            //         payload: Payload::ReadOk {
-            //             messages: self.messages.clone().into_iter().collect(),
-            //         },
-            //     },
-            // }),
-            // This read is for global counter
-            Payload::Read {} => {
-                let value = self.g_counter.load(Ordering::SeqCst);
-                Ok(Message {
-                    src: self.id.clone(),
-                    dst: msg.src,
-                    body: Body {
-                        id: msg.body.id,
-                        in_reply_to: msg.body.id,
-                        payload: Payload::ReadOk { value },
-                    },
-                })
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Line {}: expected name = value", n + 1))?;
+            let name = name.trim();
+            if !OPTIONS.contains(&name) {
+                return Err(anyhow!("Line {}: unknown option {name}", n + 1));
             }
-            Payload::Topology { topology: _ } => Ok(Message {
-                src: self.id.clone(),
-                dst: msg.src,
-                body: Body {
-                    id: msg.body.id,
-                    in_reply_to: msg.body.id,
-                    payload: Payload::TopologyOk {},
-                },
-            }),
-            Payload::Add { delta } => {
-                let _value = self.g_counter.fetch_add(delta, Ordering::Relaxed);
-                Ok(Message {
-                    src: self.id.clone(),
-                    dst: msg.src,
-                    body: Body {
-                        id: msg.body.id,
-                        in_reply_to: msg.body.id,
-                        payload: Payload::AddOk {},
-                    },
-                })
-            }
-            _ => panic!("Unrecognized msg type"),
+            let value = serde_json::from_str(value.trim())
+                .map_err(|e| anyhow!("Line {}: invalid value, expected JSON: {e}", n + 1))?;
+            file.insert(name.to_string(), value);
         }
+        Ok(file)
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Message {
-    src: String,
-    #[serde(rename = "dest")]
-    dst: String,
-    body: Body,
-}
+    /// Value following `name` on the command line.
+    fn arg(name: &str) -> Option<String> {
+        env::args().skip_while(|arg| arg != name).nth(1)
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Body {
-    #[serde(rename = "msg_id")]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    id: Option<usize>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    in_reply_to: Option<usize>,
-    #[serde(flatten)]
-    payload: Payload,
-}
+    fn env(name: &str) -> Option<String> {
+        let var = name
+            .trim_start_matches('-')
+            .replace('-', "_")
+            .to_uppercase();
+        env::var(format!("NODE_{var}")).ok()
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Echo {
-        echo: String,
-    },
-    EchoOk {
-        echo: String,
-    },
-    Init {
-        node_id: String,
-        node_ids: Vec<String>,
-    },
-    InitOk {},
+    fn file(&self, name: &str) -> Option<&Value> {
+        self.file.get(name.trim_start_matches('-'))
+    }
 
-    Generate {},
-    GenerateOk {
-        id: String,
-    },
+    /// Value of the option `name`, if given.
+    fn value(&self, name: &str) -> Option<String> {
+        Self::arg(name).or_else(|| Self::env(name)).or_else(|| {
+            self.file(name).map(|value| match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            })
+        })
+    }
 
-    Broadcast {
-        message: usize,
-    },
-    BroadcastOk {},
+    /// Values of every `name` on the command line, or of the option
+    /// elsewhere.
+    #[cfg(feature = "kv")]
+    fn values(&self, name: &str) -> Result<Vec<String>> {
+        let args: Vec<String> = env::args().collect();
+        let given: Vec<String> = args
+            .windows(2)
+            .filter(|pair| pair[0] == name)
+            .map(|pair| pair[1].clone())
+            .collect();
+        if !given.is_empty() {
+            return Ok(given);
+        }
+        if let Some(var) = Self::env(name) {
+            return Ok(var.split(',').map(str::to_string).collect());
+        }
+        match self.file(name) {
+            None => Ok(Vec::new()),
+            Some(value) => {
+                serde_json::from_value(value.clone()).map_err(|e| anyhow!("Invalid {name}: {e}"))
+            }
+        }
+    }
+
+    /// Whether the switch `name` is on.
+    fn flag(&self, name: &str) -> Result<bool> {
+        if env::args().any(|arg| arg == name) {
+            return Ok(true);
+        }
+        match Self::env(name) {
+            Some(var) => var
+                .parse()
+                .map_err(|e| anyhow!("Invalid {name} {var}: {e}")),
+            None => match self.file(name) {
+                None => Ok(false),
+                Some(value) => value
+                    .as_bool()
+                    .ok_or_else(|| anyhow!("Invalid {name} {value}")),
+            },
+        }
+    }
 
-    // Topology Read
-    // Read {},
-    // ReadOk {
-    //     messages: Vec<usize>,
-    // },
-    Topology {
-        topology: HashMap<String, Vec<String>>,
-    },
-    TopologyOk {},
+    /// Number given for `name`, if any. None of the options take a
+    /// negative one.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn number(&self, name: &str) -> Result<Option<f64>> {
+        self.value(name)
+            .map(|value| {
+                let number: f64 = value
+                    .parse()
+                    .map_err(|e| anyhow!("Invalid {name} {value}: {e}"))?;
+                if !number.is_finite() || number < 0.0 {
+                    return Err(anyhow!(
+                        "Invalid {name} {value}: expected a finite number of at least 0"
+                    ));
+                }
+                Ok(number)
+            })
+            .transpose()
+    }
 
-    // Add Read
-    Read {},
-    ReadOk {
-        value: usize,
-    },
+    /// Whole number given for `name`, if any.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn count(&self, name: &str) -> Result<Option<usize>> {
+        self.number(name)?
+            .map(|n| match n.fract() == 0.0 && n <= usize::MAX as f64 {
+                true => Ok(n as usize),
+                false => Err(anyhow!("Invalid {name} {n}: expected a whole number")),
+            })
+            .transpose()
+    }
 
-    Add {
-        delta: usize,
-    },
-    AddOk {},
+    /// Milliseconds given for `name`, if any.
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    fn millis(&self, name: &str) -> Result<Option<Duration>> {
+        self.number(name)?
+            .map(|ms| {
+                Duration::try_from_secs_f64(ms / 1000.0).map_err(|e| anyhow!("Invalid {name}: {e}"))
+            })
+            .transpose()
+    }
+}
 
-    Error {
-        code: usize,
-        text: String,
-    },
+/// `line` without its `#` comment, if any outside a string.
+fn uncomment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
 }
 
 fn main() -> Result<()> {
-    let mut node = Node {
-        id: "n0".to_string(),
-        node_ids: HashSet::new(),
-        messages: HashSet::new(),
-        g_counter: AtomicUsize::new(0),
+    let options = Options::load()?;
+    let config = Config {
+        workload: Workload::parse(options.value("--workload").as_deref().unwrap_or("auto"))?,
+        allow_any_source: options.flag("--allow-any-source")?,
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        trace: options.flag("--trace")?,
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        msgpack: options.flag("--msgpack")?,
+        #[cfg(feature = "counter")]
+        kv_counter: match options.value("--counter").as_deref() {
+            None | Some("crdt") => false,
+            Some("lin-kv") => true,
+            Some(mode) => return Err(anyhow!("Unknown counter mode {mode}")),
+        },
+        #[cfg(feature = "counter")]
+        read_staleness: options.flag("--read-staleness")?,
+        #[cfg(feature = "unique-ids")]
+        id_strategy: match options.value("--ids") {
+            None => IdStrategy::default(),
+            Some(name) => {
+                IdStrategy::parse(&name).ok_or_else(|| anyhow!("Unknown id strategy {name}"))?
+            }
+        },
+        #[cfg(feature = "kv")]
+        kv_resolution: options
+            .values("--kv-resolve")?
+            .iter()
+            .map(|arg| {
+                let (prefix, name) = arg
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Expected PREFIX=POLICY, got {arg}"))?;
+                let resolution = Resolution::parse(name)
+                    .ok_or_else(|| anyhow!("Unknown resolution policy {name}"))?;
+                Ok((prefix.to_string(), resolution))
+            })
+            .collect::<Result<_>>()?,
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        gossip_limit: RateLimit {
+            msgs_per_sec: options.number("--gossip-msgs-per-sec")?,
+            bytes_per_sec: options.number("--gossip-bytes-per-sec")?,
+        },
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        gossip_interval: options.millis("--gossip-interval-ms")?,
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        journal: options.value("--journal").map(PathBuf::from),
+        #[cfg(feature = "kv")]
+        history: options.value("--history").map(PathBuf::from),
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        memory_limit: options
+            .number("--memory-limit-mb")?
+            .map(|mb| (mb * 1024.0 * 1024.0) as usize),
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        overload: {
+            let limits = OverloadLimits {
+                queue_depth: options.count("--overload-queue-depth")?,
+                latency: options.millis("--overload-latency-ms")?,
+            };
+            (limits != OverloadLimits::default()).then_some(limits)
+        },
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        gossip_key: env::var("GOSSIP_HMAC_KEY").ok().map(String::into_bytes),
+        #[cfg(feature = "broadcast")]
+        compress_gossip: options.flag("--compress-gossip")?,
+        #[cfg(feature = "broadcast")]
+        repair_topology: options.flag("--repair-topology")?,
+        #[cfg(feature = "broadcast")]
+        view_sync: options.flag("--view-sync")?,
+        #[cfg(feature = "broadcast")]
+        defer_broadcast_ok: options.millis("--defer-broadcast-ok-ms")?,
+        #[cfg(feature = "broadcast")]
+        on_deliver: None,
+        #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+        adaptive_gossip: match options.flag("--adaptive-gossip")? {
+            false => None,
+            true => {
+                let defaults = AdaptiveGossip::default();
+                Some(AdaptiveGossip {
+                    target_msgs_per_op: options
+                        .number("--target-msgs-per-op")?
+                        .unwrap_or(defaults.target_msgs_per_op),
+                    max_interval: options
+                        .millis("--max-gossip-interval-ms")?
+                        .unwrap_or(defaults.max_interval),
+                })
+            }
+        },
     };
+    let transport = transport::open(options.value("--transport").as_deref().unwrap_or("stdio"))?;
 
-    for (i, line) in io::stdin().lines().enumerate() {
-        let msg: Message = serde_json::from_str(&line?)?;
+    run(config, transport)
+}
 
-        let resp = if i == 0 {
-            let (resp, new_node) = Node::from_init(msg)?;
-            node = new_node;
-            resp
-        } else {
-            node.process(msg)?
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        println!("{}", serde_json::to_string(&resp)?);
+    #[test]
+    fn config_file_rejects_unknown_options() {
+        let file = Options::parse("# tuned\ngossip-interval-ms = 100 # faster\n").unwrap();
+        assert_eq!(file["gossip-interval-ms"], 100);
+        let e = Options::parse("workload = \"kv\"\ngossip-intervall-ms = 100\n").unwrap_err();
+        assert_eq!(e.to_string(), "Line 2: unknown option gossip-intervall-ms");
+        // TOML's sections and single quotes are not part of the format
+        assert!(Options::parse("[gossip]\ninterval-ms = 100\n").is_err());
+        assert!(Options::parse("workload = 'kv'\n").is_err());
     }
-
-    Ok(())
 }
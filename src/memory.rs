@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+
+/// Fraction of the limit usage has to fall back under before a node that
+/// went over it accepts writes again, so it does not flap at the limit.
+const RECOVER_PERCENT: usize = 90;
+
+/// Approximate bytes held, by the part of the node holding them.
+pub type Usage = BTreeMap<String, usize>;
+
+/// Watches a node's approximate memory use against a limit. Over it, the
+/// node turns away client writes and compacts what it can, so a long run
+/// slows down rather than running out of memory.
+#[derive(Debug, Clone)]
+pub struct MemoryGuard {
+    limit: usize,
+    over: bool,
+}
+
+impl MemoryGuard {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, over: false }
+    }
+
+    /// Whether the node was over its limit when last checked.
+    pub fn over(&self) -> bool {
+        self.over
+    }
+
+    /// Checks `usage` against the limit, logging when the node goes over
+    /// or back under it. Returns whether it is over.
+    pub fn check(&mut self, usage: &Usage) -> bool {
+        let total: usize = usage.values().sum();
+        if !self.over && total > self.limit {
+            self.over = true;
+            eprintln!(
+                "Over the memory limit of {} bytes with {total}, refusing writes: {usage:?}",
+                self.limit
+            );
+        } else if self.over && total <= self.limit / 100 * RECOVER_PERCENT {
+            self.over = false;
+            eprintln!("Back under the memory limit with {total} bytes, accepting writes");
+        }
+        self.over
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_only_well_under_the_limit() {
+        let mut guard = MemoryGuard::new(1000);
+        let usage = |bytes| Usage::from([("seen".to_string(), bytes)]);
+        assert!(!guard.check(&usage(1000)));
+        assert!(guard.check(&usage(1001)));
+        assert!(guard.check(&usage(950)));
+        assert!(!guard.check(&usage(900)));
+    }
+}
@@ -0,0 +1,298 @@
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Number, Value};
+
+use crate::base64;
+
+/// Body field holding the rest of a packed message's body.
+pub const MSGPACK_FIELD: &str = "msgpack";
+
+/// Body field with which a node that packs tells its peers it reads packed
+/// messages too.
+pub const WIRE_FIELD: &str = "wire";
+
+/// Wire version of nodes that read packed messages. Nodes that leave out
+/// `WIRE_FIELD` are taken to be version 1, and only get JSON.
+pub const WIRE_VERSION: u64 = 2;
+
+/// Deepest nesting decoded, as serde_json limits it when parsing.
+const MAX_DEPTH: usize = 128;
+
+/// Replaces every field of `body` but `type` with a `msgpack` field
+/// holding them as base64 MessagePack, so the message can still be told
+/// apart by type on its way.
+pub fn pack(body: &mut Map<String, Value>) {
+    let kind = body.remove("type");
+    let rest = Value::Object(std::mem::take(body));
+    if let Some(kind) = kind {
+        body.insert("type".to_string(), kind);
+    }
+    body.insert(
+        MSGPACK_FIELD.to_string(),
+        base64::encode(&encode(&rest)).into(),
+    );
+}
+
+/// Puts the fields of a packed `body` back, if it was packed.
+pub fn unpack(body: &mut Map<String, Value>) -> Result<()> {
+    let Some(packed) = body.remove(MSGPACK_FIELD) else {
+        return Ok(());
+    };
+    let packed = packed
+        .as_str()
+        .ok_or_else(|| anyhow!("Packed body is not a string"))?;
+    match decode(&base64::decode(packed)?)? {
+        Value::Object(rest) => {
+            body.extend(rest);
+            Ok(())
+        }
+        other => Err(anyhow!("Packed body is not a map: {other}")),
+    }
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write(&mut out, value);
+    out
+}
+
+fn write(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => write_uint(out, n),
+            (None, Some(n)) => write_int(out, n),
+            _ => {
+                out.push(0xcb);
+                out.extend(n.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        },
+        Value::String(s) => {
+            write_len(out, s.len(), 0xa0, 32, [0xd9, 0xda, 0xdb]);
+            out.extend(s.as_bytes());
+        }
+        Value::Array(items) => {
+            write_len(out, items.len(), 0x90, 16, [0, 0xdc, 0xdd]);
+            for item in items {
+                write(out, item);
+            }
+        }
+        Value::Object(map) => {
+            write_len(out, map.len(), 0x80, 16, [0, 0xde, 0xdf]);
+            for (key, value) in map {
+                write_len(out, key.len(), 0xa0, 32, [0xd9, 0xda, 0xdb]);
+                out.extend(key.as_bytes());
+                write(out, value);
+            }
+        }
+    }
+}
+
+fn write_uint(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0x7f => out.push(n as u8),
+        0x80..=0xff => out.extend([0xcc, n as u8]),
+        0x100..=0xffff => {
+            out.push(0xcd);
+            out.extend((n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend((n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xcf);
+            out.extend(n.to_be_bytes());
+        }
+    }
+}
+
+/// Writes a negative `n`.
+fn write_int(out: &mut Vec<u8>, n: i64) {
+    if n >= -32 {
+        out.push(n as u8);
+    } else if n >= i8::MIN.into() {
+        out.extend([0xd0, n as u8]);
+    } else if n >= i16::MIN.into() {
+        out.push(0xd1);
+        out.extend((n as i16).to_be_bytes());
+    } else if n >= i32::MIN.into() {
+        out.push(0xd2);
+        out.extend((n as i32).to_be_bytes());
+    } else {
+        out.push(0xd3);
+        out.extend(n.to_be_bytes());
+    }
+}
+
+/// Writes the header of a string, array or map of `len`: `fix | len` below
+/// `fix_limit`, and then the 8, 16 and 32 bit forms, where an 8 bit form of
+/// 0 means there is none.
+fn write_len(out: &mut Vec<u8>, len: usize, fix: u8, fix_limit: usize, markers: [u8; 3]) {
+    if len < fix_limit {
+        out.push(fix | len as u8);
+    } else if len <= 0xff && markers[0] != 0 {
+        out.extend([markers[0], len as u8]);
+    } else if len <= 0xffff {
+        out.push(markers[1]);
+        out.extend((len as u16).to_be_bytes());
+    } else {
+        out.push(markers[2]);
+        out.extend((len as u32).to_be_bytes());
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Value> {
+    let mut input = bytes;
+    let value = read(&mut input, 0)?;
+    if !input.is_empty() {
+        return Err(anyhow!("{} bytes after the value", input.len()));
+    }
+    Ok(value)
+}
+
+fn read(input: &mut &[u8], depth: usize) -> Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("Nested deeper than {MAX_DEPTH}"));
+    }
+    let marker = take(input, 1)?[0];
+    let value = match marker {
+        0x00..=0x7f => marker.into(),
+        0x80..=0x8f => read_map(input, usize::from(marker & 0x0f), depth)?,
+        0x90..=0x9f => read_array(input, usize::from(marker & 0x0f), depth)?,
+        0xa0..=0xbf => read_str(input, usize::from(marker & 0x1f))?,
+        0xc0 => Value::Null,
+        0xc2 => false.into(),
+        0xc3 => true.into(),
+        0xca => float(f32::from_be_bytes(array(input)?).into())?,
+        0xcb => float(f64::from_be_bytes(array(input)?))?,
+        0xcc => u8::from_be_bytes(array(input)?).into(),
+        0xcd => u16::from_be_bytes(array(input)?).into(),
+        0xce => u32::from_be_bytes(array(input)?).into(),
+        0xcf => u64::from_be_bytes(array(input)?).into(),
+        0xd0 => i8::from_be_bytes(array(input)?).into(),
+        0xd1 => i16::from_be_bytes(array(input)?).into(),
+        0xd2 => i32::from_be_bytes(array(input)?).into(),
+        0xd3 => i64::from_be_bytes(array(input)?).into(),
+        0xd9 => {
+            let len = usize::from(u8::from_be_bytes(array(input)?));
+            read_str(input, len)?
+        }
+        0xda => {
+            let len = usize::from(u16::from_be_bytes(array(input)?));
+            read_str(input, len)?
+        }
+        0xdb => {
+            let len = u32::from_be_bytes(array(input)?) as usize;
+            read_str(input, len)?
+        }
+        0xdc => {
+            let len = usize::from(u16::from_be_bytes(array(input)?));
+            read_array(input, len, depth)?
+        }
+        0xdd => {
+            let len = u32::from_be_bytes(array(input)?) as usize;
+            read_array(input, len, depth)?
+        }
+        0xde => {
+            let len = usize::from(u16::from_be_bytes(array(input)?));
+            read_map(input, len, depth)?
+        }
+        0xdf => {
+            let len = u32::from_be_bytes(array(input)?) as usize;
+            read_map(input, len, depth)?
+        }
+        0xe0..=0xff => (marker as i8).into(),
+        // Binary and extension types have no JSON counterpart
+        _ => return Err(anyhow!("Unsupported MessagePack type {marker:#04x}")),
+    };
+    Ok(value)
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(anyhow!("Truncated MessagePack"));
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+fn array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N]> {
+    Ok(take(input, N)?.try_into()?)
+}
+
+fn float(f: f64) -> Result<Value> {
+    Number::from_f64(f)
+        .map(Value::Number)
+        .ok_or_else(|| anyhow!("{f} has no JSON representation"))
+}
+
+fn read_str(input: &mut &[u8], len: usize) -> Result<Value> {
+    Ok(std::str::from_utf8(take(input, len)?)?.into())
+}
+
+fn read_array(input: &mut &[u8], len: usize, depth: usize) -> Result<Value> {
+    // Every item takes a byte, which bounds what a bad length allocates
+    let mut items = Vec::with_capacity(len.min(input.len()));
+    for _ in 0..len {
+        items.push(read(input, depth + 1)?);
+    }
+    Ok(Value::Array(items))
+}
+
+fn read_map(input: &mut &[u8], len: usize, depth: usize) -> Result<Value> {
+    let mut map = Map::new();
+    for _ in 0..len {
+        let Value::String(key) = read(input, depth + 1)? else {
+            return Err(anyhow!("Map key is not a string"));
+        };
+        map.insert(key, read(input, depth + 1)?);
+    }
+    Ok(Value::Object(map))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn round_trips_json() {
+        let long = "x".repeat(70_000);
+        let values = [
+            json!(null),
+            json!([true, false, 0, 127, 128, 255, 256, 65_536, u64::MAX]),
+            json!([-1, -32, -33, -128, -129, -32_769, -2_147_483_649i64, 1.5]),
+            json!({ "type": "gossip", "messages": (0..40).collect::<Vec<_>>(), "s": long }),
+            json!({ "nested": [[{ "a": "b" }]], "empty": {} }),
+        ];
+        for value in values {
+            assert_eq!(decode(&encode(&value)).unwrap(), value);
+        }
+        // From the MessagePack spec
+        assert_eq!(
+            encode(&json!({ "compact": true, "schema": 0 }))[..2],
+            [0x82, 0xa7]
+        );
+        assert!(decode(&[0x91]).is_err());
+        assert!(decode(&[0xc4, 0x01, 0x00]).is_err());
+        assert!(decode(&[0x91; 200]).is_err());
+    }
+
+    #[test]
+    fn pack_keeps_type_in_the_clear() {
+        let Value::Object(original) = json!({ "type": "gossip", "messages": [1, 2], "seq": 3 })
+        else {
+            unreachable!()
+        };
+        let mut body = original.clone();
+        pack(&mut body);
+        assert_eq!(body.len(), 2);
+        assert_eq!(body["type"], "gossip");
+        unpack(&mut body).unwrap();
+        assert_eq!(body, original);
+    }
+}
@@ -0,0 +1,94 @@
+/// Dense index of each node, from 0, by its place among the node ids
+/// sorted. Every node that knows the same membership derives the same
+/// indexes without a word exchanged, which is what Snowflake ids and tree
+/// overlays need. Adding or removing a node shifts the indexes of those
+/// after it, so users that must not reuse one across a change should
+/// derive theirs again only when the change is agreed on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeIndex {
+    // Sorted, without duplicates
+    ids: Vec<String>,
+}
+
+impl NodeIndex {
+    pub fn new<I>(ids: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let mut ids: Vec<String> = ids.into_iter().map(|id| id.as_ref().to_string()).collect();
+        ids.sort();
+        ids.dedup();
+        Self { ids }
+    }
+
+    /// Index of `id`, if it is a member.
+    pub fn index(&self, id: &str) -> Option<usize> {
+        self.ids
+            .binary_search_by(|other| other.as_str().cmp(id))
+            .ok()
+    }
+
+    /// Member at `index`.
+    pub fn id(&self, index: usize) -> Option<&str> {
+        self.ids.get(index).map(String::as_str)
+    }
+
+    /// Members in index order.
+    pub fn ids(&self) -> &[String] {
+        &self.ids
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Adds `id`, shifting the members after it up by one. Returns whether
+    /// it was not a member already.
+    pub fn insert(&mut self, id: &str) -> bool {
+        match self.ids.binary_search_by(|other| other.as_str().cmp(id)) {
+            Ok(_) => false,
+            Err(at) => {
+                self.ids.insert(at, id.to_string());
+                true
+            }
+        }
+    }
+
+    /// Removes `id`, shifting the members after it down by one. Returns
+    /// whether it was a member.
+    pub fn remove(&mut self, id: &str) -> bool {
+        match self.index(id) {
+            Some(at) => {
+                self.ids.remove(at);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_members_give_same_indexes() {
+        let mut index = NodeIndex::new(["n3", "n1", "n2", "n1"]);
+        assert_eq!(index, NodeIndex::new(["n2", "n3", "n1"]));
+        assert_eq!(index.index("n1"), Some(0));
+        assert_eq!(index.index("n3"), Some(2));
+        assert_eq!(index.index("n4"), None);
+
+        assert!(index.remove("n2"));
+        assert_eq!(index.index("n3"), Some(1));
+        assert!(index.insert("n0"));
+        assert!(!index.insert("n0"));
+        assert_eq!(index.id(0), Some("n0"));
+        assert_eq!(index.ids(), ["n0", "n1", "n3"]);
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::{Duration, Instant};
+
+/// How many times less often a node sheds gossip while overloaded, and how
+/// many times more values each gossip message carries.
+pub const SHED_FACTOR: u32 = 4;
+
+/// How long load has to stay under half the limits before the node gossips
+/// as usual again, so it does not flap at them.
+const CALM_FOR: Duration = Duration::from_secs(1);
+
+/// Past either of these a node is overloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OverloadLimits {
+    /// Messages waiting to be handled.
+    pub queue_depth: Option<usize>,
+    /// Time taken to handle one message.
+    pub latency: Option<Duration>,
+}
+
+/// Watches how far behind a node is on its input. Overloaded, the node
+/// sheds gossip, which can catch up later, to keep answering clients.
+#[derive(Debug, Clone)]
+pub struct Overload {
+    limits: OverloadLimits,
+    shedding: bool,
+    // Since when load was under half the limits, while shedding
+    calm_since: Option<Instant>,
+}
+
+impl Overload {
+    pub fn new(limits: OverloadLimits) -> Self {
+        Self {
+            limits,
+            shedding: false,
+            calm_since: None,
+        }
+    }
+
+    /// Whether the node is shedding gossip.
+    pub fn shedding(&self) -> bool {
+        self.shedding
+    }
+
+    /// Records the messages still queued once one took `latency` to
+    /// handle, logging when the node starts or stops shedding. Returns
+    /// whether it is shedding.
+    pub fn observe(&mut self, depth: usize, latency: Duration, now: Instant) -> bool {
+        let over = self.limits.queue_depth.is_some_and(|limit| depth > limit)
+            || self.limits.latency.is_some_and(|limit| latency > limit);
+        let calm = self
+            .limits
+            .queue_depth
+            .is_none_or(|limit| depth <= limit / 2)
+            && self.limits.latency.is_none_or(|limit| latency <= limit / 2);
+        if !self.shedding {
+            if over {
+                self.shedding = true;
+                self.calm_since = None;
+                eprintln!(
+                    "Overloaded with {depth} messages queued, {latency:?} to handle the last: \
+                     gossiping {SHED_FACTOR}x less often in {SHED_FACTOR}x larger batches"
+                );
+            }
+        } else if !calm {
+            self.calm_since = None;
+        } else if now.duration_since(*self.calm_since.get_or_insert(now)) >= CALM_FOR {
+            self.shedding = false;
+            self.calm_since = None;
+            eprintln!("No longer overloaded, gossiping as usual");
+        }
+        self.shedding
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheds_until_calm_for_a_while() {
+        let mut overload = Overload::new(OverloadLimits {
+            queue_depth: Some(100),
+            latency: Some(Duration::from_millis(10)),
+        });
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        assert!(!overload.observe(100, ms(10), start));
+        assert!(overload.observe(101, ms(1), start));
+        // Under the limits, but not by half
+        assert!(overload.observe(60, ms(1), start + CALM_FOR * 2));
+        assert!(overload.observe(50, ms(5), start + CALM_FOR * 2));
+        assert!(overload.observe(0, ms(0), start + CALM_FOR * 3 - ms(1)));
+        assert!(!overload.observe(0, ms(0), start + CALM_FOR * 3));
+    }
+}
@@ -0,0 +1,83 @@
+use std::{collections::HashMap, time::Instant};
+
+/// Limits on the background sync traffic sent to each peer. `None` leaves
+/// that dimension unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    pub msgs_per_sec: Option<f64>,
+    pub bytes_per_sec: Option<f64>,
+}
+
+/// Refills at `rate` tokens per second up to one second's worth.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.tokens = (self.tokens + self.rate * elapsed.as_secs_f64()).min(self.rate);
+        self.last = now;
+    }
+
+    /// Whether `amount` tokens are available. A request larger than a whole
+    /// bucket is let through once the bucket is full, so it is not starved,
+    /// and the overdraft is paid back before anything else goes out.
+    fn has(&self, amount: f64) -> bool {
+        self.tokens >= amount.min(self.rate)
+    }
+}
+
+/// Token buckets for every peer, checked before each gossip send.
+#[derive(Debug, Default)]
+pub struct PeerLimiter {
+    limit: RateLimit,
+    buckets: HashMap<String, (Option<TokenBucket>, Option<TokenBucket>)>,
+}
+
+impl PeerLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes one message of `bytes` from `peer`'s budget if it has room.
+    /// A message that is not allowed should be dropped; gossip is resent.
+    pub fn allow(&mut self, peer: &str, bytes: usize, now: Instant) -> bool {
+        let limit = self.limit;
+        let (msgs, size) = self.buckets.entry(peer.to_string()).or_insert_with(|| {
+            (
+                limit.msgs_per_sec.map(|rate| TokenBucket::new(rate, now)),
+                limit.bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            )
+        });
+
+        for bucket in [&mut *msgs, &mut *size].into_iter().flatten() {
+            bucket.refill(now);
+        }
+        let allowed = msgs.as_ref().is_none_or(|bucket| bucket.has(1.0))
+            && size.as_ref().is_none_or(|bucket| bucket.has(bytes as f64));
+        if allowed {
+            if let Some(bucket) = msgs {
+                bucket.tokens -= 1.0;
+            }
+            if let Some(bucket) = size {
+                bucket.tokens -= bytes as f64;
+            }
+        }
+        allowed
+    }
+}
@@ -0,0 +1,43 @@
+use std::time::{Duration, Instant};
+
+/// How often the redundancy ratio is logged, when anything arrived.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Counts how many times broadcast values arrive, from clients and from
+/// gossip, against how many of them were new.
+#[derive(Debug, Default)]
+pub struct Redundancy {
+    deliveries: u64,
+    unique: u64,
+    last_report: Option<Instant>,
+}
+
+impl Redundancy {
+    /// Counts `deliveries` values arriving, `new` of which were not seen
+    /// before.
+    pub fn record(&mut self, deliveries: usize, new: usize) {
+        self.deliveries += deliveries as u64;
+        self.unique += new as u64;
+    }
+
+    /// Deliveries per distinct value; 1.0 means nothing arrived twice.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.unique > 0).then(|| self.deliveries as f64 / self.unique as f64)
+    }
+
+    /// Summary line to log, at most once per `REPORT_INTERVAL`.
+    pub fn report(&mut self, now: Instant) -> Option<String> {
+        if self
+            .last_report
+            .is_some_and(|last| now.duration_since(last) < REPORT_INTERVAL)
+        {
+            return None;
+        }
+        let ratio = self.ratio()?;
+        self.last_report = Some(now);
+        Some(format!(
+            "Broadcast redundancy: {} deliveries of {} values ({ratio:.2}x)",
+            self.deliveries, self.unique
+        ))
+    }
+}
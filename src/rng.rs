@@ -0,0 +1,21 @@
+/// SplitMix64, so runs with the same seed draw the same numbers.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Rng(pub u64);
+
+impl Rng {
+    /// Uniformly below `n`.
+    #[cfg_attr(not(feature = "sim"), allow(dead_code))]
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_f64() * n as f64) as usize
+    }
+
+    /// Uniformly in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,169 @@
+use std::cmp::Ordering;
+
+/// Set of broadcast values stored as sorted, non-overlapping, non-adjacent
+/// inclusive runs. Dense value ranges (which is what Maelstrom generates)
+/// collapse into a handful of runs, so both memory use and set operations
+/// scale with the number of runs rather than the number of values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SeenSet {
+    runs: Vec<(usize, usize)>,
+}
+
+impl SeenSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index of the run containing `value`, or the index at which a run
+    /// starting at `value` would be inserted.
+    fn search(&self, value: usize) -> Result<usize, usize> {
+        self.runs.binary_search_by(|&(start, end)| {
+            if end < value {
+                Ordering::Less
+            } else if start > value {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        })
+    }
+
+    /// Inserts `value`, returning whether it was not already present.
+    pub fn insert(&mut self, value: usize) -> bool {
+        let i = match self.search(value) {
+            Ok(_) => return false,
+            Err(i) => i,
+        };
+
+        let joins_prev = i > 0 && self.runs[i - 1].1 + 1 == value;
+        let joins_next = i < self.runs.len() && value + 1 == self.runs[i].0;
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                self.runs[i - 1].1 = self.runs[i].1;
+                self.runs.remove(i);
+            }
+            (true, false) => self.runs[i - 1].1 = value,
+            (false, true) => self.runs[i].0 = value,
+            (false, false) => self.runs.insert(i, (value, value)),
+        }
+        true
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        self.search(value).is_ok()
+    }
+
+    /// Builds a set from inclusive runs in any order, which may overlap.
+    /// Runs whose start is past their end are ignored.
+    pub fn from_runs(mut runs: Vec<(usize, usize)>) -> Self {
+        runs.retain(|&(start, end)| start <= end);
+        runs.sort_unstable();
+        let mut set = SeenSet::new();
+        for (start, end) in runs {
+            set.push_run(start, end);
+        }
+        set
+    }
+
+    /// Values not below `from`, in order.
+    pub fn iter_from(&self, from: usize) -> impl Iterator<Item = usize> + '_ {
+        let (Ok(i) | Err(i)) = self.search(from);
+        self.runs[i..]
+            .iter()
+            .flat_map(move |&(start, end)| start.max(from)..=end)
+    }
+
+    /// Approximate bytes held.
+    pub fn memory(&self) -> usize {
+        self.runs.len() * std::mem::size_of::<(usize, usize)>()
+    }
+
+    pub fn runs(&self) -> &[(usize, usize)] {
+        &self.runs
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.runs.iter().flat_map(|&(start, end)| start..=end)
+    }
+
+    /// Values in `self` that are not in `other`, computed run by run.
+    pub fn difference(&self, other: &SeenSet) -> SeenSet {
+        let mut out = SeenSet::new();
+        let mut theirs = other.runs.iter().peekable();
+
+        for &(start, end) in &self.runs {
+            let mut cursor = start;
+            let mut covered = false;
+            while let Some(&&(o_start, o_end)) = theirs.peek() {
+                if o_end < cursor {
+                    theirs.next();
+                    continue;
+                }
+                if o_start > end {
+                    break;
+                }
+                if o_start > cursor {
+                    out.push_run(cursor, o_start - 1);
+                }
+                if o_end >= end {
+                    covered = true;
+                    break;
+                }
+                cursor = o_end + 1;
+                theirs.next();
+            }
+            if !covered {
+                out.push_run(cursor, end);
+            }
+        }
+        out
+    }
+
+    /// Adds every value of `other` to `self`.
+    pub fn union_with(&mut self, other: &SeenSet) {
+        let mut merged = SeenSet::new();
+        let mut mine = self.runs.iter().peekable();
+        let mut theirs = other.runs.iter().peekable();
+
+        loop {
+            let next = match (mine.peek(), theirs.peek()) {
+                (Some(a), Some(b)) if a.0 <= b.0 => mine.next(),
+                (Some(_), Some(_)) => theirs.next(),
+                (Some(_), None) => mine.next(),
+                (None, Some(_)) => theirs.next(),
+                (None, None) => break,
+            };
+            let &(start, end) = next.expect("peeked a run");
+            merged.push_run(start, end);
+        }
+        *self = merged;
+    }
+
+    /// Appends a run whose start is not below the start of the last run,
+    /// coalescing it with the last run when they overlap or touch.
+    fn push_run(&mut self, start: usize, end: usize) {
+        if let Some(last) = self.runs.last_mut() {
+            if start <= last.1.saturating_add(1) {
+                last.1 = last.1.max(end);
+                return;
+            }
+        }
+        self.runs.push((start, end));
+    }
+}
+
+impl FromIterator<usize> for SeenSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut set = SeenSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl Extend<usize> for SeenSet {
+    fn extend<I: IntoIterator<Item = usize>>(&mut self, iter: I) {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Body field holding a message's sequence number.
+pub const SEQ_FIELD: &str = "seq";
+
+/// Body field telling which run of the sender the sequence number belongs
+/// to, as numbering starts over when it restarts.
+pub const INCARNATION_FIELD: &str = "incarnation";
+
+/// How many messages to each peer are kept to be sent again, and how many
+/// may arrive past a gap before it is given up on.
+const WINDOW: usize = 256;
+
+/// How long a gap may stay open before it is asked for again, in case the
+/// request or what was sent again got lost too.
+pub const ASK_AGAIN_AFTER: Duration = Duration::from_secs(1);
+
+/// Numbers the messages sent to each peer from 1, keeping the last
+/// `WINDOW` of them so a peer that missed some can have them sent again.
+#[derive(Debug)]
+pub struct Outgoing<T> {
+    // Last sequence number given out to each peer, and what was sent under
+    // the ones still kept
+    peers: HashMap<String, (u64, VecDeque<(u64, T)>)>,
+    incarnation: Option<u64>,
+}
+
+impl<T> Default for Outgoing<T> {
+    fn default() -> Self {
+        Self {
+            peers: HashMap::new(),
+            incarnation: None,
+        }
+    }
+}
+
+impl<T: Clone> Outgoing<T> {
+    /// Which run of this node the numbering belongs to: the Unix
+    /// milliseconds `unix_ms` of the first call, so a restarted node
+    /// numbers under a later one.
+    pub fn incarnation(&mut self, unix_ms: u64) -> u64 {
+        *self.incarnation.get_or_insert(unix_ms)
+    }
+
+    /// Makes the item to send `peer` under the next sequence number, and
+    /// keeps a copy of it.
+    pub fn number(&mut self, peer: &str, make: impl FnOnce(u64) -> T) -> T {
+        let (last, sent) = self.peers.entry(peer.to_string()).or_default();
+        *last += 1;
+        let item = make(*last);
+        sent.push_back((*last, item.clone()));
+        if sent.len() > WINDOW {
+            sent.pop_front();
+        }
+        item
+    }
+
+    /// Everything kept to be sent again.
+    pub fn held(&self) -> impl Iterator<Item = &T> {
+        self.peers
+            .values()
+            .flat_map(|(_, sent)| sent.iter().map(|(_, item)| item))
+    }
+
+    /// Stops keeping what was sent, while numbering carries on. Peers that
+    /// ask for it again are left to catch up through gossip.
+    pub fn forget(&mut self) {
+        for (_, sent) in self.peers.values_mut() {
+            sent.clear();
+        }
+    }
+
+    /// What was sent to `peer` from `from` up to but not including `to`,
+    /// as far as it is still kept.
+    pub fn resend(&self, peer: &str, from: u64, to: u64) -> Vec<T> {
+        self.peers.get(peer).map_or_else(Vec::new, |(_, sent)| {
+            sent.iter()
+                .filter(|(seq, _)| (from..to).contains(seq))
+                .map(|(_, item)| item.clone())
+                .collect()
+        })
+    }
+}
+
+/// What receiving a numbered message revealed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Receipt {
+    /// Whether the message was not received before.
+    pub fresh: bool,
+    /// Sequence numbers, from the first up to but not including the
+    /// second, that this message showed missing and that nobody asked to
+    /// be sent again yet.
+    pub missing: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Default)]
+struct Stream {
+    // Lowest sequence number not received; all before it were
+    next: u64,
+    // Received past a gap
+    ahead: BTreeSet<u64>,
+    // Highest sequence number received or asked for again
+    requested: u64,
+    // When gaps were last asked for
+    asked: Option<Instant>,
+    // Run of the peer the numbers belong to, if it says
+    incarnation: Option<u64>,
+}
+
+impl Stream {
+    /// Sequence numbers still missing before the last one received, as
+    /// ranges from the first up to but not including the second.
+    fn gaps(&self) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut from = self.next;
+        for &seq in &self.ahead {
+            if seq > from {
+                gaps.push((from, seq));
+            }
+            from = seq + 1;
+        }
+        gaps
+    }
+}
+
+/// Tracks the sequence numbers received from each peer, telling gaps from
+/// reordering and duplicates. A protocol that needs its messages in order
+/// can hold back those past `contiguous`. A gap still open after
+/// `ASK_AGAIN_AFTER` is asked for again, and given up on once `WINDOW`
+/// messages arrived past it, as the sender no longer keeps what fell in it.
+#[derive(Debug, Default)]
+pub struct Incoming {
+    peers: HashMap<String, Stream>,
+}
+
+impl Incoming {
+    /// Records message `seq` of `peer`'s run `incarnation`, if it says.
+    pub fn receive(
+        &mut self,
+        peer: &str,
+        incarnation: Option<u64>,
+        seq: u64,
+        now: Instant,
+    ) -> Receipt {
+        let stream = self.peers.entry(peer.to_string()).or_insert(Stream {
+            next: 1,
+            incarnation,
+            ..Default::default()
+        });
+        // Numbering starts over when the peer restarts. Peers that do not
+        // say which run they are on are taken to have restarted when they
+        // start over from 1.
+        let restarted = match incarnation {
+            Some(_) => incarnation > stream.incarnation,
+            None => seq == 1 && stream.next > 1,
+        };
+        if incarnation < stream.incarnation {
+            // From before the peer restarted
+            return Receipt::default();
+        }
+        if restarted {
+            *stream = Stream {
+                next: 1,
+                incarnation,
+                ..Default::default()
+            };
+        }
+        if seq < stream.next || !stream.ahead.insert(seq) {
+            return Receipt::default();
+        }
+        let missing = (seq > stream.next && seq > stream.requested + 1)
+            .then(|| (stream.next.max(stream.requested + 1), seq));
+        stream.requested = stream.requested.max(seq);
+        if missing.is_some() {
+            stream.asked = Some(now);
+        }
+        while stream.ahead.remove(&stream.next) {
+            stream.next += 1;
+        }
+        if stream.ahead.len() > WINDOW {
+            // Skip to the oldest message past the gap
+            if let Some(&first) = stream.ahead.first() {
+                stream.next = first;
+                while stream.ahead.remove(&stream.next) {
+                    stream.next += 1;
+                }
+            }
+        }
+        Receipt {
+            fresh: true,
+            missing,
+        }
+    }
+
+    /// Gaps, by peer, asked for `ASK_AGAIN_AFTER` ago or longer and still
+    /// open, to ask for again now.
+    pub fn overdue(&mut self, now: Instant) -> Vec<(String, u64, u64)> {
+        let mut overdue = Vec::new();
+        for (peer, stream) in &mut self.peers {
+            let due = stream
+                .asked
+                .is_none_or(|asked| now.duration_since(asked) >= ASK_AGAIN_AFTER);
+            if !due || stream.ahead.is_empty() {
+                continue;
+            }
+            stream.asked = Some(now);
+            let gaps = stream.gaps().into_iter();
+            overdue.extend(gaps.map(|(from, to)| (peer.clone(), from, to)));
+        }
+        overdue
+    }
+
+    /// Everything from `peer` before this sequence number was received.
+    pub fn contiguous(&self, peer: &str) -> u64 {
+        self.peers.get(peer).map_or(1, |stream| stream.next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asks_once_for_each_gap() {
+        let now = Instant::now();
+        let mut incoming = Incoming::default();
+        assert!(incoming.receive("n2", None, 1, now).fresh);
+        let receipt = incoming.receive("n2", None, 4, now);
+        assert_eq!(receipt.missing, Some((2, 4)));
+        // Reordered, not lost: nothing more to ask for
+        assert_eq!(incoming.receive("n2", None, 3, now).missing, None);
+        assert_eq!(incoming.receive("n2", None, 6, now).missing, Some((5, 6)));
+        assert!(!incoming.receive("n2", None, 4, now).fresh);
+        assert_eq!(incoming.contiguous("n2"), 2);
+
+        let mut outgoing = Outgoing::default();
+        for _ in 0..5 {
+            outgoing.number("n1", |seq| seq);
+        }
+        let resent = [outgoing.resend("n1", 2, 3), outgoing.resend("n1", 5, 6)].concat();
+        assert_eq!(resent, [2, 5]);
+        for seq in resent {
+            assert!(incoming.receive("n2", None, seq, now).fresh);
+        }
+        assert_eq!(incoming.contiguous("n2"), 7);
+    }
+
+    #[test]
+    fn asks_again_for_gaps_left_open() {
+        let now = Instant::now();
+        let mut incoming = Incoming::default();
+        incoming.receive("n2", None, 1, now);
+        incoming.receive("n2", None, 3, now);
+        incoming.receive("n2", None, 6, now);
+        assert!(incoming.overdue(now + ASK_AGAIN_AFTER / 2).is_empty());
+
+        let later = now + ASK_AGAIN_AFTER;
+        let gaps = [("n2".to_string(), 2, 3), ("n2".to_string(), 4, 6)];
+        assert_eq!(incoming.overdue(later), gaps);
+        assert!(incoming.overdue(later).is_empty());
+        incoming.receive("n2", None, 2, later);
+        incoming.receive("n2", None, 4, later);
+        incoming.receive("n2", None, 5, later);
+        assert!(incoming.overdue(later + ASK_AGAIN_AFTER).is_empty());
+    }
+
+    #[test]
+    fn starts_over_when_the_peer_restarts() {
+        let now = Instant::now();
+        let mut incoming = Incoming::default();
+        for seq in 1..=5 {
+            assert!(incoming.receive("n2", Some(10), seq, now).fresh);
+        }
+        assert!(!incoming.receive("n2", Some(10), 1, now).fresh);
+        // The restarted peer's first message went missing
+        let receipt = incoming.receive("n2", Some(20), 2, now);
+        assert!(receipt.fresh);
+        assert_eq!(receipt.missing, Some((1, 2)));
+        assert!(!incoming.receive("n2", Some(10), 6, now).fresh);
+    }
+}
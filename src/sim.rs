@@ -0,0 +1,772 @@
+//! Deterministic in-process cluster for tests and soak runs: every node
+//! shares one manual clock, and messages between nodes take one step to
+//! arrive unless a partition drops them, the node they are for is down, or
+//! a link is given a latency of its own.
+
+pub mod soak;
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    clock::{Clock, ManualClock},
+    rng::Rng,
+    Message, Node, RawMessage, Workload, TICK_INTERVAL,
+};
+
+/// How long messages on a link take to arrive. Messages are delivered on
+/// the first step at or after they are due, so anything up to a tick takes
+/// one step.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "broadcast"), allow(dead_code))]
+pub enum Latency {
+    Fixed(Duration),
+    /// Anywhere from the first to the second, uniformly.
+    Uniform(Duration, Duration),
+    /// Exponentially distributed with this mean, as queueing delays are.
+    Exponential(Duration),
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut Rng) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform(low, high) => low + (high - low).mul_f64(rng.next_f64()),
+            Latency::Exponential(mean) => mean.mul_f64(-(1.0 - rng.next_f64()).ln()),
+        }
+    }
+}
+
+pub struct Sim {
+    clock: Rc<ManualClock>,
+    nodes: BTreeMap<String, Node>,
+    // Messages between nodes, with when they are due, in the order sent
+    in_flight: Vec<(Instant, Message)>,
+    // Partition each node is in; nodes only hear from their own
+    partition: HashMap<String, usize>,
+    // Links messages are dropped on in one direction only, as (src, dest)
+    cut: HashSet<(String, String)>,
+    latency: Latency,
+    // Links with a latency of their own, as (src, dest)
+    link_latency: HashMap<(String, String), Latency>,
+    // Crashed nodes, which neither receive messages nor tick
+    down: BTreeSet<String>,
+    rng: Rng,
+    next_client_msg_id: usize,
+    // Replies to clients, by the msg_id of the request
+    replies: HashMap<usize, Message>,
+}
+
+impl Sim {
+    pub fn new(workload: Workload, nodes: usize) -> Self {
+        let clock = Rc::new(ManualClock::new());
+        let node_ids: Vec<String> = (1..=nodes).map(|i| format!("n{i}")).collect();
+        let nodes = node_ids
+            .iter()
+            .map(|id| (id.clone(), boot(id, &node_ids, workload, &clock)))
+            .collect();
+        Self {
+            clock,
+            nodes,
+            in_flight: Vec::new(),
+            partition: HashMap::new(),
+            cut: HashSet::new(),
+            latency: Latency::Fixed(TICK_INTERVAL),
+            link_latency: HashMap::new(),
+            down: BTreeSet::new(),
+            rng: Rng(1),
+            next_client_msg_id: 0,
+            replies: HashMap::new(),
+        }
+    }
+
+    #[cfg(feature = "broadcast")]
+    pub(crate) fn node_mut(&mut self, id: &str) -> &mut Node {
+        self.nodes.get_mut(id).unwrap()
+    }
+
+    pub fn node_ids(&self) -> Vec<String> {
+        self.nodes.keys().cloned().collect()
+    }
+
+    /// Sends a client request with `body` to `node` and handles it right
+    /// away, returning the request's msg_id. A node that is down never
+    /// replies.
+    pub fn request(&mut self, node: &str, mut body: Value) -> usize {
+        self.next_client_msg_id += 1;
+        let msg_id = self.next_client_msg_id;
+        if self.down.contains(node) {
+            return msg_id;
+        }
+        body["msg_id"] = msg_id.into();
+        let msg = raw(json!({ "src": "c1", "dest": node, "body": body }));
+        let out = self.nodes.get_mut(node).unwrap().process(msg).unwrap();
+        self.route(out);
+        msg_id
+    }
+
+    /// The reply to the client request `msg_id`, once it arrived.
+    pub fn reply(&self, msg_id: usize) -> Option<&Message> {
+        self.replies.get(&msg_id)
+    }
+
+    /// Splits the cluster so nodes only reach those in the same group.
+    /// Nodes in no group are cut off from everyone.
+    pub fn partition(&mut self, groups: &[&[&str]]) {
+        self.partition = self
+            .nodes
+            .keys()
+            .enumerate()
+            .map(|(i, id)| (id.clone(), groups.len() + i))
+            .collect();
+        for (group, members) in groups.iter().enumerate() {
+            for member in *members {
+                self.partition.insert(member.to_string(), group);
+            }
+        }
+    }
+
+    #[cfg(feature = "broadcast")]
+    /// Drops messages from `src` to `dest`, while those the other way
+    /// still arrive.
+    pub fn cut(&mut self, src: &str, dest: &str) {
+        self.cut.insert((src.to_string(), dest.to_string()));
+    }
+
+    /// Stops `id` as if it crashed: until restarted it drops what arrives
+    /// and does not tick.
+    pub fn crash(&mut self, id: &str) {
+        self.down.insert(id.to_string());
+    }
+
+    /// Starts `id` again with what it journaled before it went down,
+    /// losing everything else, as a restarted process would.
+    pub fn restart(&mut self, id: &str) {
+        if !self.down.remove(id) {
+            return;
+        }
+        let node_ids = self.node_ids();
+        let mut old = self.nodes.remove(id).unwrap();
+        let mut node = boot(id, &node_ids, old.workload.unwrap(), &self.clock);
+        #[cfg(feature = "broadcast")]
+        {
+            node.broadcast.repair_topology = old.broadcast.repair_topology;
+            node.broadcast.view_sync = old.broadcast.view_sync;
+        }
+        let outbox = node.restore(std::mem::take(&mut old.ctx.journal)).unwrap();
+        self.nodes.insert(id.to_string(), node);
+        self.route(outbox);
+    }
+
+    /// Nodes that are down.
+    pub fn down(&self) -> Vec<String> {
+        self.down.iter().cloned().collect()
+    }
+
+    /// Undoes partitions and cuts.
+    pub fn heal(&mut self) {
+        self.partition.clear();
+        self.cut.clear();
+    }
+
+    #[cfg(feature = "broadcast")]
+    /// Latency of every link not given one with `set_link_latency`.
+    pub fn set_latency(&mut self, latency: Latency) {
+        self.latency = latency;
+    }
+
+    #[cfg(feature = "broadcast")]
+    /// Latency of messages from `src` to `dest`, leaving the other way as
+    /// it was.
+    pub fn set_link_latency(&mut self, src: &str, dest: &str, latency: Latency) {
+        self.link_latency
+            .insert((src.to_string(), dest.to_string()), latency);
+    }
+
+    #[cfg(feature = "broadcast")]
+    /// Samples latencies from `seed` from now on, instead of the default.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Rng(seed);
+    }
+
+    /// Advances time by one tick: delivers what is due, then ticks every
+    /// node. Returns how many messages went between nodes.
+    pub fn step(&mut self) -> usize {
+        self.clock.advance(TICK_INTERVAL);
+        let now = self.clock.now();
+        let (mut delivered, in_flight) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition(|(due, _)| *due <= now);
+        self.in_flight = in_flight;
+        delivered.sort_by_key(|(due, _)| *due);
+        let count = delivered.len();
+        for (_, msg) in delivered {
+            if self.down.contains(&msg.dst) {
+                continue;
+            }
+            let Some(node) = self.nodes.get_mut(&msg.dst) else {
+                continue;
+            };
+            let msg = raw(serde_json::to_value(msg).unwrap());
+            let out = node.process(msg).unwrap();
+            self.route(out);
+        }
+        let mut ids = self.node_ids();
+        ids.retain(|id| !self.down.contains(id));
+        for id in ids {
+            let out = self.nodes.get_mut(&id).unwrap().tick().unwrap();
+            self.route(out);
+        }
+        count
+    }
+
+    pub fn run(&mut self, duration: Duration) {
+        for _ in 0..duration.as_millis() / TICK_INTERVAL.as_millis() {
+            self.step();
+        }
+    }
+
+    /// Runs until no node has sent another a message for `quiet`, or fails
+    /// once `limit` passed without that happening.
+    #[cfg(feature = "broadcast")]
+    pub fn settle(&mut self, quiet: Duration, limit: Duration) {
+        let steps = |d: Duration| d.as_millis() / TICK_INTERVAL.as_millis();
+        let mut idle = 0;
+        for _ in 0..steps(limit) {
+            idle = match self.step() + self.in_flight.len() {
+                0 => idle + 1,
+                _ => 0,
+            };
+            if idle >= steps(quiet) {
+                return;
+            }
+        }
+        panic!("Cluster still busy after {limit:?}");
+    }
+
+    fn route(&mut self, out: impl IntoIterator<Item = Message>) {
+        for msg in out {
+            if !self.nodes.contains_key(&msg.dst) {
+                if let Some(id) = msg.body.in_reply_to {
+                    self.replies.insert(id, msg);
+                }
+                continue;
+            }
+            let link = (msg.src.clone(), msg.dst.clone());
+            if self.partition.get(&msg.src) != self.partition.get(&msg.dst)
+                || self.cut.contains(&link)
+            {
+                continue;
+            }
+            let latency = self.link_latency.get(&link).unwrap_or(&self.latency);
+            let due = self.clock.now() + latency.sample(&mut self.rng);
+            self.in_flight.push((due, msg));
+        }
+    }
+}
+
+/// Kinds of client request a `Load` issues.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    not(all(feature = "broadcast", feature = "counter", feature = "kv")),
+    allow(dead_code)
+)]
+pub enum Request {
+    /// A broadcast of a value no other request broadcast.
+    Broadcast,
+    /// An add of 1 to 5.
+    Add,
+    /// A read, of a `Load::keys` key for kv.
+    Read,
+    Write,
+    Cas,
+}
+
+/// Client traffic shaped like Maelstrom's: requests at a steady rate to
+/// random nodes, picked from a weighted mix, with kv requests skewed
+/// towards one hot key. A request left unanswered for `timeout` is sent
+/// again to another node, up to `retries` times.
+#[derive(Debug, Clone)]
+pub struct Load {
+    /// Requests per second across the cluster.
+    pub rate: f64,
+    /// Requests to pick from, with their relative weights.
+    pub mix: Vec<(Request, u32)>,
+    /// Keys kv requests pick from.
+    pub keys: usize,
+    /// Share of kv requests that go to key 0.
+    pub hot: f64,
+    pub timeout: Duration,
+    pub retries: usize,
+}
+
+/// A request a `Load` issued and how it ended.
+#[derive(Debug)]
+pub struct Outcome {
+    pub body: Value,
+    /// The reply to the last attempt, if it came.
+    pub reply: Option<Value>,
+    pub attempts: usize,
+}
+
+impl Outcome {
+    /// Whether the request succeeded, as opposed to failing or never
+    /// being answered.
+    pub fn ok(&self) -> bool {
+        self.reply
+            .as_ref()
+            .is_some_and(|reply| reply["body"]["type"] != "error")
+    }
+}
+
+impl Sim {
+    /// Runs the cluster for `duration` under `load`, then waits out the
+    /// requests still unanswered. Returns every request in the order
+    /// issued.
+    pub fn drive(&mut self, load: &Load, duration: Duration) -> Vec<Outcome> {
+        let mut outcomes: Vec<Outcome> = Vec::new();
+        // Attempts awaiting a reply, as (outcome, msg_id, sent at)
+        let mut pending: Vec<(usize, usize, Instant)> = Vec::new();
+        let total: u32 = load.mix.iter().map(|(_, weight)| weight).sum();
+        let start = self.clock.now();
+        let mut due = 0.0;
+        while self.clock.now() - start < duration || !pending.is_empty() {
+            if self.clock.now() - start < duration {
+                due += load.rate * TICK_INTERVAL.as_secs_f64();
+            }
+            while due >= 1.0 {
+                due -= 1.0;
+                let body = self.generate(load, total, outcomes.len());
+                let msg_id = self.send_anywhere(body.clone());
+                pending.push((outcomes.len(), msg_id, self.clock.now()));
+                outcomes.push(Outcome {
+                    body,
+                    reply: None,
+                    attempts: 1,
+                });
+            }
+            self.step();
+
+            let now = self.clock.now();
+            let mut retried = Vec::new();
+            pending.retain(|&(i, msg_id, sent)| {
+                if let Some(reply) = self.replies.get(&msg_id) {
+                    outcomes[i].reply = Some(serde_json::to_value(reply).unwrap());
+                    return false;
+                }
+                if now - sent < load.timeout {
+                    return true;
+                }
+                if outcomes[i].attempts <= load.retries {
+                    retried.push(i);
+                }
+                false
+            });
+            for i in retried {
+                outcomes[i].attempts += 1;
+                let msg_id = self.send_anywhere(outcomes[i].body.clone());
+                pending.push((i, msg_id, now));
+            }
+        }
+        outcomes
+    }
+
+    /// Body of the `n`th request of `load`, whose weights sum to `total`.
+    fn generate(&mut self, load: &Load, total: u32, n: usize) -> Value {
+        let mut pick = self.rng.below(total as usize) as u32;
+        let request = load
+            .mix
+            .iter()
+            .find(|(_, weight)| {
+                let found = pick < *weight;
+                pick = pick.saturating_sub(*weight);
+                found
+            })
+            .map_or(Request::Read, |(request, _)| *request);
+        let key = match self.rng.next_f64() < load.hot {
+            true => 0,
+            false => self.rng.below(load.keys.max(1)),
+        };
+        let mut value = || self.rng.below(5) + 1;
+        match request {
+            Request::Broadcast => json!({ "type": "broadcast", "message": n }),
+            Request::Add => json!({ "type": "add", "delta": value() }),
+            Request::Read if load.keys == 0 => json!({ "type": "read" }),
+            Request::Read => json!({ "type": "read", "key": key }),
+            Request::Write => json!({ "type": "write", "key": key, "value": value() }),
+            Request::Cas => {
+                json!({ "type": "cas", "key": key, "from": value(), "to": value() })
+            }
+        }
+    }
+
+    fn send_anywhere(&mut self, body: Value) -> usize {
+        let ids = self.node_ids();
+        let node = ids[self.rng.below(ids.len())].clone();
+        self.request(&node, body)
+    }
+}
+
+/// Node `id` of a cluster of `node_ids`, initialized and on `clock`.
+fn boot(id: &str, node_ids: &[String], workload: Workload, clock: &Rc<ManualClock>) -> Node {
+    let init = raw(json!({
+        "src": "c0",
+        "dest": id,
+        "body": { "type": "init", "msg_id": 1, "node_id": id, "node_ids": node_ids },
+    }));
+    let (_, node) = Node::from_init(init).unwrap();
+    let mut node = node.unwrap();
+    node.ctx.clock = Box::new(clock.clone());
+    node.workload = Some(workload);
+    node
+}
+
+fn raw(value: Value) -> RawMessage {
+    serde_json::from_value(value).unwrap()
+}
+
+/// Records every value broadcast through it, and checks that once the
+/// cluster has settled every node reads back exactly those values.
+#[cfg(feature = "broadcast")]
+#[derive(Default)]
+pub struct BroadcastChecker {
+    injected: std::collections::BTreeSet<usize>,
+}
+
+#[cfg(feature = "broadcast")]
+impl BroadcastChecker {
+    pub fn broadcast(&mut self, sim: &mut Sim, node: &str, value: usize) {
+        let msg_id = sim.request(node, json!({ "type": "broadcast", "message": value }));
+        assert!(sim.reply(msg_id).is_some(), "{node} did not ack {value}");
+        self.injected.insert(value);
+    }
+
+    pub fn check(&self, sim: &mut Sim) {
+        for node in sim.node_ids() {
+            let msg_id = sim.request(&node, json!({ "type": "read" }));
+            let reply = serde_json::to_value(sim.reply(msg_id).unwrap()).unwrap();
+            let mut read: Vec<usize> =
+                serde_json::from_value(reply["body"]["messages"].clone()).unwrap();
+            read.sort();
+            let expected: Vec<usize> = self.injected.iter().copied().collect();
+            assert_eq!(read, expected, "{node} read the wrong values");
+        }
+    }
+}
+
+/// Records every `add` sent through it, and checks reads the way
+/// Maelstrom's g-counter checker does: a node never reads a smaller value
+/// than it did before while every delta is positive, and once the cluster
+/// has settled every node reads the sum of the acknowledged adds, plus any
+/// share of the adds whose outcome is unknown.
+#[cfg(feature = "counter")]
+#[derive(Default)]
+pub struct CounterChecker {
+    // Adds sent, by msg_id
+    adds: Vec<(usize, i64)>,
+    last_read: HashMap<String, i64>,
+}
+
+#[cfg(feature = "counter")]
+impl CounterChecker {
+    pub fn add(&mut self, sim: &mut Sim, node: &str, delta: i64) {
+        let msg_id = sim.request(node, json!({ "type": "add", "delta": delta }));
+        self.adds.push((msg_id, delta));
+    }
+
+    /// Reads `node`'s value, checking it did not go down.
+    pub fn read(&mut self, sim: &mut Sim, node: &str) -> i64 {
+        let msg_id = sim.request(node, json!({ "type": "read" }));
+        let reply = serde_json::to_value(sim.reply(msg_id).unwrap()).unwrap();
+        let value = reply["body"]["value"]
+            .as_i64()
+            .unwrap_or_else(|| panic!("{node} did not answer the read: {reply}"));
+        if self.adds.iter().all(|(_, delta)| *delta >= 0) {
+            let last = self.last_read.insert(node.to_string(), value);
+            assert!(
+                last.is_none_or(|last| last <= value),
+                "{node} read {value} after {last:?}"
+            );
+        }
+        value
+    }
+
+    pub fn check(&mut self, sim: &mut Sim) {
+        let (mut low, mut high) = (0, 0);
+        for (msg_id, delta) in &self.adds {
+            let acked = sim.reply(*msg_id).is_some_and(|reply| {
+                serde_json::to_value(reply).unwrap()["body"]["type"] == "add_ok"
+            });
+            match acked {
+                true => (low, high) = (low + delta, high + delta),
+                false if *delta < 0 => low += delta,
+                false => high += delta,
+            }
+        }
+        for node in sim.node_ids() {
+            let value = self.read(sim, &node);
+            assert!(
+                (low..=high).contains(&value),
+                "{node} read {value}, expected {low}..={high}"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn counter_converges_after_partition_heals() {
+        let mut sim = Sim::new(Workload::Counter, 3);
+        let mut checker = CounterChecker::default();
+        sim.partition(&[&["n1"], &["n2", "n3"]]);
+        for delta in 1..=30 {
+            let node = format!("n{}", delta % 3 + 1);
+            checker.add(&mut sim, &node, delta);
+            sim.step();
+            checker.read(&mut sim, &node);
+        }
+        sim.run(Duration::from_secs(1));
+        sim.heal();
+        // The counter gossips its state every round, so it never goes quiet
+        sim.run(Duration::from_secs(1));
+        checker.check(&mut sim);
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn kv_converges_after_partition_heals() {
+        let mut sim = Sim::new(Workload::Kv, 3);
+        sim.request("n1", json!({ "type": "write", "key": 1, "value": 10 }));
+        sim.request("n1", json!({ "type": "write", "key": 2, "value": 20 }));
+        sim.run(Duration::from_secs(1));
+
+        // Each side changes the keys the other deletes
+        sim.partition(&[&["n1"], &["n2", "n3"]]);
+        sim.request("n1", json!({ "type": "delete", "key": 1 }));
+        sim.request("n1", json!({ "type": "write", "key": 2, "value": 21 }));
+        sim.request(
+            "n2",
+            json!({ "type": "cas", "key": 1, "from": 10, "to": 11 }),
+        );
+        sim.request("n3", json!({ "type": "delete", "key": 2 }));
+        sim.run(Duration::from_secs(1));
+        sim.heal();
+        sim.run(Duration::from_secs(1));
+
+        for node in sim.node_ids() {
+            for (key, value) in [(1, 11), (2, 21)] {
+                let msg_id = sim.request(&node, json!({ "type": "read", "key": key }));
+                let reply = serde_json::to_value(sim.reply(msg_id).unwrap()).unwrap();
+                assert_eq!(reply["body"]["value"], value, "{node} read {key}: {reply}");
+            }
+        }
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn view_sync_delivers_within_view() {
+        let mut sim = Sim::new(Workload::Broadcast, 3);
+        for id in sim.node_ids() {
+            sim.node_mut(&id).broadcast.view_sync = true;
+        }
+        let read = |sim: &mut Sim, node: &str| {
+            let msg_id = sim.request(node, json!({ "type": "read" }));
+            let reply = serde_json::to_value(sim.reply(msg_id).unwrap()).unwrap();
+            reply["body"]["messages"].clone()
+        };
+
+        sim.partition(&[&["n1", "n2"]]);
+        sim.request("n1", json!({ "type": "broadcast", "message": 7 }));
+        sim.run(Duration::from_secs(1));
+        // n3 never got 7, so nobody delivers it
+        assert_eq!(read(&mut sim, "n1"), json!([]));
+
+        // A view without n3 lets n1 and n2 deliver it
+        let n1 = sim.node_mut("n1");
+        let topology = [("n1", ["n2"]), ("n2", ["n1"])]
+            .map(|(node, neighbors)| (node.to_string(), neighbors.map(String::from).to_vec()))
+            .into();
+        n1.broadcast.propose(&mut n1.ctx, topology).unwrap();
+        sim.run(Duration::from_secs(1));
+        assert_eq!(read(&mut sim, "n1"), json!([7]));
+        assert_eq!(read(&mut sim, "n2"), json!([7]));
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn view_sync_releases_values_once_a_crashed_member_leaves_the_view() {
+        let mut sim = Sim::new(Workload::Broadcast, 3);
+        for id in sim.node_ids() {
+            let node = sim.node_mut(&id);
+            node.broadcast.view_sync = true;
+            node.broadcast.repair_topology = true;
+        }
+        let read = |sim: &mut Sim, node: &str| {
+            let msg_id = sim.request(node, json!({ "type": "read" }));
+            let reply = serde_json::to_value(sim.reply(msg_id).unwrap()).unwrap();
+            reply["body"]["messages"].clone()
+        };
+
+        sim.crash("n3");
+        sim.request("n1", json!({ "type": "broadcast", "message": 7 }));
+        sim.run(Duration::from_secs(1));
+        assert_eq!(read(&mut sim, "n1"), json!([]));
+
+        // n3 stops answering for long enough to be left out of the view
+        let rounds = crate::workload::broadcast::SUSPECT_AFTER_ROUNDS + 5;
+        sim.run(crate::GOSSIP_INTERVAL * rounds);
+        let n1 = sim.node_mut("n1");
+        assert_eq!(n1.broadcast.members(&n1.ctx), ["n1", "n2"]);
+        assert_eq!(read(&mut sim, "n1"), json!([7]));
+        assert_eq!(read(&mut sim, "n2"), json!([7]));
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn broadcast_converges_after_partition_heals() {
+        let mut sim = Sim::new(Workload::Broadcast, 5);
+        let mut checker = BroadcastChecker::default();
+        sim.partition(&[&["n1", "n2"], &["n3", "n4", "n5"]]);
+        for value in 0..20 {
+            let node = format!("n{}", value % 5 + 1);
+            checker.broadcast(&mut sim, &node, value);
+            sim.step();
+        }
+        sim.run(Duration::from_secs(1));
+        sim.heal();
+        sim.settle(Duration::from_secs(1), Duration::from_secs(10));
+        checker.check(&mut sim);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn broadcast_crosses_slow_links_within_budget() {
+        let mut sim = Sim::new(Workload::Broadcast, 5);
+        sim.seed(7);
+        sim.set_latency(Latency::Uniform(
+            Duration::from_millis(80),
+            Duration::from_millis(120),
+        ));
+        sim.set_link_latency("n3", "n4", Latency::Exponential(Duration::from_millis(100)));
+        // A line, so values take four hops end to end
+        let ids = sim.node_ids();
+        for (i, id) in ids.iter().enumerate() {
+            let neighbors = [i.checked_sub(1), Some(i + 1)]
+                .into_iter()
+                .flatten()
+                .filter_map(|j| ids.get(j))
+                .collect::<Vec<_>>();
+            let topology: BTreeMap<_, _> = [(id, neighbors)].into();
+            sim.request(id, json!({ "type": "topology", "topology": topology }));
+        }
+
+        // n2 can still ack n1, but never gets its gossip
+        sim.cut("n1", "n2");
+        let mut checker = BroadcastChecker::default();
+        checker.broadcast(&mut sim, "n1", 1);
+        sim.run(Duration::from_secs(2));
+        let msg_id = sim.request("n5", json!({ "type": "read" }));
+        let reply = serde_json::to_value(sim.reply(msg_id).unwrap()).unwrap();
+        assert_eq!(reply["body"]["messages"], json!([]));
+
+        sim.heal();
+        let start = sim.clock.now();
+        while sim.clock.now() - start < Duration::from_secs(5) {
+            sim.step();
+            let msg_id = sim.request("n5", json!({ "type": "read" }));
+            let reply = serde_json::to_value(sim.reply(msg_id).unwrap()).unwrap();
+            if reply["body"]["messages"] == json!([1]) {
+                break;
+            }
+        }
+        // Four hops, each waiting up to a gossip round and a slow link
+        let elapsed = sim.clock.now() - start;
+        assert!(elapsed < Duration::from_millis(2000), "took {elapsed:?}");
+        sim.settle(Duration::from_secs(1), Duration::from_secs(10));
+        checker.check(&mut sim);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn generated_broadcasts_reach_every_node() {
+        let mut sim = Sim::new(Workload::Broadcast, 5);
+        let load = Load {
+            rate: 100.0,
+            mix: vec![(Request::Broadcast, 1), (Request::Read, 1)],
+            keys: 0,
+            hot: 0.0,
+            timeout: Duration::from_secs(1),
+            retries: 2,
+        };
+        let outcomes = sim.drive(&load, Duration::from_secs(2));
+        assert!(outcomes.iter().all(Outcome::ok));
+        let checker = BroadcastChecker {
+            injected: outcomes
+                .iter()
+                .filter(|outcome| outcome.body["type"] == "broadcast")
+                .map(|outcome| outcome.body["message"].as_u64().unwrap() as usize)
+                .collect(),
+        };
+        sim.settle(Duration::from_secs(1), Duration::from_secs(10));
+        checker.check(&mut sim);
+    }
+
+    #[cfg(feature = "counter")]
+    #[test]
+    fn generated_adds_all_count() {
+        let mut sim = Sim::new(Workload::Counter, 3);
+        let load = Load {
+            rate: 200.0,
+            mix: vec![(Request::Add, 3), (Request::Read, 1)],
+            keys: 0,
+            hot: 0.0,
+            timeout: Duration::from_secs(1),
+            retries: 0,
+        };
+        let outcomes = sim.drive(&load, Duration::from_secs(1));
+        // Adds are local to the node, so every one is acknowledged
+        let expected: i64 = outcomes
+            .iter()
+            .filter(|outcome| outcome.ok() && outcome.body["type"] == "add")
+            .map(|outcome| outcome.body["delta"].as_i64().unwrap())
+            .sum();
+        sim.run(Duration::from_secs(1));
+        let mut checker = CounterChecker::default();
+        for node in sim.node_ids() {
+            assert_eq!(checker.read(&mut sim, &node), expected);
+        }
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn generated_kv_load_concentrates_on_hot_key() {
+        let mut sim = Sim::new(Workload::Kv, 3);
+        let load = Load {
+            rate: 200.0,
+            mix: vec![(Request::Write, 2), (Request::Read, 2), (Request::Cas, 1)],
+            keys: 10,
+            hot: 0.8,
+            timeout: Duration::from_secs(1),
+            retries: 1,
+        };
+        let outcomes = sim.drive(&load, Duration::from_secs(1));
+        let hot = outcomes
+            .iter()
+            .filter(|outcome| outcome.body["key"] == 0)
+            .count();
+        assert!(hot * 10 > outcomes.len() * 7, "{hot} of {}", outcomes.len());
+        // Reads and cas can fail on what the key holds, writes cannot
+        assert!(outcomes
+            .iter()
+            .all(|outcome| outcome.ok() || outcome.body["type"] != "write"));
+    }
+}
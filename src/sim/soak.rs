@@ -0,0 +1,418 @@
+//! Long randomized runs of the simulator: client load under partitions,
+//! crashes and restarts, with the cluster healed every so often and checked
+//! to have converged, and every node's memory checked against a bound.
+
+#[cfg(feature = "broadcast")]
+use std::collections::BTreeSet;
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+
+use super::{Load, Request, Sim};
+use crate::{clock::Clock, rng::Rng, supervisor::panic_message, Workload};
+
+/// Keys kv requests pick from.
+#[cfg(feature = "kv")]
+const KEYS: usize = 8;
+
+/// How long a healed cluster is given to converge before it is checked.
+const SETTLE: Duration = Duration::from_secs(10);
+
+/// How often each node's memory is added up.
+const MEMORY_EVERY: Duration = Duration::from_secs(1);
+
+/// Settings of a soak run.
+#[derive(Debug, Clone)]
+pub struct Soak {
+    pub workload: Workload,
+    pub nodes: usize,
+    /// Simulated time each seed runs for.
+    pub duration: Duration,
+    /// Simulated time between churn events: a partition, a heal, a crash
+    /// or a restart.
+    pub churn: Duration,
+    /// Simulated time between convergence checks.
+    pub check_every: Duration,
+    /// Client requests per second across the cluster.
+    pub rate: f64,
+    /// Most approximate bytes of state a node may hold.
+    pub memory_limit: usize,
+}
+
+impl Soak {
+    /// Defaults for `workload`, which has to be one whose nodes gossip.
+    pub fn new(workload: Workload) -> Result<Self> {
+        match workload {
+            #[cfg(feature = "broadcast")]
+            Workload::Broadcast => {}
+            #[cfg(feature = "counter")]
+            Workload::Counter => {}
+            #[cfg(feature = "kv")]
+            Workload::Kv => {}
+            #[allow(unreachable_patterns)]
+            _ => {
+                return Err(anyhow!(
+                    "Cannot soak {workload:?}, whose nodes do not gossip"
+                ))
+            }
+        }
+        Ok(Self {
+            workload,
+            nodes: 5,
+            duration: Duration::from_secs(120),
+            churn: Duration::from_secs(2),
+            check_every: Duration::from_secs(30),
+            rate: 50.0,
+            memory_limit: 64 << 20,
+        })
+    }
+}
+
+/// What a run that upheld every invariant did.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub requests: usize,
+    pub acked: usize,
+    pub partitions: usize,
+    pub crashes: usize,
+    pub restarts: usize,
+    pub checks: usize,
+    /// Most approximate bytes any node held.
+    pub peak_memory: usize,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} requests ({} acked), {} partitions, {} crashes, {} restarts, {} checks, \
+             peak memory {} KiB",
+            self.requests,
+            self.acked,
+            self.partitions,
+            self.crashes,
+            self.restarts,
+            self.checks,
+            self.peak_memory >> 10
+        )
+    }
+}
+
+/// The first invariant a run broke.
+#[derive(Debug)]
+pub struct Violation {
+    pub seed: u64,
+    /// Simulated time into the run.
+    pub at: Duration,
+    pub what: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Seed {} at {:.2}s: {}",
+            self.seed,
+            self.at.as_secs_f64(),
+            self.what
+        )
+    }
+}
+
+impl Soak {
+    /// Runs one seed. The same seed churns and loads the cluster the same
+    /// way, so a violation can be replayed. A node panicking counts as one.
+    pub fn run(&self, seed: u64) -> Result<Report, Violation> {
+        let mut at = Duration::ZERO;
+        let run = panic::catch_unwind(AssertUnwindSafe(|| self.run_seed(seed, &mut at)));
+        let what = match run {
+            Ok(Ok(report)) => return Ok(report),
+            Ok(Err(what)) => what,
+            Err(panic) => format!("panicked: {}", panic_message(&*panic)),
+        };
+        Err(Violation { seed, at, what })
+    }
+
+    fn run_seed(&self, seed: u64, at: &mut Duration) -> Result<Report, String> {
+        let mut sim = Sim::new(self.workload, self.nodes);
+        sim.rng = Rng(seed);
+        #[cfg(feature = "broadcast")]
+        if self.workload == Workload::Broadcast {
+            // Nodes gossip over a tree and route around those that go down,
+            // so the overlay's membership changes with the churn
+            let ids = sim.node_ids();
+            let topology = crate::topology::tree(&crate::node_index::NodeIndex::new(&ids));
+            for id in &ids {
+                sim.node_mut(id).broadcast.repair_topology = true;
+                sim.request(id, json!({ "type": "topology", "topology": topology }));
+            }
+        }
+        let load = self.load();
+        let total: u32 = load.mix.iter().map(|(_, weight)| weight).sum();
+        // Every client request, as (msg_id, body)
+        let mut history: Vec<(usize, Value)> = Vec::new();
+        let mut report = Report::default();
+        let start = sim.clock.now();
+        let (mut next_churn, mut next_check, mut next_memory) =
+            (self.churn, self.check_every, Duration::ZERO);
+        let mut due = 0.0;
+        while *at < self.duration {
+            due += load.rate * super::TICK_INTERVAL.as_secs_f64();
+            while due >= 1.0 {
+                due -= 1.0;
+                let body = sim.generate(&load, total, history.len());
+                let msg_id = sim.send_anywhere(body.clone());
+                history.push((msg_id, body));
+            }
+            sim.step();
+            *at = sim.clock.now() - start;
+            if *at >= next_memory {
+                next_memory += MEMORY_EVERY;
+                self.check_memory(&sim, &mut report)?;
+            }
+            if *at >= next_churn {
+                next_churn += self.churn;
+                churn(&mut sim, &mut report);
+            }
+            if *at >= next_check {
+                next_check += self.check_every;
+                self.check(&mut sim, &history)?;
+                report.checks += 1;
+                *at = sim.clock.now() - start;
+            }
+        }
+        self.check(&mut sim, &history)?;
+        report.checks += 1;
+        report.requests = history.len();
+        report.acked = history
+            .iter()
+            .filter(|(msg_id, _)| reply(&sim, *msg_id).is_some_and(|body| body["type"] != "error"))
+            .count();
+        Ok(report)
+    }
+
+    fn load(&self) -> Load {
+        let (mix, keys) = match self.workload {
+            #[cfg(feature = "broadcast")]
+            Workload::Broadcast => (vec![(Request::Broadcast, 3), (Request::Read, 1)], 0),
+            #[cfg(feature = "counter")]
+            Workload::Counter => (vec![(Request::Add, 3), (Request::Read, 1)], 0),
+            #[cfg(feature = "kv")]
+            Workload::Kv => (
+                vec![(Request::Write, 2), (Request::Cas, 1), (Request::Read, 1)],
+                KEYS,
+            ),
+            // `new` turns the others away
+            #[allow(unreachable_patterns)]
+            _ => (Vec::new(), 0),
+        };
+        Load {
+            rate: self.rate,
+            mix,
+            keys,
+            hot: 0.2,
+            timeout: Duration::ZERO,
+            retries: 0,
+        }
+    }
+
+    fn check_memory(&self, sim: &Sim, report: &mut Report) -> Result<(), String> {
+        for (id, node) in &sim.nodes {
+            let usage = node.memory_usage();
+            let total: usize = usage.values().sum();
+            report.peak_memory = report.peak_memory.max(total);
+            if total > self.memory_limit {
+                return Err(format!(
+                    "{id} holds {total} bytes, over the limit of {}: {usage:?}",
+                    self.memory_limit
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Heals the cluster, restarts every node that is down, and checks the
+    /// nodes agree once they had `SETTLE` to converge.
+    // Only broadcast and counter check against the requests made
+    #[cfg_attr(
+        not(any(feature = "broadcast", feature = "counter")),
+        allow(unused_variables)
+    )]
+    fn check(&self, sim: &mut Sim, history: &[(usize, Value)]) -> Result<(), String> {
+        sim.heal();
+        for id in sim.down() {
+            sim.restart(&id);
+        }
+        sim.run(SETTLE);
+        match self.workload {
+            #[cfg(feature = "broadcast")]
+            Workload::Broadcast => check_broadcast(sim, history),
+            #[cfg(feature = "counter")]
+            Workload::Counter => check_counter(sim, history),
+            #[cfg(feature = "kv")]
+            Workload::Kv => check_kv(sim),
+            #[allow(unreachable_patterns)]
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Partitions, heals, crashes or restarts nodes at random, keeping at
+/// least one node up.
+fn churn(sim: &mut Sim, report: &mut Report) {
+    let ids = sim.node_ids();
+    let down = sim.down();
+    let up: Vec<&String> = ids.iter().filter(|id| !down.contains(id)).collect();
+    match sim.rng.below(4) {
+        0 => {
+            let (left, right): (Vec<&str>, Vec<&str>) = ids
+                .iter()
+                .map(String::as_str)
+                .partition(|_| sim.rng.below(2) == 0);
+            sim.partition(&[&left, &right]);
+            report.partitions += 1;
+        }
+        1 => sim.heal(),
+        2 if up.len() > 1 => {
+            let id = up[sim.rng.below(up.len())].clone();
+            sim.crash(&id);
+            report.crashes += 1;
+        }
+        _ if !down.is_empty() => {
+            let id = down[sim.rng.below(down.len())].clone();
+            sim.restart(&id);
+            report.restarts += 1;
+        }
+        _ => {}
+    }
+}
+
+/// Body of the reply to client request `msg_id`, if it came.
+fn reply(sim: &Sim, msg_id: usize) -> Option<Value> {
+    sim.reply(msg_id)
+        .map(|reply| serde_json::to_value(reply).unwrap()["body"].take())
+}
+
+/// Sends `node` a client request and returns the body of its reply.
+fn ask(sim: &mut Sim, node: &str, body: Value) -> Result<Value, String> {
+    let msg_id = sim.request(node, body.clone());
+    reply(sim, msg_id).ok_or_else(|| format!("{node} did not answer {body}"))
+}
+
+/// Every node reads the same values, which include every acknowledged
+/// broadcast and nothing that was not broadcast.
+#[cfg(feature = "broadcast")]
+fn check_broadcast(sim: &mut Sim, history: &[(usize, Value)]) -> Result<(), String> {
+    let sent: BTreeSet<u64> = history
+        .iter()
+        .filter_map(|(_, body)| body["message"].as_u64())
+        .collect();
+    let acked: BTreeSet<u64> = history
+        .iter()
+        .filter(|(msg_id, _)| {
+            reply(sim, *msg_id).is_some_and(|body| body["type"] == "broadcast_ok")
+        })
+        .filter_map(|(_, body)| body["message"].as_u64())
+        .collect();
+    let mut first: Option<(String, BTreeSet<u64>)> = None;
+    for node in sim.node_ids() {
+        let read = ask(sim, &node, json!({ "type": "read" }))?;
+        let read: BTreeSet<u64> = serde_json::from_value(read["messages"].clone())
+            .map_err(|e| format!("{node} answered the read with {read}: {e}"))?;
+        if let Some(lost) = acked.difference(&read).next() {
+            return Err(format!("{node} lost acknowledged value {lost}"));
+        }
+        if let Some(made_up) = read.difference(&sent).next() {
+            return Err(format!("{node} read {made_up}, which was never broadcast"));
+        }
+        match &first {
+            Some((other, values)) if *values != read => {
+                let differ = values.symmetric_difference(&read).next().unwrap();
+                return Err(format!("{node} and {other} disagree on {differ}"));
+            }
+            Some(_) => {}
+            None => first = Some((node, read)),
+        }
+    }
+    Ok(())
+}
+
+/// Every node reads the same value, at least the sum of the acknowledged
+/// adds and at most that plus the adds whose outcome is unknown.
+#[cfg(feature = "counter")]
+fn check_counter(sim: &mut Sim, history: &[(usize, Value)]) -> Result<(), String> {
+    let (mut low, mut high) = (0, 0);
+    for (msg_id, body) in history {
+        let Some(delta) = body["delta"].as_i64() else {
+            continue;
+        };
+        match reply(sim, *msg_id) {
+            Some(reply) if reply["type"] == "add_ok" => (low, high) = (low + delta, high + delta),
+            Some(_) => {}
+            None => high += delta,
+        }
+    }
+    let mut first: Option<(String, i64)> = None;
+    for node in sim.node_ids() {
+        let read = ask(sim, &node, json!({ "type": "read" }))?;
+        let value = read["value"]
+            .as_i64()
+            .ok_or_else(|| format!("{node} answered the read with {read}"))?;
+        if !(low..=high).contains(&value) {
+            return Err(format!("{node} read {value}, expected {low}..={high}"));
+        }
+        match &first {
+            Some((other, seen)) if *seen != value => {
+                return Err(format!("{node} read {value} but {other} read {seen}"));
+            }
+            Some(_) => {}
+            None => first = Some((node, value)),
+        }
+    }
+    Ok(())
+}
+
+/// Every node reads the same value for every key, or none.
+#[cfg(feature = "kv")]
+fn check_kv(sim: &mut Sim) -> Result<(), String> {
+    for key in 0..KEYS {
+        let mut first: Option<(String, Value)> = None;
+        for node in sim.node_ids() {
+            let read = ask(sim, &node, json!({ "type": "read", "key": key }))?;
+            let value = read.get("value").cloned().unwrap_or(Value::Null);
+            match &first {
+                Some((other, seen)) if *seen != value => {
+                    return Err(format!(
+                        "{node} read {value} for key {key} but {other} read {seen}"
+                    ));
+                }
+                Some(_) => {}
+                None => first = Some((node, value)),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "broadcast"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broadcast_survives_churn() {
+        let soak = Soak {
+            duration: Duration::from_secs(20),
+            check_every: Duration::from_secs(10),
+            ..Soak::new(Workload::Broadcast).unwrap()
+        };
+        let report = soak
+            .run(3)
+            .unwrap_or_else(|violation| panic!("{violation}"));
+        assert!(report.crashes > 0 && report.acked > 0, "{report}");
+    }
+}
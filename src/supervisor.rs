@@ -0,0 +1,100 @@
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Pause before restarting a task that panicked, so one that panics right
+/// away does not spin.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// How a background task is doing, as reported by `debug_state`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskHealth {
+    pub running: bool,
+    pub restarts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_panic: Option<String>,
+}
+
+/// Owns the node's background threads (input readers, the tick timer) and
+/// restarts any that panic, so a panic in one does not silently stop the
+/// node from reading or gossiping.
+#[derive(Debug, Clone, Default)]
+pub struct Supervisor {
+    tasks: Arc<Mutex<BTreeMap<&'static str, TaskHealth>>>,
+}
+
+impl Supervisor {
+    /// Runs `task` on its own thread, running it again whenever it panics.
+    /// A task that returns is done and is not restarted.
+    pub fn spawn(&self, name: &'static str, mut task: impl FnMut() + Send + 'static) {
+        self.update(name, |health| health.running = true);
+        let supervisor = self.clone();
+        thread::spawn(move || loop {
+            match panic::catch_unwind(AssertUnwindSafe(&mut task)) {
+                Ok(()) => {
+                    supervisor.update(name, |health| health.running = false);
+                    return;
+                }
+                Err(panic) => {
+                    let message = panic_message(panic.as_ref());
+                    eprintln!("Background task {name} panicked, restarting: {message}");
+                    supervisor.update(name, |health| {
+                        health.restarts += 1;
+                        health.last_panic = Some(message);
+                    });
+                    thread::sleep(RESTART_DELAY);
+                }
+            }
+        });
+    }
+
+    pub fn health(&self) -> BTreeMap<String, TaskHealth> {
+        let tasks = self.tasks.lock().unwrap();
+        tasks
+            .iter()
+            .map(|(name, health)| (name.to_string(), health.clone()))
+            .collect()
+    }
+
+    fn update(&self, name: &'static str, f: impl FnOnce(&mut TaskHealth)) {
+        f(self.tasks.lock().unwrap().entry(name).or_default());
+    }
+}
+
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_panicking_task() {
+        let supervisor = Supervisor::default();
+        let mut runs = 0;
+        supervisor.spawn("flaky", move || {
+            runs += 1;
+            if runs < 3 {
+                panic!("run {runs}");
+            }
+        });
+        while supervisor.health()["flaky"].running {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let health = &supervisor.health()["flaky"];
+        assert_eq!(health.restarts, 2);
+        assert_eq!(health.last_panic.as_deref(), Some("run 2"));
+    }
+}
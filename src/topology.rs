@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::node_index::NodeIndex;
+
+/// Children per node in the trees `tree` builds.
+pub const TREE_FANOUT: usize = 4;
+
+/// Each node's neighbors, as in Maelstrom's `topology` message.
+pub type Topology = HashMap<String, Vec<String>>;
+
+/// Tree over `nodes` in index order, each node linked to its parent and up
+/// to `TREE_FANOUT` children. This is the overlay `--repair-topology`
+/// proposes over the nodes still answering.
+pub fn tree(nodes: &NodeIndex) -> Topology {
+    let nodes = nodes.ids();
+    let mut topology: Topology = nodes
+        .iter()
+        .map(|node| (node.clone(), Vec::new()))
+        .collect();
+    for (i, node) in nodes.iter().enumerate().skip(1) {
+        let parent = &nodes[(i - 1) / TREE_FANOUT];
+        topology.get_mut(node).unwrap().push(parent.clone());
+        topology.get_mut(parent).unwrap().push(node.clone());
+    }
+    topology
+}
+
+/// Every node linked to every other, which is who a node gossips to before
+/// it is given a topology.
+pub fn mesh(nodes: &[String]) -> Topology {
+    nodes
+        .iter()
+        .map(|node| {
+            let others = nodes.iter().filter(|other| *other != node).cloned();
+            (node.clone(), others.collect())
+        })
+        .collect()
+}
+
+/// Shape of a topology, as far as spreading gossip over it goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    /// Neighbors each node gossips to.
+    pub degrees: BTreeMap<String, usize>,
+    /// Fewest hops a value needs to reach every node from the best placed
+    /// one, or `None` if some node cannot reach them all.
+    pub depth: Option<usize>,
+    /// Most hops a value needs between any two nodes, or `None` if some
+    /// node cannot reach them all.
+    pub diameter: Option<usize>,
+}
+
+impl Analysis {
+    pub fn of(topology: &Topology) -> Self {
+        let degrees = topology
+            .iter()
+            .map(|(node, neighbors)| (node.clone(), neighbors.len()))
+            .collect();
+        // Hops for a value to reach every node from each node
+        let reach: Option<Vec<usize>> = topology
+            .keys()
+            .map(|node| eccentricity(topology, node))
+            .collect();
+        Self {
+            degrees,
+            depth: reach.as_ref().and_then(|reach| reach.iter().min().copied()),
+            diameter: reach.as_ref().and_then(|reach| reach.iter().max().copied()),
+        }
+    }
+}
+
+/// Most hops from `start` to any node, following each node's neighbors,
+/// or `None` if some node is out of reach.
+fn eccentricity(topology: &Topology, start: &str) -> Option<usize> {
+    let mut hops = HashMap::from([(start, 0)]);
+    let mut queue = VecDeque::from([start]);
+    while let Some(node) = queue.pop_front() {
+        let next = hops[node] + 1;
+        for neighbor in topology.get(node).into_iter().flatten() {
+            if !hops.contains_key(neighbor.as_str()) {
+                hops.insert(neighbor, next);
+                queue.push_back(neighbor);
+            }
+        }
+    }
+    if topology
+        .keys()
+        .any(|node| !hops.contains_key(node.as_str()))
+    {
+        return None;
+    }
+    hops.values().max().copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_depth_grows_with_fanout_levels() {
+        let nodes: Vec<String> = (0..21).map(|i| format!("n{i:02}")).collect();
+        let analysis = Analysis::of(&tree(&NodeIndex::new(&nodes)));
+        assert_eq!(analysis.depth, Some(2));
+        assert_eq!(analysis.diameter, Some(4));
+        assert_eq!(analysis.degrees["n00"], TREE_FANOUT);
+        assert_eq!(analysis.degrees["n20"], 1);
+
+        let mut split = mesh(&nodes[..2]);
+        split.insert("n02".into(), Vec::new());
+        assert_eq!(Analysis::of(&split).diameter, None);
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use serde_json::{json, Value};
+
+use crate::{Body, Message, Payload, RawMessage};
+
+/// Body field holding the traces a message from another node belongs to.
+pub const TRACE_FIELD: &str = "trace";
+
+/// How many traces are remembered as passed on, and how many requests to
+/// services as awaiting a reply.
+const REMEMBERED: usize = 4096;
+
+/// Follows client requests through the cluster. Each client request starts
+/// a trace, and everything a node sends because of a message carries that
+/// message's traces: its reply, and every message up to and including the
+/// node's next gossip round, which is when what it learned spreads. Nodes
+/// pass the traces on in a body field, and a service's reply takes on the
+/// traces of the request it answers. Every message sent or received with
+/// traces is logged to stderr as a JSON event, so a request's lineage can
+/// be put back together from the nodes' logs.
+#[derive(Debug, Default)]
+pub struct Tracer {
+    started: u64,
+    // Traces passed on already, oldest first, so each spreads only once
+    seen: VecDeque<String>,
+    seen_set: HashSet<String>,
+    // Traces heard of since the last gossip round
+    pending: Vec<String>,
+    // Traces of requests to services, by msg_id, oldest first
+    requests: HashMap<usize, Vec<String>>,
+    request_order: VecDeque<usize>,
+}
+
+impl Tracer {
+    /// Takes the traces off `msg`, which is from another node if `peer`,
+    /// and logs its arrival. A client request starts a trace of its own.
+    pub fn receive(
+        &mut self,
+        node: &str,
+        msg: &mut RawMessage,
+        peer: bool,
+        now: u64,
+    ) -> Vec<String> {
+        let carried = msg.body.payload.remove(TRACE_FIELD);
+        let traces = match (carried, msg.body.in_reply_to) {
+            (Some(traces), _) if peer => serde_json::from_value(traces).unwrap_or_default(),
+            (_, Some(id)) => self.requests.remove(&id).unwrap_or_default(),
+            _ if peer => Vec::new(),
+            _ => {
+                self.started += 1;
+                vec![format!("{node}-{}", self.started)]
+            }
+        };
+        for trace in &traces {
+            if self.seen_set.insert(trace.clone()) {
+                self.seen.push_back(trace.clone());
+                self.pending.push(trace.clone());
+            }
+        }
+        while self.seen.len() > REMEMBERED {
+            if let Some(old) = self.seen.pop_front() {
+                self.seen_set.remove(&old);
+            }
+        }
+        let kind = msg.body.payload.get("type").and_then(Value::as_str);
+        log(node, "receive", &traces, msg, kind, now);
+        traces
+    }
+
+    /// Traces heard of since the last gossip round, which whatever the node
+    /// sends until the end of the next one belongs to.
+    pub fn pending(&self) -> Vec<String> {
+        self.pending.clone()
+    }
+
+    /// Ends a gossip round, after which the traces heard of so far have
+    /// been passed on.
+    pub fn round(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Logs `msg` as sent with `traces`, attaching them if it goes to
+    /// another node, which `peer` says, and remembering them for the reply
+    /// if it is a request to a service.
+    pub fn send(
+        &mut self,
+        node: &str,
+        msg: Message,
+        traces: &[String],
+        peer: bool,
+        now: u64,
+    ) -> Message {
+        if traces.is_empty() {
+            return msg;
+        }
+        let Ok(Value::Object(mut payload)) = serde_json::to_value(&msg.body.payload) else {
+            return msg;
+        };
+        let kind = payload.get("type").and_then(Value::as_str);
+        log(node, "send", traces, &msg, kind, now);
+        if !peer {
+            if let (Some(id), None) = (msg.body.id, msg.body.in_reply_to) {
+                self.requests.insert(id, traces.to_vec());
+                self.request_order.push_back(id);
+                while self.request_order.len() > REMEMBERED {
+                    if let Some(old) = self.request_order.pop_front() {
+                        self.requests.remove(&old);
+                    }
+                }
+            }
+            return msg;
+        }
+        payload.insert(TRACE_FIELD.to_string(), json!(traces));
+        Message {
+            body: Body {
+                payload: Payload::Raw(payload),
+                ..msg.body
+            },
+            ..msg
+        }
+    }
+}
+
+fn log<P>(
+    node: &str,
+    event: &str,
+    traces: &[String],
+    msg: &Message<P>,
+    kind: Option<&str>,
+    now: u64,
+) {
+    if traces.is_empty() {
+        return;
+    }
+    let event = json!({
+        "event": event,
+        "traces": traces,
+        "node": node,
+        "src": msg.src,
+        "dest": msg.dst,
+        "type": kind,
+        "msg_id": msg.body.id,
+        "in_reply_to": msg.body.in_reply_to,
+        "time_ms": now,
+    });
+    eprintln!("{event}");
+}
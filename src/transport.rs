@@ -0,0 +1,106 @@
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::net::{UnixListener, UnixStream},
+};
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use anyhow::{anyhow, Result};
+
+/// A duplex connection carrying one JSON message per line. The incoming
+/// side is read on its own thread while the node writes to the outgoing one.
+pub trait Transport {
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn BufRead + Send>, Box<dyn Write>)>;
+}
+
+/// How Maelstrom talks to a node.
+pub struct Stdio;
+
+impl Transport for Stdio {
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn BufRead + Send>, Box<dyn Write>)> {
+        Ok((
+            Box::new(BufReader::new(io::stdin())),
+            Box::new(io::stdout()),
+        ))
+    }
+}
+
+/// Newline-delimited JSON over a TCP connection, for running nodes outside
+/// Maelstrom.
+pub struct Tcp(TcpStream);
+
+impl Tcp {
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        Ok(Self(TcpStream::connect(addr)?))
+    }
+
+    /// Waits for a single peer to connect to `addr`.
+    pub fn listen(addr: &str) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        Ok(Self(stream))
+    }
+}
+
+impl Transport for Tcp {
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn BufRead + Send>, Box<dyn Write>)> {
+        let reader = self.0.try_clone()?;
+        self.0.set_nodelay(true)?;
+        Ok((
+            Box::new(BufReader::new(reader)),
+            Box::new(BufWriter::new(self.0)),
+        ))
+    }
+}
+
+/// Newline-delimited JSON over a Unix domain socket, for wiring several
+/// local node processes together.
+#[cfg(unix)]
+pub struct Uds(UnixStream);
+
+#[cfg(unix)]
+impl Uds {
+    pub fn connect(path: &str) -> io::Result<Self> {
+        Ok(Self(UnixStream::connect(path)?))
+    }
+
+    /// Waits for a single peer to connect to `path`, replacing any socket
+    /// file left behind by a previous run.
+    pub fn listen(path: &str) -> io::Result<Self> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let (stream, _) = UnixListener::bind(path)?.accept()?;
+        Ok(Self(stream))
+    }
+}
+
+#[cfg(unix)]
+impl Transport for Uds {
+    fn split(self: Box<Self>) -> io::Result<(Box<dyn BufRead + Send>, Box<dyn Write>)> {
+        let reader = self.0.try_clone()?;
+        Ok((
+            Box::new(BufReader::new(reader)),
+            Box::new(BufWriter::new(self.0)),
+        ))
+    }
+}
+
+/// Opens the transport named by a `--transport` value: `stdio`,
+/// `tcp://HOST:PORT` or `unix://PATH` to connect, or `tcp-listen://HOST:PORT`
+/// or `unix-listen://PATH` to accept.
+pub fn open(spec: &str) -> Result<Box<dyn Transport>> {
+    match spec.split_once("://") {
+        None if spec == "stdio" => Ok(Box::new(Stdio)),
+        Some(("tcp", addr)) => Ok(Box::new(Tcp::connect(addr)?)),
+        Some(("tcp-listen", addr)) => Ok(Box::new(Tcp::listen(addr)?)),
+        #[cfg(unix)]
+        Some(("unix", path)) => Ok(Box::new(Uds::connect(path)?)),
+        #[cfg(unix)]
+        Some(("unix-listen", path)) => Ok(Box::new(Uds::listen(path)?)),
+        _ => Err(anyhow!("Unknown transport {spec}")),
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashSet;
+
+#[cfg(feature = "broadcast")]
+use crate::workload::broadcast;
+use crate::{workload::Handler, Error, Message};
+
+/// Checks an `init` beyond what serde can express. Returns the
+/// `malformed-request` error naming the offending field.
+pub fn init(node_id: &str, node_ids: &[String]) -> Result<(), Error> {
+    if node_ids.is_empty() {
+        return Err(Error::malformed("node_ids", "must not be empty"));
+    }
+    if !node_ids.iter().any(|id| id == node_id) {
+        return Err(Error::malformed(
+            "node_id",
+            format!("{node_id} is not one of node_ids"),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `msg` comes from someone the workload `H` talks to: another
+/// of the cluster's `members`, a client (`c1`, `c2`...) or one of the
+/// workload's services. Messages between nodes are only accepted from
+/// members.
+pub fn source<H: Handler>(
+    msg: &Message<H::Payload>,
+    members: &HashSet<String>,
+) -> Result<(), Error> {
+    let src = msg.src.as_str();
+    if members.contains(src) {
+        return Ok(());
+    }
+    if H::internal(&msg.body.payload) {
+        return Err(Error::NodeNotFound(format!(
+            "{src} is not a node of this cluster"
+        )));
+    }
+    let client = src
+        .strip_prefix('c')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    if client || H::SERVICES.contains(&src) {
+        return Ok(());
+    }
+    Err(Error::NodeNotFound(format!("Unknown sender {src}")))
+}
+
+/// Checks a broadcast payload against the cluster `members`.
+#[cfg(feature = "broadcast")]
+pub fn broadcast(payload: &broadcast::Payload, members: &HashSet<String>) -> Result<(), Error> {
+    match payload {
+        broadcast::Payload::Topology { topology }
+        | broadcast::Payload::TopologyUpdate { topology, .. } => {
+            let unknown = topology
+                .iter()
+                .flat_map(|(node, neighbors)| std::iter::once(node).chain(neighbors))
+                .find(|node| !members.contains(*node));
+            match unknown {
+                Some(node) => Err(Error::malformed("topology", format!("unknown node {node}"))),
+                None => Ok(()),
+            }
+        }
+        broadcast::Payload::Read { limit: Some(0), .. } => {
+            Err(Error::malformed("limit", "must be positive"))
+        }
+        _ => Ok(()),
+    }
+}
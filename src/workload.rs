@@ -0,0 +1,178 @@
+#[cfg(feature = "broadcast")]
+pub mod broadcast;
+#[cfg(feature = "counter")]
+pub mod counter;
+#[cfg(feature = "echo")]
+pub mod echo;
+#[cfg(feature = "kv")]
+pub mod kv;
+#[cfg(feature = "unique-ids")]
+pub mod unique_ids;
+
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+use crate::{clock::Clock, journal::Journal};
+use crate::{Error, Message, Payload};
+
+/// The Maelstrom workload a node serves, picked with `--workload` or
+/// detected from the first client request that identifies one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Workload {
+    #[cfg(feature = "echo")]
+    Echo,
+    #[cfg(feature = "unique-ids")]
+    UniqueIds,
+    #[cfg(feature = "broadcast")]
+    Broadcast,
+    #[cfg(feature = "counter")]
+    #[serde(rename = "g-counter")]
+    Counter,
+    #[cfg(feature = "kv")]
+    Kv,
+}
+
+impl Workload {
+    /// Every workload compiled in.
+    pub const ALL: &'static [Workload] = &[
+        #[cfg(feature = "echo")]
+        Workload::Echo,
+        #[cfg(feature = "unique-ids")]
+        Workload::UniqueIds,
+        #[cfg(feature = "broadcast")]
+        Workload::Broadcast,
+        #[cfg(feature = "counter")]
+        Workload::Counter,
+        #[cfg(feature = "kv")]
+        Workload::Kv,
+    ];
+
+    /// The `--workload` value naming it.
+    pub fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "echo")]
+            Workload::Echo => "echo",
+            #[cfg(feature = "unique-ids")]
+            Workload::UniqueIds => "unique-ids",
+            #[cfg(feature = "broadcast")]
+            Workload::Broadcast => "broadcast",
+            #[cfg(feature = "counter")]
+            Workload::Counter => "g-counter",
+            #[cfg(feature = "kv")]
+            Workload::Kv => "kv",
+        }
+    }
+
+    /// Parses a `--workload` value, where `auto` means detect it.
+    pub fn parse(name: &str) -> Result<Option<Self>> {
+        match name {
+            "auto" => Ok(None),
+            #[cfg(feature = "echo")]
+            "echo" => Ok(Some(Workload::Echo)),
+            #[cfg(feature = "unique-ids")]
+            "unique-ids" => Ok(Some(Workload::UniqueIds)),
+            #[cfg(feature = "broadcast")]
+            "broadcast" => Ok(Some(Workload::Broadcast)),
+            #[cfg(feature = "counter")]
+            "g-counter" => Ok(Some(Workload::Counter)),
+            #[cfg(feature = "kv")]
+            "kv" => Ok(Some(Workload::Kv)),
+            _ => Err(anyhow!("Unknown or disabled workload {name}")),
+        }
+    }
+
+    /// The workload a client request of type `kind` belongs to, if only
+    /// one has it.
+    pub(crate) fn of(kind: &str) -> Option<Self> {
+        match kind {
+            #[cfg(feature = "echo")]
+            "echo" => Some(Workload::Echo),
+            #[cfg(feature = "unique-ids")]
+            "generate" => Some(Workload::UniqueIds),
+            #[cfg(feature = "broadcast")]
+            "broadcast" | "topology" => Some(Workload::Broadcast),
+            #[cfg(feature = "counter")]
+            "add" => Some(Workload::Counter),
+            #[cfg(feature = "kv")]
+            "write" | "cas" | "delete" | "scan" | "watch" | "unwatch" => Some(Workload::Kv),
+            _ => None,
+        }
+    }
+}
+
+/// Node state every workload can use: who the node is, and for the
+/// gossiping workloads the msg_id counter, clock and journal.
+#[derive(Default)]
+pub(crate) struct Context {
+    pub id: String,
+    // Only the gossiping workloads need to know the membership
+    #[cfg_attr(
+        not(any(feature = "broadcast", feature = "counter", feature = "kv")),
+        allow(dead_code)
+    )]
+    pub node_ids: HashSet<String>,
+    // Last msg_id this node sent
+    pub next_msg_id: usize,
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub clock: Box<dyn Clock>,
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv"))]
+    pub journal: Journal,
+}
+
+impl Context {
+    /// msg_id for a new message from this node.
+    pub fn next_msg_id(&mut self) -> usize {
+        self.next_msg_id += 1;
+        self.next_msg_id
+    }
+
+    /// Gives `msg` a msg_id of its own if it has none yet. Replies only
+    /// point at the request with `in_reply_to`; their msg_id is as fresh as
+    /// any other message's, so it never collides with one the node uses
+    /// for a request.
+    pub fn stamp(&mut self, mut msg: Message) -> Message {
+        if msg.body.id.is_none() {
+            msg.body.id = Some(self.next_msg_id());
+        }
+        msg
+    }
+}
+
+/// A workload's share of a node. Each workload parses incoming messages
+/// into its own payload enum, so two workloads can both have a `read`
+/// without their payloads colliding.
+#[cfg_attr(
+    not(any(
+        feature = "echo",
+        feature = "unique-ids",
+        feature = "broadcast",
+        feature = "counter",
+        feature = "kv"
+    )),
+    allow(dead_code)
+)]
+pub(crate) trait Handler {
+    type Payload: Serialize + DeserializeOwned + Into<Payload>;
+
+    /// Maelstrom services the workload talks to, whose messages it
+    /// accepts along with those from clients and other nodes.
+    const SERVICES: &'static [&'static str] = &[];
+
+    /// Whether `payload` is part of the protocol between nodes, and so is
+    /// only accepted from other nodes.
+    fn internal(_payload: &Self::Payload) -> bool {
+        false
+    }
+
+    /// Handles a request or reply, returning what to send back, if
+    /// anything. A request that fails gets the error as its reply.
+    fn handle(
+        &mut self,
+        ctx: &mut Context,
+        msg: Message<Self::Payload>,
+    ) -> Result<Option<Message>, Error>;
+}
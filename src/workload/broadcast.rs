@@ -0,0 +1,961 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{Context, Handler};
+use crate::{
+    ack_stream::AckStream,
+    codec,
+    journal::{Change, Snapshot},
+    node_index::NodeIndex,
+    overload::SHED_FACTOR,
+    redundancy::Redundancy,
+    seen_set::SeenSet,
+    topology::tree,
+    unsupported, validate, Body, Error, Message,
+};
+
+/// Most values sent in one gossip message, keeping lines a manageable size
+/// when a peer is far behind.
+const GOSSIP_CHUNK: usize = 4096;
+/// Version of the node-to-node gossip protocol, advertised on every gossip
+/// message and reply. Version 2 added `packed` gossip, and version 3
+/// snapshot transfers.
+const PROTOCOL_VERSION: u32 = 3;
+/// Most runs of values sent in one snapshot chunk.
+const SNAPSHOT_CHUNK: usize = 1024;
+/// Gossip with fewer values than this is sent as a plain list, which is
+/// about as small and easier to read in logs.
+const PACK_MIN_VALUES: usize = 64;
+/// Gossip rounds a neighbor can leave our gossip unacknowledged before
+/// `--repair-topology` routes around it.
+pub(crate) const SUSPECT_AFTER_ROUNDS: u32 = 25;
+
+/// Offsets from `start` up to `end` of the part of the stream a gossip
+/// chunk covers.
+type Span = (usize, usize);
+
+/// Called with every broadcast value the first time the node learns of it,
+/// whether from a client or from gossip, so embedders can use broadcast as
+/// a pub/sub channel.
+#[derive(Clone)]
+pub struct OnDeliver(pub Arc<dyn Fn(usize) + Send + Sync>);
+
+impl fmt::Debug for OnDeliver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnDeliver")
+    }
+}
+
+/// A cluster-wide topology proposed by a node, versioned so every node
+/// settles on the same one. A higher epoch wins and equal epochs are
+/// ordered by the proposing node, so concurrent proposals converge too.
+/// The `topology` message from Maelstrom is epoch 0.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Overlay {
+    pub epoch: u64,
+    pub origin: String,
+    pub topology: HashMap<String, Vec<String>>,
+}
+
+impl Overlay {
+    fn version(&self) -> (u64, &str) {
+        (self.epoch, &self.origin)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Broadcast {
+        message: usize,
+    },
+    BroadcastOk {},
+
+    Read {
+        /// Most values to return, for paging through large sets.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+        /// Continuation token from a previous page's `next`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<usize>,
+    },
+    ReadOk {
+        messages: Vec<usize>,
+        /// Set when a `limit` cut the values short; pass it back as `from`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<usize>,
+    },
+
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk {},
+
+    /// A node-proposed topology, sent to every node until acknowledged.
+    TopologyUpdate {
+        epoch: u64,
+        origin: String,
+        topology: HashMap<String, Vec<String>>,
+    },
+    /// Names the overlay the replying node now has, which acknowledges the
+    /// update if it is the one sent.
+    TopologyUpdateOk {
+        epoch: u64,
+        origin: String,
+    },
+
+    Gossip {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        messages: Vec<usize>,
+        /// Values packed by `codec::pack`, sent instead of `messages` to
+        /// peers speaking protocol 2 or later.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        packed: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol: Option<u32>,
+    },
+    GossipOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol: Option<u32>,
+    },
+    /// Part of every value the sender has, as inclusive runs, sent to a
+    /// peer too far behind for gossip. `next` is where the following chunk
+    /// starts, if there is one.
+    SnapshotChunk {
+        runs: Vec<(usize, usize)>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<usize>,
+    },
+    /// Acknowledges a snapshot chunk, echoing its `next`.
+    SnapshotChunkOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<usize>,
+    },
+    /// Reply to gossip that held nothing new, listing every value the
+    /// replying node has as inclusive `[start, end]` runs.
+    GossipHave {
+        have: Vec<(usize, usize)>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol: Option<u32>,
+    },
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Broadcast {
+    messages: SeenSet,
+    neighbors: Vec<String>,
+    // Values each peer is known to have, either because it sent them to us
+    // or because it acknowledged our gossip
+    known: HashMap<String, SeenSet>,
+    // Values in the order they were first seen, with how far along it each
+    // peer acknowledged our gossip
+    stream: AckStream<usize>,
+    // Gossip sent since the last tick, by msg_id, awaiting gossip_ok, with
+    // the part of the stream it covers
+    pending_gossip: HashMap<usize, (String, SeenSet, Option<Span>)>,
+    // Peers being sent a snapshot, with the value the next chunk starts at
+    snapshots: HashMap<String, usize>,
+    redundancy: Redundancy,
+    // Highest protocol version each peer advertised
+    peer_protocol: HashMap<String, u32>,
+    // Newest node-proposed topology, and the nodes that acknowledged it
+    overlay: Overlay,
+    overlay_acked: HashSet<String>,
+    // Consecutive rounds each neighbor left our gossip unacknowledged
+    missed_rounds: HashMap<String, u32>,
+    // Nodes left out of the overlay we proposed, until we hear from them
+    suspects: HashSet<String>,
+    // A suspect was heard from, so the overlay should take it back
+    suspect_returned: bool,
+    // Set with `--compress-gossip`
+    pub compress_gossip: bool,
+    // Set with `--repair-topology`
+    pub repair_topology: bool,
+    // Set with `--view-sync`
+    pub view_sync: bool,
+    // Most values per gossip message, set with `reconfigure`; `GOSSIP_CHUNK`
+    // if not
+    pub gossip_chunk: Option<usize>,
+    // Set by embedders, once the journal is restored
+    pub on_deliver: Option<OnDeliver>,
+    // Set with `--defer-broadcast-ok-ms`: how long a `broadcast_ok` may be
+    // held back for the next gossip round, so broadcasts arriving together
+    // are acknowledged together once it carried them
+    pub defer_ok: Option<Duration>,
+    // Replies held back, and when the first of them is due at the latest
+    deferred: Vec<Message>,
+    deferred_due: Option<Instant>,
+    // Set while the node is overloaded, batching gossip more
+    pub shedding: bool,
+}
+
+impl Broadcast {
+    /// Approximate bytes held by the values and what each peer has.
+    pub fn memory(&self) -> usize {
+        let known: usize = self.known.values().map(SeenSet::memory).sum();
+        let pending: usize = self
+            .pending_gossip
+            .values()
+            .map(|(_, values, _)| size_of::<(usize, String, Option<Span>)>() + values.memory())
+            .sum();
+        self.messages.memory() + known + pending + self.stream.memory()
+    }
+
+    pub fn neighbors(&self) -> &[String] {
+        &self.neighbors
+    }
+
+    /// Nodes the overlay leaves out, in order. Suspects are not
+    /// journaled, so after a restart this is how they are known.
+    pub fn left_out(&self, ctx: &Context) -> Vec<String> {
+        if self.overlay.epoch == 0 {
+            return Vec::new();
+        }
+        let mut left_out: Vec<String> = ctx
+            .node_ids
+            .iter()
+            .filter(|id| **id != ctx.id && !self.overlay.topology.contains_key(*id))
+            .cloned()
+            .collect();
+        left_out.sort();
+        left_out
+    }
+
+    /// Starts a gossip round to `peers`. Gossip that was not acknowledged
+    /// since the last round is simply sent again from each peer's
+    /// watermark, so lost messages and late acks need no special handling,
+    /// and values all of `peers` acknowledged leave the stream.
+    pub fn start_round(&mut self, peers: &[String]) {
+        let unacked: HashSet<String> = self
+            .pending_gossip
+            .drain()
+            .map(|(_, (peer, _, _))| peer)
+            .collect();
+        self.stream.release(peers);
+        for peer in unacked {
+            *self.missed_rounds.entry(peer).or_default() += 1;
+        }
+    }
+
+    /// The gossip `in_reply_to` answers, if it went to `src` and is still
+    /// awaiting an ack. A late or duplicate ack finds nothing: the gossip
+    /// it acknowledged was sent again from the watermark, and counting it
+    /// twice does no harm. An ack from a peer the gossip did not go to is
+    /// left for the real one.
+    fn take_pending(
+        &mut self,
+        src: &str,
+        in_reply_to: Option<usize>,
+    ) -> Option<(String, SeenSet, Option<Span>)> {
+        let id = in_reply_to?;
+        if self.pending_gossip.get(&id)?.0 != src {
+            eprintln!("Ignoring ack from {src} for gossip {id} sent elsewhere");
+            return None;
+        }
+        self.pending_gossip.remove(&id)
+    }
+
+    fn heard_from(&mut self, ctx: &Context, peer: &str) {
+        self.missed_rounds.remove(peer);
+        // The origin of an overlay takes back whoever it left out, even if
+        // it restarted since and no longer knows them as suspects
+        let left_out = self.overlay.epoch > 0
+            && self.overlay.origin == ctx.id
+            && !self.overlay.topology.contains_key(peer);
+        if self.suspects.remove(peer) || left_out {
+            self.suspect_returned = true;
+        }
+    }
+
+    /// With `--repair-topology`, proposes a tree over the nodes that are
+    /// still answering once a neighbor stops acknowledging gossip, and
+    /// again once a node left out of it is heard from. With `--view-sync`
+    /// every member of the view is watched, as any one holds back reads.
+    pub fn repair(&mut self, ctx: &mut Context) -> Result<()> {
+        if !self.repair_topology {
+            return Ok(());
+        }
+        let mut watched = self.neighbors.clone();
+        if self.view_sync {
+            watched = self.members(ctx);
+            watched.retain(|id| *id != ctx.id);
+        }
+        let failed: Vec<String> = watched
+            .iter()
+            .filter(|peer| {
+                self.missed_rounds.get(*peer).copied().unwrap_or(0) >= SUSPECT_AFTER_ROUNDS
+                    && !self.suspects.contains(*peer)
+            })
+            .cloned()
+            .collect();
+        if failed.is_empty() && !self.suspect_returned {
+            return Ok(());
+        }
+        for peer in failed {
+            eprintln!("Routing around unresponsive neighbor {peer}");
+            self.suspects.insert(peer);
+        }
+        self.suspect_returned = false;
+
+        let mut live = NodeIndex::new(&ctx.node_ids);
+        for suspect in &self.suspects {
+            live.remove(suspect);
+        }
+        self.propose(ctx, tree(&live))
+    }
+
+    /// Proposes `topology` for the whole cluster under the next epoch.
+    pub fn propose(
+        &mut self,
+        ctx: &mut Context,
+        topology: HashMap<String, Vec<String>>,
+    ) -> Result<()> {
+        let overlay = Overlay {
+            epoch: self.overlay.epoch + 1,
+            origin: ctx.id.clone(),
+            topology,
+        };
+        self.commit(ctx, Change::Overlay { overlay })?;
+        Ok(())
+    }
+
+    /// Sends the current overlay to every node that has not acknowledged
+    /// it yet, so it reaches nodes the old topology no longer connects.
+    pub fn announce(&mut self, ctx: &mut Context) -> Vec<Message> {
+        if self.overlay.epoch == 0 {
+            return Vec::new();
+        }
+        let mut peers: Vec<String> = ctx
+            .node_ids
+            .iter()
+            .filter(|id| **id != ctx.id && !self.overlay_acked.contains(*id))
+            .cloned()
+            .collect();
+        peers.sort();
+        peers
+            .into_iter()
+            .map(|peer| Message {
+                src: ctx.id.clone(),
+                dst: peer,
+                body: Body {
+                    id: Some(ctx.next_msg_id()),
+                    in_reply_to: None,
+                    payload: Payload::TopologyUpdate {
+                        epoch: self.overlay.epoch,
+                        origin: self.overlay.origin.clone(),
+                        topology: self.overlay.topology.clone(),
+                    }
+                    .into(),
+                },
+            })
+            .collect()
+    }
+
+    /// Sends `peer` the values it is not known to have yet, split into
+    /// chunks of at most `GOSSIP_CHUNK` values, or as many as reconfigured,
+    /// that are acknowledged separately.
+    pub fn gossip(&mut self, ctx: &mut Context, peer: String) -> Vec<Message> {
+        let chunks = self.chunks(&peer);
+
+        // Peers that never advertised a version may predate `packed`
+        let pack = self.compress_gossip && self.peer_protocol.get(&peer).copied().unwrap_or(1) >= 2;
+
+        let mut out = Vec::new();
+        for (chunk, offsets) in chunks {
+            let pack_chunk = pack && chunk.len() >= PACK_MIN_VALUES;
+            let chunk: SeenSet = chunk.into_iter().collect();
+            let (messages, packed) = match pack_chunk {
+                true => (Vec::new(), Some(codec::pack(&chunk))),
+                false => (chunk.iter().collect(), None),
+            };
+            let id = ctx.next_msg_id();
+            out.push(Message {
+                src: ctx.id.clone(),
+                dst: peer.clone(),
+                body: Body {
+                    id: Some(id),
+                    in_reply_to: None,
+                    payload: Payload::Gossip {
+                        messages,
+                        packed,
+                        protocol: Some(PROTOCOL_VERSION),
+                    }
+                    .into(),
+                },
+            });
+            self.pending_gossip
+                .insert(id, (peer.clone(), chunk, offsets));
+        }
+        out.extend(self.snapshot_chunk(ctx, &peer));
+        out
+    }
+
+    /// The next chunk of the snapshot being sent to `peer`, if any. A lost
+    /// chunk is sent again on the next round, since the transfer only moves
+    /// on once a chunk is acknowledged.
+    fn snapshot_chunk(&mut self, ctx: &mut Context, peer: &str) -> Option<Message> {
+        let from = *self.snapshots.get(peer)?;
+        let mut runs: Vec<(usize, usize)> = self
+            .messages
+            .runs()
+            .iter()
+            .filter(|(_, end)| *end >= from)
+            .take(SNAPSHOT_CHUNK + 1)
+            .map(|&(start, end)| (start.max(from), end))
+            .collect();
+        let next = runs.get(SNAPSHOT_CHUNK).map(|(start, _)| *start);
+        runs.truncate(SNAPSHOT_CHUNK);
+
+        let id = ctx.next_msg_id();
+        self.pending_gossip.insert(
+            id,
+            (peer.to_string(), SeenSet::from_runs(runs.clone()), None),
+        );
+        Some(Message {
+            src: ctx.id.clone(),
+            dst: peer.to_string(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload: Payload::SnapshotChunk { runs, next }.into(),
+            },
+        })
+    }
+
+    /// The values to gossip to `peer` in chunks, each with the part of the
+    /// stream it covers. Values past the peer's watermark that it already
+    /// sent us are skipped. A peer that fell behind the released part of
+    /// the stream is sent a snapshot of every value, while gossip carries
+    /// on from the end of the stream. Peers too old for snapshots get every
+    /// value they are not known to have instead, and rejoin the stream once
+    /// they have them all.
+    fn chunks(&mut self, peer: &str) -> Vec<(Vec<usize>, Option<Span>)> {
+        let mut size = self.gossip_chunk.unwrap_or(GOSSIP_CHUNK);
+        if self.shedding {
+            size *= SHED_FACTOR as usize;
+        }
+        if self.stream.unacked(peer).is_none()
+            && self.peer_protocol.get(peer).copied().unwrap_or(1) >= 3
+        {
+            eprintln!("Sending a snapshot to {peer}, which fell behind gossip");
+            self.snapshots.insert(peer.to_string(), 0);
+            self.stream.catch_up(peer);
+        }
+        let known = self.known.get(peer);
+        let Some(unacked) = self.stream.unacked(peer) else {
+            let delta = match known {
+                Some(known) => self.messages.difference(known),
+                None => self.messages.clone(),
+            };
+            if delta.runs().is_empty() {
+                self.stream.catch_up(peer);
+            }
+            let values: Vec<usize> = delta.iter().collect();
+            return values
+                .chunks(size)
+                .map(|chunk| (chunk.to_vec(), None))
+                .collect();
+        };
+
+        let mut chunks = Vec::new();
+        let mut chunk = Vec::new();
+        let mut start = self.stream.watermark(peer);
+        for (offset, value) in unacked {
+            if !known.is_some_and(|known| known.contains(*value)) {
+                chunk.push(*value);
+            }
+            if chunk.len() == size {
+                chunks.push((std::mem::take(&mut chunk), Some((start, offset + 1))));
+                start = offset + 1;
+            }
+        }
+        let end = self.stream.end();
+        match chunk.is_empty() {
+            false => chunks.push((chunk, Some((start, end)))),
+            // The peer already has the rest
+            true if start < end => {
+                self.stream.ack(peer, start, end);
+            }
+            true => {}
+        }
+        chunks
+    }
+
+    /// Whether `reply` is a `broadcast_ok` to hold back.
+    pub fn defers(&self, reply: &Message) -> bool {
+        self.defer_ok.is_some()
+            && matches!(
+                reply.body.payload,
+                crate::Payload::Broadcast(Payload::BroadcastOk {})
+            )
+    }
+
+    /// Holds `reply` back until the next gossip round, or `defer_ok` from
+    /// now if the round comes later.
+    pub fn hold(&mut self, reply: Message, now: Instant) {
+        self.deferred_due
+            .get_or_insert(now + self.defer_ok.unwrap_or_default());
+        self.deferred.push(reply);
+    }
+
+    /// The `broadcast_ok`s held back.
+    pub fn held(&self) -> &[Message] {
+        &self.deferred
+    }
+
+    /// When the `broadcast_ok`s held back are due at the latest, if any are.
+    pub fn flush_at(&self) -> Option<Instant> {
+        self.deferred_due
+    }
+
+    /// The `broadcast_ok`s held back, once a gossip `round` carried their
+    /// values or the first has waited `defer_ok`.
+    pub fn due_replies(&mut self, now: Instant, round: bool) -> Vec<Message> {
+        match self.deferred_due {
+            Some(due) if round || now >= due => {
+                self.deferred_due = None;
+                std::mem::take(&mut self.deferred)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Redundancy summary to log, at most every few seconds.
+    pub fn report(&mut self, now: Instant) -> Option<String> {
+        self.redundancy.report(now)
+    }
+
+    /// Folds a journaled broadcast change in, returning whether it changed
+    /// anything.
+    pub fn apply(&mut self, node_id: &str, change: &Change) -> bool {
+        match change {
+            // A node-proposed overlay takes precedence over Maelstrom's
+            Change::Topology { .. } if self.overlay.epoch > 0 => return false,
+            Change::Topology { neighbors } => self.neighbors = neighbors.clone(),
+            Change::Overlay { overlay } => {
+                if overlay.version() <= self.overlay.version() {
+                    return false;
+                }
+                self.neighbors = overlay.topology.get(node_id).cloned().unwrap_or_default();
+                self.overlay = overlay.clone();
+                self.overlay_acked.clear();
+            }
+            Change::Seen { runs } => {
+                let new = SeenSet::from_runs(runs.clone()).difference(&self.messages);
+                if new.runs().is_empty() {
+                    return false;
+                }
+                self.messages.union_with(&new);
+                for value in new.iter() {
+                    self.stream.push(value);
+                    if let Some(OnDeliver(deliver)) = &self.on_deliver {
+                        deliver(value);
+                    }
+                }
+            }
+            Change::Acked { peer, watermark } => return self.stream.ack(peer, 0, *watermark),
+            _ => return false,
+        }
+        true
+    }
+
+    fn commit(&mut self, ctx: &mut Context, change: Change) -> Result<bool> {
+        let changed = self.apply(&ctx.id, &change);
+        if changed {
+            ctx.journal.append(change)?;
+        }
+        Ok(changed)
+    }
+
+    /// Records that `peer` acknowledged the stream from `start` up to
+    /// `end`, journaling its watermark if that moved it.
+    fn ack(&mut self, ctx: &mut Context, peer: &str, start: usize, end: usize) -> Result<()> {
+        if self.stream.ack(peer, start, end) {
+            ctx.journal.append(Change::Acked {
+                peer: peer.to_string(),
+                watermark: self.stream.watermark(peer),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Values still in the stream, in the order first seen, as runs.
+    pub fn stream_runs(&self) -> Vec<(usize, usize)> {
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for &value in self.stream.kept() {
+            match runs.last_mut() {
+                Some((_, end)) if *end + 1 == value => *end = value,
+                _ => runs.push((value, value)),
+            }
+        }
+        runs
+    }
+
+    /// Each peer's ack watermark in the stream.
+    pub fn acked(&self) -> BTreeMap<String, usize> {
+        self.stream
+            .watermarks()
+            .iter()
+            .map(|(peer, watermark)| (peer.clone(), *watermark))
+            .collect()
+    }
+
+    /// Rebuilds the stream with every value at the offset it had: those
+    /// released first, as every peer had them, then the rest in the order
+    /// first seen. Peers keep their watermarks, so values acknowledged to
+    /// clients but not yet to every peer are gossiped again.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.neighbors = snapshot.neighbors.clone();
+        self.messages = SeenSet::from_runs(snapshot.messages.clone());
+        let kept: Vec<usize> = snapshot
+            .stream
+            .iter()
+            .flat_map(|&(start, end)| start..=end)
+            .collect();
+        let released = self.messages.difference(&kept.iter().copied().collect());
+        self.stream = AckStream::default();
+        for value in released.iter().chain(kept) {
+            self.stream.push(value);
+        }
+        for (peer, watermark) in &snapshot.acked {
+            self.stream.ack(peer, 0, *watermark);
+        }
+        self.overlay = snapshot.overlay.clone();
+    }
+
+    pub fn overlay(&self) -> &Overlay {
+        &self.overlay
+    }
+
+    /// Members of the current view: the nodes of the newest proposed
+    /// overlay, or the whole cluster until one is proposed.
+    pub fn members(&self, ctx: &Context) -> Vec<String> {
+        let mut members: Vec<String> = match self.overlay.epoch {
+            0 => ctx.node_ids.iter().cloned().collect(),
+            _ => self.overlay.topology.keys().cloned().collect(),
+        };
+        members.sort();
+        members
+    }
+
+    /// Values reads return. With `--view-sync` that is only the values
+    /// every other member of the view is known to have, so a value is
+    /// readable on one member only once all of them have it. When a view
+    /// change drops a member, the values it was holding back become
+    /// readable once the remaining members have exchanged them.
+    fn delivered(&self, ctx: &Context) -> SeenSet {
+        let mut delivered = self.messages.clone();
+        if !self.view_sync {
+            return delivered;
+        }
+        for member in self.members(ctx) {
+            if member == ctx.id {
+                continue;
+            }
+            let missing = match self.known.get(&member) {
+                Some(known) => delivered.difference(known),
+                None => delivered.clone(),
+            };
+            delivered = delivered.difference(&missing);
+        }
+        delivered
+    }
+
+    pub fn values(&self) -> &[(usize, usize)] {
+        self.messages.runs()
+    }
+
+    fn record_protocol(&mut self, peer: &str, protocol: Option<u32>) {
+        let version = self.peer_protocol.entry(peer.to_string()).or_insert(1);
+        *version = (*version).max(protocol.unwrap_or(1));
+    }
+}
+
+impl Handler for Broadcast {
+    type Payload = Payload;
+
+    fn internal(payload: &Payload) -> bool {
+        matches!(
+            payload,
+            Payload::Gossip { .. }
+                | Payload::GossipOk { .. }
+                | Payload::GossipHave { .. }
+                | Payload::SnapshotChunk { .. }
+                | Payload::SnapshotChunkOk { .. }
+                | Payload::TopologyUpdate { .. }
+                | Payload::TopologyUpdateOk { .. }
+        )
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &mut Context,
+        msg: Message<Payload>,
+    ) -> Result<Option<Message>, Error> {
+        validate::broadcast(&msg.body.payload, &ctx.node_ids)?;
+        if ctx.node_ids.contains(&msg.src) {
+            self.heard_from(ctx, &msg.src);
+        }
+        let reply = match msg.body.payload {
+            Payload::Broadcast { message } => {
+                let new = self.commit(
+                    ctx,
+                    Change::Seen {
+                        runs: vec![(message, message)],
+                    },
+                )?;
+                self.redundancy.record(1, usize::from(new));
+                Message {
+                    src: ctx.id.clone(),
+                    dst: msg.src,
+                    body: Body {
+                        id: None,
+                        in_reply_to: msg.body.id,
+                        payload: Payload::BroadcastOk {}.into(),
+                    },
+                }
+            }
+            Payload::Read { limit, from } => {
+                // Clients that pass a `limit` page through the values with
+                // the `next` token; Maelstrom's checker passes neither
+                let delivered = self.delivered(ctx);
+                let mut values = delivered.iter_from(from.unwrap_or(0));
+                let messages: Vec<usize> = match limit {
+                    Some(limit) => values.by_ref().take(limit).collect(),
+                    None => values.by_ref().collect(),
+                };
+                Message {
+                    src: ctx.id.clone(),
+                    dst: msg.src,
+                    body: Body {
+                        id: None,
+                        in_reply_to: msg.body.id,
+                        payload: Payload::ReadOk {
+                            messages,
+                            next: values.next(),
+                        }
+                        .into(),
+                    },
+                }
+            }
+            Payload::Topology { mut topology } => {
+                let neighbors = topology.remove(&ctx.id).unwrap_or_default();
+                self.commit(ctx, Change::Topology { neighbors })?;
+                Message {
+                    src: ctx.id.clone(),
+                    dst: msg.src,
+                    body: Body {
+                        id: None,
+                        in_reply_to: msg.body.id,
+                        payload: Payload::TopologyOk {}.into(),
+                    },
+                }
+            }
+            Payload::TopologyUpdate {
+                epoch,
+                origin,
+                topology,
+            } => {
+                let overlay = Overlay {
+                    epoch,
+                    origin,
+                    topology,
+                };
+                self.commit(ctx, Change::Overlay { overlay })?;
+                Message {
+                    src: ctx.id.clone(),
+                    dst: msg.src,
+                    body: Body {
+                        id: None,
+                        in_reply_to: msg.body.id,
+                        payload: Payload::TopologyUpdateOk {
+                            epoch: self.overlay.epoch,
+                            origin: self.overlay.origin.clone(),
+                        }
+                        .into(),
+                    },
+                }
+            }
+            Payload::TopologyUpdateOk { epoch, origin } => {
+                if (epoch, origin.as_str()) == self.overlay.version() {
+                    self.overlay_acked.insert(msg.src);
+                }
+                return Ok(None);
+            }
+            Payload::Gossip {
+                messages,
+                packed,
+                protocol,
+            } => {
+                self.record_protocol(&msg.src, protocol);
+                let batch: SeenSet = match packed.as_deref().map(codec::unpack) {
+                    None => messages.into_iter().collect(),
+                    Some(batch) => batch.map_err(|e| Error::malformed("packed", e))?,
+                };
+                let new = batch.difference(&self.messages);
+                self.redundancy
+                    .record(batch.iter().count(), new.iter().count());
+                let fresh = self.commit(
+                    ctx,
+                    Change::Seen {
+                        runs: new.runs().to_vec(),
+                    },
+                )?;
+                self.known
+                    .entry(msg.src.clone())
+                    .or_default()
+                    .union_with(&batch);
+                // Nothing new means the sender's view of us is stale, so tell
+                // it everything we have rather than just acking this batch
+                let payload = match fresh {
+                    true => Payload::GossipOk {
+                        protocol: Some(PROTOCOL_VERSION),
+                    },
+                    false => Payload::GossipHave {
+                        have: self.messages.runs().to_vec(),
+                        protocol: Some(PROTOCOL_VERSION),
+                    },
+                };
+                Message {
+                    src: ctx.id.clone(),
+                    dst: msg.src,
+                    body: Body {
+                        id: None,
+                        in_reply_to: msg.body.id,
+                        payload: payload.into(),
+                    },
+                }
+            }
+            Payload::SnapshotChunk { runs, next } => {
+                let chunk = SeenSet::from_runs(runs);
+                let new = chunk.difference(&self.messages);
+                self.commit(
+                    ctx,
+                    Change::Seen {
+                        runs: new.runs().to_vec(),
+                    },
+                )?;
+                self.known
+                    .entry(msg.src.clone())
+                    .or_default()
+                    .union_with(&chunk);
+                Message {
+                    src: ctx.id.clone(),
+                    dst: msg.src,
+                    body: Body {
+                        id: None,
+                        in_reply_to: msg.body.id,
+                        payload: Payload::SnapshotChunkOk { next }.into(),
+                    },
+                }
+            }
+            Payload::SnapshotChunkOk { next } => {
+                let acked = self.take_pending(&msg.src, msg.body.in_reply_to);
+                if let Some((peer, chunk, _)) = acked {
+                    self.known
+                        .entry(peer.clone())
+                        .or_default()
+                        .union_with(&chunk);
+                    match next {
+                        Some(next) => self.snapshots.insert(peer, next),
+                        None => self.snapshots.remove(&peer),
+                    };
+                }
+                return Ok(None);
+            }
+            Payload::GossipOk { protocol } => {
+                self.record_protocol(&msg.src, protocol);
+                let acked = self.take_pending(&msg.src, msg.body.in_reply_to);
+                if let Some((peer, delta, offsets)) = acked {
+                    if let Some((start, end)) = offsets {
+                        self.ack(ctx, &peer, start, end)?;
+                    }
+                    self.known.entry(peer).or_default().union_with(&delta);
+                }
+                return Ok(None);
+            }
+            Payload::GossipHave { have, protocol } => {
+                self.record_protocol(&msg.src, protocol);
+                let acked = self.take_pending(&msg.src, msg.body.in_reply_to);
+                if let Some((_, _, Some((start, end)))) = acked {
+                    self.ack(ctx, &msg.src, start, end)?;
+                }
+                let known = self.known.entry(msg.src).or_default();
+                if let Some((_, delta, _)) = acked {
+                    known.union_with(&delta);
+                }
+                known.union_with(&SeenSet::from_runs(have));
+                return Ok(None);
+            }
+            _ => return Err(unsupported()),
+        };
+        Ok(Some(reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot_chunk(out: &[Message]) -> (usize, usize, Option<usize>) {
+        match out {
+            [Message {
+                body:
+                    Body {
+                        id: Some(id),
+                        payload: crate::Payload::Broadcast(Payload::SnapshotChunk { runs, next }),
+                        ..
+                    },
+                ..
+            }] => (*id, runs.len(), *next),
+            _ => panic!("Expected one snapshot chunk, got {out:?}"),
+        }
+    }
+
+    #[test]
+    fn lagging_peer_gets_snapshot_in_chunks() {
+        let mut ctx = Context {
+            id: "n1".to_string(),
+            ..Default::default()
+        };
+        let mut broadcast = Broadcast::default();
+        let runs = (0..SNAPSHOT_CHUNK + 10).map(|i| (2 * i, 2 * i)).collect();
+        broadcast.apply("n1", &Change::Seen { runs });
+        // Once n2 has every value the stream lets go of them
+        broadcast.stream.catch_up("n2");
+        broadcast.start_round(&["n2".to_string()]);
+        broadcast
+            .peer_protocol
+            .insert("n3".to_string(), PROTOCOL_VERSION);
+
+        let (_, len, next) = snapshot_chunk(&broadcast.gossip(&mut ctx, "n3".to_string()));
+        assert_eq!((len, next), (SNAPSHOT_CHUNK, Some(2 * SNAPSHOT_CHUNK)));
+        // Unacknowledged, the same chunk goes out again
+        broadcast.start_round(&[]);
+        let (id, _, next) = snapshot_chunk(&broadcast.gossip(&mut ctx, "n3".to_string()));
+        assert_eq!(next, Some(2 * SNAPSHOT_CHUNK));
+
+        let ack = |id, next| Message {
+            src: "n3".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: Some(id),
+                payload: Payload::SnapshotChunkOk { next },
+            },
+        };
+        broadcast.handle(&mut ctx, ack(id, next)).unwrap();
+        let (id, len, next) = snapshot_chunk(&broadcast.gossip(&mut ctx, "n3".to_string()));
+        assert_eq!((len, next), (10, None));
+        broadcast.handle(&mut ctx, ack(id, next)).unwrap();
+        assert!(broadcast.gossip(&mut ctx, "n3".to_string()).is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{Context, Handler};
+use crate::{
+    counter::{Counter, CounterState, Refused},
+    journal::{Change, Snapshot},
+    kv_counter::KvCounter,
+    unsupported, Body, Error, Message,
+};
+
+/// Counter workload payloads, along with the lin-kv requests and replies
+/// the `--counter lin-kv` mode exchanges.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Add {
+        delta: serde_json::Number,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        op_id: Option<String>,
+    },
+    AddOk {},
+
+    // Sent to lin-kv with a key
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    ReadOk {
+        value: i64,
+        /// How stale `value` may be, with `--read-staleness`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        debug: Option<Staleness>,
+    },
+
+    CounterGossip {
+        state: CounterState,
+    },
+
+    Cas {
+        key: String,
+        from: i64,
+        to: i64,
+        create_if_not_exists: bool,
+    },
+    CasOk {},
+
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+/// How long since each peer's counter state was last merged in, in
+/// milliseconds, or `None` for peers never heard from. A local read misses
+/// at most the adds each peer took since then, so `bound_ms`, the longest
+/// of them, bounds how stale it is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Staleness {
+    pub staleness_ms: BTreeMap<String, Option<u64>>,
+    pub bound_ms: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct GCounter {
+    counter: Counter,
+    // Set when running with `--counter lin-kv`, replacing `counter`
+    kv: Option<KvCounter>,
+    // Set with `--read-staleness`, telling readers how stale each read is
+    pub read_staleness: bool,
+    // When each peer's gossip was last merged in
+    heard: HashMap<String, Instant>,
+}
+
+impl GCounter {
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            counter: Counter::new(node_id),
+            ..Default::default()
+        }
+    }
+
+    /// How stale a local read is, as far as gossip from each peer goes.
+    fn staleness(&self, ctx: &Context) -> Staleness {
+        let now = ctx.clock.now();
+        let staleness_ms: BTreeMap<String, Option<u64>> = ctx
+            .node_ids
+            .iter()
+            .filter(|id| **id != ctx.id)
+            .map(|peer| {
+                let since = self.heard.get(peer).map(|heard| now.duration_since(*heard));
+                (peer.clone(), since.map(|since| since.as_millis() as u64))
+            })
+            .collect();
+        let bound_ms = staleness_ms
+            .values()
+            .try_fold(0, |bound, ms| ms.map(|ms| bound.max(ms)));
+        Staleness {
+            staleness_ms,
+            bound_ms,
+        }
+    }
+
+    /// Approximate bytes held.
+    pub fn memory(&self) -> usize {
+        self.counter.memory() + self.kv.as_ref().map_or(0, KvCounter::memory)
+    }
+
+    /// Keeps the counter as a single lin-kv key instead of a gossiped CRDT.
+    pub fn use_lin_kv(&mut self, node_id: &str) {
+        self.kv = Some(KvCounter::new(node_id));
+    }
+
+    /// The startup read that checks lin-kv is there, with `--counter lin-kv`.
+    pub fn probe(&mut self, ctx: &mut Context, now: Instant) -> Option<Message> {
+        let kv = self.kv.as_mut()?;
+        Some(kv.probe(&ctx.id, &mut ctx.next_msg_id, now))
+    }
+
+    /// Fails if lin-kv did not pass the startup read.
+    pub fn check_probe(&self, now: Instant) -> Result<()> {
+        self.kv.as_ref().map_or(Ok(()), |kv| kv.check_probe(now))
+    }
+
+    /// Retries lin-kv requests that timed out or backed off.
+    pub fn tick(&mut self, ctx: &mut Context, now: Instant) -> Vec<Message> {
+        match &mut self.kv {
+            Some(kv) => kv.tick(&ctx.id, &mut ctx.next_msg_id, now),
+            None => Vec::new(),
+        }
+    }
+
+    /// Contention summary to log, when running with `--counter lin-kv`.
+    pub fn report(&mut self, now: Instant) -> Option<String> {
+        self.kv.as_mut()?.report(now)
+    }
+
+    /// The counter is small, so its full state goes out on every tick.
+    pub fn gossip(&self, ctx: &Context, peer: &str) -> Option<Message> {
+        if self.counter.is_empty() {
+            return None;
+        }
+        Some(Message {
+            src: ctx.id.clone(),
+            dst: peer.to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: Payload::CounterGossip {
+                    state: self.counter.state(),
+                }
+                .into(),
+            },
+        })
+    }
+
+    /// Folds a journaled counter change in, returning whether it changed
+    /// anything.
+    pub fn apply(&mut self, change: &Change) -> bool {
+        match change {
+            Change::CounterAdd {
+                client,
+                op_id,
+                delta,
+            } => self.counter.add(client, op_id.clone(), *delta) == Ok(true),
+            Change::CounterMerge { state } => self.counter.merge(state.clone()),
+            _ => false,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.counter = Counter::new(&snapshot.node_id);
+        self.counter.merge(snapshot.counter.clone());
+    }
+
+    pub fn state(&self) -> CounterState {
+        self.counter.state()
+    }
+}
+
+impl Handler for GCounter {
+    type Payload = Payload;
+
+    const SERVICES: &'static [&'static str] = &["lin-kv"];
+
+    fn internal(payload: &Payload) -> bool {
+        matches!(payload, Payload::CounterGossip { .. })
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &mut Context,
+        msg: Message<Payload>,
+    ) -> Result<Option<Message>, Error> {
+        if let Some(kv) = &mut self.kv {
+            if kv.owns(&msg) {
+                let now = ctx.clock.now();
+                return Ok(kv.handle(&ctx.id, &mut ctx.next_msg_id, now, msg));
+            }
+        }
+        let payload = match msg.body.payload {
+            Payload::Add { delta, op_id } => {
+                let delta = counter_delta(&delta)?;
+                if let Some(kv) = &mut self.kv {
+                    // The key holds only the total, with nothing to tell a
+                    // retried op from a new one
+                    if op_id.is_some() {
+                        return Err(Error::NotSupported(
+                            "op_id is not supported with --counter lin-kv".to_string(),
+                        ));
+                    }
+                    let now = ctx.clock.now();
+                    return Ok(kv.add(
+                        &ctx.id,
+                        &mut ctx.next_msg_id,
+                        now,
+                        msg.src,
+                        msg.body.id,
+                        delta,
+                    ));
+                }
+                // A retried op that was already applied is acked again
+                // without being counted twice
+                let applied =
+                    self.counter
+                        .add(&msg.src, op_id.clone(), delta)
+                        .map_err(|refused| match refused {
+                            Refused::Overflow(value) => Error::PreconditionFailed(format!(
+                                "Adding {delta} to {value} overflows the counter"
+                            )),
+                            Refused::Forgotten => Error::PreconditionFailed(format!(
+                                "Op {} is below the ops remembered for {}, so it was not applied",
+                                op_id.as_deref().unwrap_or_default(),
+                                msg.src
+                            )),
+                        })?;
+                if applied {
+                    ctx.journal.append(Change::CounterAdd {
+                        client: msg.src.clone(),
+                        op_id,
+                        delta,
+                    })?;
+                }
+                Payload::AddOk {}
+            }
+            Payload::Read { .. } => {
+                if let Some(kv) = &mut self.kv {
+                    let now = ctx.clock.now();
+                    return Ok(Some(kv.read(
+                        &ctx.id,
+                        &mut ctx.next_msg_id,
+                        now,
+                        msg.src,
+                        msg.body.id,
+                    )));
+                }
+                let value = self.counter.value().ok_or_else(|| {
+                    Error::PreconditionFailed("Counter value overflows".to_string())
+                })?;
+                let debug = self.read_staleness.then(|| self.staleness(ctx));
+                Payload::ReadOk { value, debug }
+            }
+            Payload::CounterGossip { state } => {
+                self.heard.insert(msg.src, ctx.clock.now());
+                let change = Change::CounterMerge { state };
+                if self.apply(&change) {
+                    ctx.journal.append(change)?;
+                }
+                return Ok(None);
+            }
+            _ => return Err(unsupported()),
+        };
+        Ok(Some(Message {
+            src: ctx.id.clone(),
+            dst: msg.src,
+            body: Body {
+                id: None,
+                in_reply_to: msg.body.id,
+                payload: payload.into(),
+            },
+        }))
+    }
+}
+
+/// Converts an `add` delta given as any JSON number into an `i64`, or the
+/// error payload to reply with when it does not fit or is not an integer.
+fn counter_delta(delta: &serde_json::Number) -> Result<i64, Error> {
+    if let Some(delta) = delta.as_i64() {
+        return Ok(delta);
+    }
+    if delta.is_u64() {
+        return Err(Error::PreconditionFailed(format!(
+            "Delta {delta} overflows the counter"
+        )));
+    }
+    match delta.as_f64() {
+        Some(f) if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 => Ok(f as i64),
+        Some(f) if f.fract() == 0.0 => Err(Error::PreconditionFailed(format!(
+            "Delta {delta} overflows the counter"
+        ))),
+        _ => Err(Error::Malformed(format!("Delta {delta} is not an integer"))),
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Context, Handler};
+use crate::{unsupported, Body, Error, Message};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Echo { echo: String },
+    EchoOk { echo: String },
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Echo;
+
+impl Handler for Echo {
+    type Payload = Payload;
+
+    fn handle(
+        &mut self,
+        ctx: &mut Context,
+        msg: Message<Payload>,
+    ) -> Result<Option<Message>, Error> {
+        match msg.body.payload {
+            Payload::Echo { echo } => Ok(Some(Message {
+                src: ctx.id.clone(),
+                dst: msg.src,
+                body: Body {
+                    id: None,
+                    in_reply_to: msg.body.id,
+                    payload: Payload::EchoOk { echo }.into(),
+                },
+            })),
+            _ => Err(unsupported()),
+        }
+    }
+}
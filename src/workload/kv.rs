@@ -0,0 +1,434 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{Context, Handler};
+use crate::{
+    crdt_map::{CrdtMap, MapState, Register, Resolution},
+    journal::{Change, Snapshot},
+    unsupported, Body, Error, Message,
+};
+
+/// Key-value workload payloads, shaped like Maelstrom's `lin-kv` ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    /// `ttl_ms` makes the key read as missing that long after the write,
+    /// unless it is written again.
+    Write {
+        key: Value,
+        value: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_ms: Option<u64>,
+    },
+    WriteOk {},
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_ms: Option<u64>,
+    },
+    CasOk {},
+    Delete {
+        key: Value,
+    },
+    DeleteOk {},
+
+    /// Up to `limit` keys from `from` up to but not including `to`, in
+    /// key order. Ranges of string keys are in string order; numbers and
+    /// strings sort by their JSON text. `next` is where to resume if more
+    /// keys are left.
+    Scan {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    ScanOk {
+        entries: Vec<(Value, Value)>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next: Option<Value>,
+    },
+
+    /// Asks for a `watch_event` whenever `key` changes, until `unwatch`.
+    Watch {
+        key: Value,
+    },
+    WatchOk {},
+    Unwatch {
+        key: Value,
+    },
+    UnwatchOk {},
+    /// Pushed to watchers with the new value of `key`, or no value once it
+    /// is deleted.
+    WatchEvent {
+        key: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<Value>,
+    },
+
+    KvGossip {
+        state: MapState,
+    },
+}
+
+/// Who watches a key, and the value they were last told about.
+#[derive(Debug)]
+struct Watch {
+    key: Value,
+    watchers: BTreeSet<String>,
+    last: Option<Value>,
+}
+
+/// Most keys one `scan` returns, whatever `limit` asks for.
+const SCAN_LIMIT: usize = 1000;
+
+/// Totally available key-value store: every node serves reads and writes
+/// from its own replica of a `CrdtMap` and gossips it to the others, so it
+/// stays up through partitions and converges once they heal. Concurrent
+/// writes to a key are settled by its key-space's `Resolution`, last writer
+/// wins unless configured otherwise, and `cas` only compares against the
+/// local replica, so it is not linearizable.
+#[derive(Debug, Default)]
+pub(crate) struct Kv {
+    map: CrdtMap,
+    // Watched keys, by map key. Watches are not journaled and do not
+    // survive a restart
+    watches: BTreeMap<String, Watch>,
+    // Watch events waiting for the next tick
+    events: Vec<Message>,
+}
+
+impl Kv {
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            map: CrdtMap::new(node_id),
+            ..Default::default()
+        }
+    }
+
+    /// Approximate bytes held.
+    pub fn memory(&self) -> usize {
+        self.map.memory()
+            + self.watches.len() * size_of::<(String, Watch)>()
+            + self.events.len() * size_of::<Message>()
+    }
+
+    /// Drops expired keys, and returns the watch events for the changes
+    /// since the last tick. Expired keys already read as missing, so the
+    /// sweep is not journaled; a replay brings them back until the next one.
+    pub fn tick(&mut self, ctx: &Context) -> Vec<Message> {
+        self.map.sweep(ctx.clock.unix_ms());
+        if !self.watches.is_empty() {
+            self.notify(ctx);
+        }
+        std::mem::take(&mut self.events)
+    }
+
+    /// Queues a watch event for every watched key whose value changed,
+    /// whether by a client or by gossip.
+    fn notify(&mut self, ctx: &Context) {
+        let now = ctx.clock.unix_ms();
+        for (map_key, watch) in &mut self.watches {
+            let value = live(&self.map, map_key, now);
+            if value == watch.last {
+                continue;
+            }
+            watch.last = value;
+            for watcher in &watch.watchers {
+                self.events.push(Message {
+                    src: ctx.id.clone(),
+                    dst: watcher.clone(),
+                    body: Body {
+                        id: None,
+                        in_reply_to: None,
+                        payload: Payload::WatchEvent {
+                            key: watch.key.clone(),
+                            value: watch.last.clone(),
+                        }
+                        .into(),
+                    },
+                });
+            }
+        }
+    }
+
+    /// Settles writes to string keys starting with `prefix` with
+    /// `resolution`.
+    pub fn resolve(&mut self, prefix: &str, resolution: Resolution) {
+        // Keys are held as JSON text, which for a string is the string
+        // after an opening quote
+        let text = Value::from(prefix).to_string();
+        self.map.resolve(&text[..text.len() - 1], resolution);
+    }
+
+    /// The full map goes out on every tick, as the counter's does.
+    pub fn gossip(&self, ctx: &Context, peer: &str) -> Option<Message> {
+        if self.map.is_empty() {
+            return None;
+        }
+        Some(Message {
+            src: ctx.id.clone(),
+            dst: peer.to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: Payload::KvGossip {
+                    state: self.map.state(),
+                }
+                .into(),
+            },
+        })
+    }
+
+    /// Folds a journaled kv change in, returning whether it changed
+    /// anything. Local writes replay to the same dots and times, since
+    /// every change before them replays too.
+    pub fn apply(&mut self, change: &Change) -> bool {
+        match change {
+            Change::KvPut {
+                key,
+                value,
+                expires,
+                at,
+            } => {
+                self.map.put_until(key, value.clone(), *expires, *at);
+                true
+            }
+            Change::KvAdd { key, delta } => {
+                self.map.add(key, *delta);
+                true
+            }
+            Change::KvRemove { key } => self.map.remove(key),
+            Change::KvMerge { state, at } => self.map.merge(state.clone(), *at),
+            _ => false,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.map.clear();
+        // Into an empty map, so no value is merged over and the time does
+        // not matter
+        self.map.merge(snapshot.kv.clone(), 0);
+    }
+
+    pub fn state(&self) -> MapState {
+        self.map.state()
+    }
+
+    /// Writes `value` to `key`, expiring `ttl_ms` from now, or adds it if
+    /// `key` is a counter.
+    fn put(
+        &mut self,
+        ctx: &mut Context,
+        key: String,
+        value: Value,
+        ttl_ms: Option<u64>,
+    ) -> Result<(), Error> {
+        let change = match self.map.resolution(&key) {
+            Resolution::Add if ttl_ms.is_some() => {
+                return Err(Error::malformed("ttl_ms", "counter keys do not expire"))
+            }
+            Resolution::Add => Change::KvAdd {
+                delta: integer("value", &value)?,
+                key,
+            },
+            _ => {
+                let at = ctx.clock.unix_ms();
+                Change::KvPut {
+                    key,
+                    value,
+                    expires: ttl_ms.map(|ttl| at.saturating_add(ttl)),
+                    at,
+                }
+            }
+        };
+        self.apply(&change);
+        ctx.journal.append(change)?;
+        Ok(())
+    }
+}
+
+impl Handler for Kv {
+    type Payload = Payload;
+
+    fn internal(payload: &Payload) -> bool {
+        matches!(payload, Payload::KvGossip { .. })
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &mut Context,
+        msg: Message<Payload>,
+    ) -> Result<Option<Message>, Error> {
+        let reply = self.respond(ctx, msg);
+        if !self.watches.is_empty() {
+            self.notify(ctx);
+        }
+        reply
+    }
+}
+
+impl Kv {
+    fn respond(
+        &mut self,
+        ctx: &mut Context,
+        msg: Message<Payload>,
+    ) -> Result<Option<Message>, Error> {
+        let now = ctx.clock.unix_ms();
+        let payload = match msg.body.payload {
+            Payload::Read { key } => {
+                let value = live(&self.map, &map_key(&key), now).ok_or_else(|| missing(&key))?;
+                Payload::ReadOk { value }
+            }
+            Payload::Write { key, value, ttl_ms } => {
+                self.put(ctx, map_key(&key), value, ttl_ms)?;
+                Payload::WriteOk {}
+            }
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+                ttl_ms,
+            } => {
+                // An expired key is missing, so a lapsed lease can be taken
+                // over with `create_if_not_exists`
+                let current = live(&self.map, &map_key(&key), now);
+                match &current {
+                    Some(current) if *current != from => {
+                        return Err(Error::PreconditionFailed(format!(
+                            "Expected {from}, but {key} is {current}"
+                        )))
+                    }
+                    None if !create_if_not_exists => return Err(missing(&key)),
+                    _ => {}
+                }
+                // A counter gets the difference added, so concurrent adds
+                // elsewhere are kept
+                let to = match self.map.resolution(&map_key(&key)) {
+                    Resolution::Add => integer("to", &to)?
+                        .checked_sub(current.map_or(Ok(0), |_| integer("from", &from))?)
+                        .ok_or_else(|| Error::malformed("to", "overflows the counter"))?
+                        .into(),
+                    _ => to,
+                };
+                self.put(ctx, map_key(&key), to, ttl_ms)?;
+                Payload::CasOk {}
+            }
+            Payload::Delete { key } => {
+                if live(&self.map, &map_key(&key), now).is_none() {
+                    return Err(missing(&key));
+                }
+                let change = Change::KvRemove { key: map_key(&key) };
+                self.apply(&change);
+                ctx.journal.append(change)?;
+                Payload::DeleteOk {}
+            }
+            Payload::Scan { from, to, limit } => {
+                let (from, to) = (from.as_ref().map(map_key), to.as_ref().map(map_key));
+                let limit = limit.unwrap_or(SCAN_LIMIT).min(SCAN_LIMIT);
+                let mut live = self
+                    .map
+                    .range(
+                        from.as_deref().map_or(Bound::Unbounded, Bound::Included),
+                        to.as_deref().map_or(Bound::Unbounded, Bound::Excluded),
+                    )
+                    .filter(|(_, register)| !register.expired(now));
+                let mut entries = Vec::new();
+                for (key, register) in live.by_ref().take(limit) {
+                    entries.push((wire_key(key)?, register.value()));
+                }
+                let next = live.next().map(|(key, _)| wire_key(key)).transpose()?;
+                Payload::ScanOk { entries, next }
+            }
+            Payload::Watch { key } => {
+                let map_key = map_key(&key);
+                let last = live(&self.map, &map_key, now);
+                self.watches
+                    .entry(map_key)
+                    .or_insert(Watch {
+                        key,
+                        watchers: BTreeSet::new(),
+                        last,
+                    })
+                    .watchers
+                    .insert(msg.src.clone());
+                Payload::WatchOk {}
+            }
+            Payload::Unwatch { key } => {
+                let map_key = map_key(&key);
+                if let Some(watch) = self.watches.get_mut(&map_key) {
+                    watch.watchers.remove(&msg.src);
+                    if watch.watchers.is_empty() {
+                        self.watches.remove(&map_key);
+                    }
+                }
+                Payload::UnwatchOk {}
+            }
+            Payload::KvGossip { state } => {
+                let change = Change::KvMerge {
+                    state,
+                    at: ctx.clock.unix_ms(),
+                };
+                if self.apply(&change) {
+                    ctx.journal.append(change)?;
+                }
+                return Ok(None);
+            }
+            _ => return Err(unsupported()),
+        };
+        Ok(Some(Message {
+            src: ctx.id.clone(),
+            dst: msg.src,
+            body: Body {
+                id: None,
+                in_reply_to: msg.body.id,
+                payload: payload.into(),
+            },
+        }))
+    }
+}
+
+/// Keys are JSON values on the wire; the map holds them as their JSON text,
+/// so `1` and `"1"` stay different keys.
+fn map_key(key: &Value) -> String {
+    key.to_string()
+}
+
+fn wire_key(key: &str) -> Result<Value, Error> {
+    serde_json::from_str(key).map_err(|err| Error::Crash(err.to_string()))
+}
+
+/// The value of `key`, unless it is missing or expired.
+fn live(map: &CrdtMap, key: &str, now: u64) -> Option<Value> {
+    map.register(key)
+        .filter(|register| !register.expired(now))
+        .map(Register::value)
+}
+
+fn integer(field: &str, value: &Value) -> Result<i64, Error> {
+    value
+        .as_i64()
+        .ok_or_else(|| Error::malformed(field, "counter keys take integers"))
+}
+
+fn missing(key: &Value) -> Error {
+    Error::KeyDoesNotExist(format!("Key {key} does not exist"))
+}
@@ -0,0 +1,140 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{Context, Handler};
+use crate::{node_index::NodeIndex, unsupported, Body, Error, Message};
+
+/// Start of Snowflake time, 2024-01-01 in Unix milliseconds.
+const SNOWFLAKE_EPOCH: u64 = 1_704_067_200_000;
+const NODE_BITS: u32 = 10;
+const SEQ_BITS: u32 = 12;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Generate {},
+    GenerateOk { id: String },
+}
+
+/// How ids are made.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdStrategy {
+    /// Random UUIDv4s, unique without any coordination.
+    #[default]
+    Uuid,
+    /// Time-ordered 64-bit ids: milliseconds since 2024, the node's index
+    /// among the cluster's ids, and a per-millisecond sequence.
+    Snowflake,
+}
+
+impl IdStrategy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "uuid" => Some(IdStrategy::Uuid),
+            "snowflake" => Some(IdStrategy::Snowflake),
+            _ => None,
+        }
+    }
+}
+
+/// Snowflake generator that stays unique when the wall clock steps back.
+/// Time never goes below the last id's, so after a rollback ids keep
+/// counting up the sequence of the last millisecond used, and running out
+/// of sequence borrows the next millisecond ahead of the clock. Ids stay
+/// ordered and unique, just ahead of wall time until the clock catches up.
+#[derive(Debug, Default)]
+struct Snowflake {
+    // Millisecond and sequence of the last id
+    last: u64,
+    seq: u64,
+}
+
+impl Snowflake {
+    fn next(&mut self, node: u64, now_ms: u64) -> u64 {
+        let now = now_ms.saturating_sub(SNOWFLAKE_EPOCH);
+        if now > self.last {
+            (self.last, self.seq) = (now, 0);
+        } else if self.seq + 1 < 1 << SEQ_BITS {
+            self.seq += 1;
+        } else {
+            (self.last, self.seq) = (self.last + 1, 0);
+        }
+        self.last << (NODE_BITS + SEQ_BITS) | (node & ((1 << NODE_BITS) - 1)) << SEQ_BITS | self.seq
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct UniqueIds {
+    // Set with `--ids`
+    pub strategy: IdStrategy,
+    snowflake: Snowflake,
+}
+
+impl UniqueIds {
+    fn generate_uuid(&mut self) -> String {
+        Uuid::new_v4().hyphenated().to_string()
+    }
+
+    fn generate_snowflake(&mut self, ctx: &Context) -> String {
+        let node = NodeIndex::new(&ctx.node_ids).index(&ctx.id).unwrap_or(0) as u64;
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        self.snowflake.next(node, now_ms).to_string()
+    }
+}
+
+impl Handler for UniqueIds {
+    type Payload = Payload;
+
+    fn handle(
+        &mut self,
+        ctx: &mut Context,
+        msg: Message<Payload>,
+    ) -> Result<Option<Message>, Error> {
+        match msg.body.payload {
+            Payload::Generate {} => {
+                let id = match self.strategy {
+                    IdStrategy::Uuid => self.generate_uuid(),
+                    IdStrategy::Snowflake => self.generate_snowflake(ctx),
+                };
+                Ok(Some(Message {
+                    src: ctx.id.clone(),
+                    dst: msg.src,
+                    body: Body {
+                        id: None,
+                        in_reply_to: msg.body.id,
+                        payload: Payload::GenerateOk { id }.into(),
+                    },
+                }))
+            }
+            _ => Err(unsupported()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn snowflakes_stay_unique_when_the_clock_steps_back() {
+        let mut snowflake = Snowflake::default();
+        let start = SNOWFLAKE_EPOCH + 1000;
+        // A second back, then a whole millisecond's worth of sequence and
+        // more while the clock is stuck behind
+        let clock = [start, start + 1, start - 1000]
+            .into_iter()
+            .chain(std::iter::repeat_n(start - 999, 1 << SEQ_BITS));
+        let ids: Vec<u64> = clock.map(|now| snowflake.next(3, now)).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+        // Borrowed a millisecond past the clock's latest
+        assert_eq!(ids.last().unwrap() >> (NODE_BITS + SEQ_BITS), 1002);
+    }
+}
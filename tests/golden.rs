@@ -0,0 +1,154 @@
+//! Every payload variant against the exact JSON Maelstrom and peers see.
+//! The fixtures are conversations with a running node: lines with a
+//! top-level `id` were routed to it, numbered as Maelstrom numbers the
+//! messages it routes, and the rest are what the node sent back. Each line
+//! must parse as its workload's payload and serialize back to the same
+//! JSON, and each fixture must hold every variant of its payload.
+#![cfg(all(
+    feature = "echo",
+    feature = "unique-ids",
+    feature = "broadcast",
+    feature = "counter",
+    feature = "kv"
+))]
+
+use std::collections::BTreeSet;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use distributed_systems_challenges::{workload, Message, NodePayload};
+
+/// The `type` of every variant of `$payload`. The match has no catch-all,
+/// so a new variant does not compile until it is listed here, and then
+/// `check` fails until a fixture has it.
+macro_rules! kinds {
+    ($payload:ty { $($variant:ident => $kind:literal),* $(,)? }) => {{
+        type P = $payload;
+        #[allow(dead_code)]
+        fn exhaustive(payload: &P) {
+            match payload {
+                $(P::$variant { .. } => {})*
+            }
+        }
+        [$($kind),*]
+    }};
+}
+
+fn check<P: Serialize + DeserializeOwned>(fixture: &str, kinds: &[&str]) {
+    let path = format!("{}/tests/golden/{fixture}", env!("CARGO_MANIFEST_DIR"));
+    let text = std::fs::read_to_string(&path).unwrap();
+    let mut seen = BTreeSet::new();
+    for (n, line) in text.lines().enumerate() {
+        let mut expected: Value = serde_json::from_str(line).unwrap();
+        expected.as_object_mut().unwrap().remove("id");
+        // Numbering the node adds to messages between nodes, read before
+        // the payload is parsed
+        let body = expected["body"].as_object_mut().unwrap();
+        body.remove("seq");
+        body.remove("incarnation");
+        seen.insert(body["type"].as_str().unwrap().to_string());
+        let msg: Message<P> = serde_json::from_value(expected.clone())
+            .unwrap_or_else(|e| panic!("{fixture}:{}: {e}", n + 1));
+        let actual = serde_json::to_value(&msg).unwrap();
+        assert_eq!(actual, expected, "{fixture}:{}", n + 1);
+    }
+    for kind in kinds {
+        assert!(seen.contains(*kind), "{fixture} has no {kind}");
+    }
+}
+
+#[test]
+fn echo() {
+    use workload::echo::Payload;
+    let kinds = kinds!(Payload { Echo => "echo", EchoOk => "echo_ok" });
+    check::<Payload>("echo.jsonl", &kinds);
+}
+
+#[test]
+fn unique_ids() {
+    use workload::unique_ids::Payload;
+    let kinds = kinds!(Payload {
+        Generate => "generate",
+        GenerateOk => "generate_ok",
+    });
+    check::<Payload>("unique_ids.jsonl", &kinds);
+}
+
+#[test]
+fn broadcast() {
+    use workload::broadcast::Payload;
+    let kinds = kinds!(Payload {
+        Broadcast => "broadcast",
+        BroadcastOk => "broadcast_ok",
+        Read => "read",
+        ReadOk => "read_ok",
+        Topology => "topology",
+        TopologyOk => "topology_ok",
+        TopologyUpdate => "topology_update",
+        TopologyUpdateOk => "topology_update_ok",
+        Gossip => "gossip",
+        GossipOk => "gossip_ok",
+        SnapshotChunk => "snapshot_chunk",
+        SnapshotChunkOk => "snapshot_chunk_ok",
+        GossipHave => "gossip_have",
+    });
+    check::<Payload>("broadcast.jsonl", &kinds);
+}
+
+#[test]
+fn counter() {
+    use workload::counter::Payload;
+    let kinds = kinds!(Payload {
+        Add => "add",
+        AddOk => "add_ok",
+        Read => "read",
+        ReadOk => "read_ok",
+        CounterGossip => "counter_gossip",
+        Cas => "cas",
+        CasOk => "cas_ok",
+        Error => "error",
+    });
+    check::<Payload>("counter.jsonl", &kinds);
+}
+
+#[test]
+fn kv() {
+    use workload::kv::Payload;
+    let kinds = kinds!(Payload {
+        Read => "read",
+        ReadOk => "read_ok",
+        Write => "write",
+        WriteOk => "write_ok",
+        Cas => "cas",
+        CasOk => "cas_ok",
+        Delete => "delete",
+        DeleteOk => "delete_ok",
+        Scan => "scan",
+        ScanOk => "scan_ok",
+        Watch => "watch",
+        WatchOk => "watch_ok",
+        Unwatch => "unwatch",
+        UnwatchOk => "unwatch_ok",
+        WatchEvent => "watch_event",
+        KvGossip => "kv_gossip",
+    });
+    check::<Payload>("kv.jsonl", &kinds);
+}
+
+#[test]
+fn node() {
+    let kinds = kinds!(NodePayload {
+        Init => "init",
+        InitOk => "init_ok",
+        DebugState => "debug_state",
+        DebugStateOk => "debug_state_ok",
+        Resend => "resend",
+        Reconfigure => "reconfigure",
+        ReconfigureOk => "reconfigure_ok",
+        Hello => "hello",
+        HelloOk => "hello_ok",
+        Error => "error",
+    });
+    check::<NodePayload>("node.jsonl", &kinds);
+}